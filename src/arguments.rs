@@ -0,0 +1,521 @@
+//! The server's settings
+//!
+//! They are given on the command line, or in a config file, see
+//! `config_file`. The command line takes precedence.
+use anyhow::{anyhow, Context, Result};
+use clap::{parser::ValueSource, ArgMatches, Parser};
+use iris_lib::{
+    account::Account,
+    casemapping::CaseMapping,
+    cloak::Cloak,
+    config::{ConnectionClass, ListenAddress, ListenerConfig, ServerConfig, StsPolicy},
+    config_file::{check_line_len, check_nick_len, check_plugin, check_server_name, ConfigFile},
+    flood::FloodConfig,
+    keepalive::KeepaliveConfig,
+    limits::ConnectionLimits,
+    operator::Operator,
+    plugin::PLUGINS,
+    split::LongMessages,
+    types::{DEFAULT_NICK_LEN, DEFAULT_SERVER_NAME},
+    webirc::WebircGateway,
+};
+use log::LevelFilter;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+#[derive(Parser, Clone)]
+pub struct Arguments {
+    /// TOML file with the server's settings, see the `config_file` module.
+    /// Options given on the command line take precedence over it.
+    /// It is read again on SIGHUP.
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+
+    /// The name of the server, which messages from the server come from.
+    #[clap(long, default_value = DEFAULT_SERVER_NAME, value_parser = server_name_arg)]
+    pub server_name: String,
+
+    /// How much to log: "off", "error", "warn", "info", "debug" or "trace".
+    /// Nothing more than `RUST_LOG` allows is logged, if it is set.
+    #[clap(long)]
+    pub log_level: Option<LevelFilter>,
+
+    /// File with the message of the day, sent to users as they register.
+    #[clap(long)]
+    pub motd: Option<PathBuf>,
+
+    /// The longest nick a user may take.
+    #[clap(long, default_value_t = DEFAULT_NICK_LEN, value_parser = nick_len_arg)]
+    pub nick_len: usize,
+
+    /// The longest line a client may send, in bytes, including the trailing CR LF.
+    #[clap(long, default_value = "512", value_parser = line_len_arg)]
+    pub line_len: usize,
+
+    /// A plugin users may use. Can be repeated. All of them, if not given.
+    #[clap(long = "plugin", default_values = PLUGINS, value_parser = plugin_arg)]
+    pub plugins: Vec<String>,
+
+    /// Address to listen on, unless `--listen` is given.
+    #[clap(default_value = "127.0.0.1")]
+    pub ip_address: IpAddr,
+
+    /// Port to listen on, unless `--listen` is given.
+    #[clap(default_value = "6991")]
+    pub port: u16,
+
+    /// An address to listen on, as `<address>:<port>[,tls][,class=<name>][,ipv6-only]`,
+    /// e.g. `[::]:6697,tls`. `[::]` takes IPv4 clients too, unless `ipv6-only`.
+    /// WebSocket clients connect to listeners with `,websocket[,origin=<origin>...]`.
+    /// A Unix socket is `unix:<path>[,mode=<octal>][,class=<name>][,trust-peer]`;
+    /// with `trust-peer`, clients are logged in to the account bound to their uid.
+    /// With `,proxy`, connections start with a PROXY protocol header from a trusted proxy.
+    /// Can be repeated.
+    #[clap(long = "listen")]
+    pub listeners: Vec<ListenerConfig>,
+
+    /// How nicks and channel names are compared: "rfc1459" or "ascii".
+    #[clap(long, default_value = "rfc1459")]
+    pub casemapping: CaseMapping,
+
+    /// Seconds a user may be idle before the server sends them a PING.
+    #[clap(long, default_value = "120")]
+    pub ping_interval: u64,
+
+    /// Seconds a user has to answer a PING before being disconnected.
+    #[clap(long, default_value = "60")]
+    pub ping_timeout: u64,
+
+    /// Seconds a connection has to complete NICK/USER registration.
+    #[clap(long, default_value = "60")]
+    pub registration_timeout: u64,
+
+    /// An IRC operator, as `<name>:<sha256 password hash>[:<host mask>]`. Can be repeated.
+    #[clap(long = "oper")]
+    pub operators: Vec<Operator>,
+
+    /// An account, as `<name>:<SHA-256 fingerprint of a TLS client certificate>`.
+    /// Clients with the certificate log in to it with SASL EXTERNAL.
+    /// An account can be bound to a local user with `<name>:uid=<uid>` instead.
+    /// Can be repeated.
+    #[clap(long = "account")]
+    pub accounts: Vec<Account>,
+
+    /// A WEBIRC gateway, as `<name>:<sha256 password hash>:<host mask>`. Can be repeated.
+    #[clap(long = "webirc")]
+    pub gateways: Vec<WebircGateway>,
+
+    /// File the server bans (K-lines) are saved to.
+    #[clap(long, default_value = "kline.conf")]
+    pub ban_file: String,
+
+    /// File plugins save their state to when the server shuts down,
+    /// and pick it up from when it starts.
+    #[clap(long, default_value = "plugin.state")]
+    pub plugin_state_file: PathBuf,
+
+    /// Seconds the server waits, when shutting down, for clients to be sent
+    /// what they have left to receive.
+    #[clap(long, default_value = "5")]
+    pub shutdown_grace: u64,
+
+    /// A connection class, as `<name>=<host mask>[,flood-exempt][,sendq=<bytes>]`.
+    /// Can be repeated.
+    #[clap(long = "class")]
+    pub classes: Vec<ConnectionClass>,
+
+    /// Seconds of command penalties a client may run ahead before being throttled.
+    #[clap(long, default_value = "10")]
+    pub flood_burst: u64,
+
+    /// Bytes of unprocessed messages a throttled client may send before
+    /// being disconnected for flooding.
+    #[clap(long, default_value = "8192")]
+    pub recvq: usize,
+
+    /// How many clients may be connected at once.
+    #[clap(long, default_value = "1024")]
+    pub max_clients: usize,
+
+    /// How many connections a single host may have open. IPv6 addresses
+    /// in the same /64 count as one host.
+    #[clap(long, default_value = "10")]
+    pub max_per_host: usize,
+
+    /// How many times a single host may connect within the throttle window.
+    #[clap(long, default_value = "10")]
+    pub throttle_count: usize,
+
+    /// Length of the connect throttle window, in seconds.
+    #[clap(long, default_value = "60")]
+    pub throttle_window: u64,
+
+    /// Secret key used to cloak users' IP addresses. If not given, a random
+    /// key is used, and every cloak changes when the server restarts.
+    #[clap(long)]
+    pub cloak_key: Option<String>,
+
+    /// Password clients must send with `PASS` before they can register.
+    #[clap(long)]
+    pub password: Option<String>,
+
+    /// What to do with a message that is too long once the sender's prefix
+    /// is added: "split" it into several, or "reject" it.
+    #[clap(long, default_value = "split")]
+    pub long_messages: LongMessages,
+
+    /// Also listen for TLS clients on this port, unless `--listen` is given.
+    #[clap(long, requires_all = ["tls_cert", "tls_key"], conflicts_with = "listeners")]
+    pub tls_port: Option<u16>,
+
+    /// PEM file with the TLS certificate chain. It is read again on SIGHUP.
+    #[clap(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file with the TLS private key. It is read again on SIGHUP.
+    #[clap(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Advertise an STS policy, telling clients to only connect over TLS
+    /// for this many seconds. Clients are sent to the first TLS listener.
+    #[clap(long, requires = "tls_cert")]
+    pub sts_duration: Option<u64>,
+}
+
+impl Arguments {
+    /// The `--listen` addresses, or the ones from `ip_address`, `port` and `tls_port`.
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+
+        let listener = |port, tls| ListenerConfig {
+            address: ListenAddress::Tcp(SocketAddr::new(self.ip_address, port)),
+            tls,
+            websocket: false,
+            origins: Vec::new(),
+            class: None,
+            ipv6_only: false,
+            mode: None,
+            trust_peer: false,
+            proxy: false,
+        };
+
+        let mut listeners = vec![listener(self.port, false)];
+        listeners.extend(self.tls_port.map(|port| listener(port, true)));
+        listeners
+    }
+
+    /// Take every setting that wasn't given on the command line from the config file.
+    pub fn merge(&mut self, matches: &ArgMatches, file: ConfigFile) {
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        fn take<T>(setting: &mut T, value: Option<T>, given: bool) {
+            if let (Some(value), false) = (value, given) {
+                *setting = value;
+            }
+        }
+
+        let limits = file.limits;
+        take(
+            &mut self.server_name,
+            file.server_name,
+            given("server_name"),
+        );
+        take(
+            &mut self.log_level,
+            file.log_level.map(Some),
+            given("log_level"),
+        );
+        take(&mut self.motd, file.motd.map(Some), given("motd"));
+        take(
+            &mut self.listeners,
+            file.listeners,
+            ["listeners", "ip_address", "port", "tls_port"]
+                .into_iter()
+                .any(given),
+        );
+        take(&mut self.operators, file.operators, given("operators"));
+        take(&mut self.accounts, file.accounts, given("accounts"));
+        take(&mut self.gateways, file.gateways, given("gateways"));
+        take(&mut self.classes, file.classes, given("classes"));
+        take(&mut self.nick_len, limits.nick_len, given("nick_len"));
+        take(&mut self.line_len, limits.line_len, given("line_len"));
+        take(
+            &mut self.max_clients,
+            limits.max_clients,
+            given("max_clients"),
+        );
+        take(
+            &mut self.max_per_host,
+            limits.max_per_host,
+            given("max_per_host"),
+        );
+        take(
+            &mut self.throttle_count,
+            limits.throttle_count,
+            given("throttle_count"),
+        );
+        take(
+            &mut self.throttle_window,
+            limits.throttle_window,
+            given("throttle_window"),
+        );
+        take(
+            &mut self.ping_interval,
+            limits.ping_interval,
+            given("ping_interval"),
+        );
+        take(
+            &mut self.ping_timeout,
+            limits.ping_timeout,
+            given("ping_timeout"),
+        );
+        take(
+            &mut self.registration_timeout,
+            limits.registration_timeout,
+            given("registration_timeout"),
+        );
+        take(
+            &mut self.flood_burst,
+            limits.flood_burst,
+            given("flood_burst"),
+        );
+        take(&mut self.recvq, limits.recvq, given("recvq"));
+        take(&mut self.plugins, file.plugins.enabled, given("plugins"));
+        take(
+            &mut self.plugin_state_file,
+            file.plugins.state_file,
+            given("plugin_state_file"),
+        );
+    }
+
+    /// Keep the settings that only change when the server is restarted as
+    /// they are in `running`, the settings in use, returning the ones that
+    /// were changed.
+    pub fn keep_restart_only(&mut self, running: &Arguments) -> Vec<&'static str> {
+        let mut kept = Vec::new();
+        if self.server_name != running.server_name {
+            self.server_name = running.server_name.clone();
+            kept.push("server name");
+        }
+        if self.listeners() != running.listeners() {
+            self.listeners = running.listeners();
+            kept.push("listeners");
+        }
+        if self.line_len != running.line_len {
+            self.line_len = running.line_len;
+            kept.push("line length");
+        }
+
+        kept
+    }
+
+    /// The settings for the message handlers, with `cloak` to cloak hosts.
+    ///
+    /// Fails if the MOTD can't be read, or a listener is in a class that doesn't exist.
+    pub fn server_config(&self, cloak: Cloak) -> Result<ServerConfig> {
+        let listeners = self.listeners();
+
+        let motd = match &self.motd {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read the MOTD {}", path.display()))?
+                    .lines()
+                    .map(str::to_owned)
+                    .collect(),
+            ),
+            None => None,
+        };
+
+        let config = ServerConfig {
+            operators: self.operators.clone(),
+            accounts: self.accounts.clone(),
+            gateways: self.gateways.clone(),
+            classes: self.classes.clone(),
+            flood: FloodConfig {
+                burst: Duration::from_secs(self.flood_burst),
+                recvq: self.recvq,
+            },
+            cloak,
+            password: self.password.clone(),
+            long_messages: self.long_messages,
+            sts: listeners
+                .iter()
+                .find_map(|listener| match listener.address {
+                    ListenAddress::Tcp(address) if listener.tls => Some(address.port()),
+                    _ => None,
+                })
+                .zip(self.sts_duration)
+                .map(|(port, duration)| StsPolicy {
+                    port,
+                    duration: Duration::from_secs(duration),
+                }),
+            shutdown_grace: Duration::from_secs(self.shutdown_grace),
+            plugin_state_file: Some(self.plugin_state_file.clone()),
+            plugins: self.plugins.clone(),
+            motd,
+            keepalive: KeepaliveConfig {
+                ping_interval: Duration::from_secs(self.ping_interval),
+                ping_timeout: Duration::from_secs(self.ping_timeout),
+                registration_timeout: Duration::from_secs(self.registration_timeout),
+            },
+        };
+
+        for listener in &listeners {
+            if let Some(class) = &listener.class {
+                if config.find_class(class).is_none() {
+                    return Err(anyhow!(
+                        "Unknown connection class {} for {}",
+                        class,
+                        listener.address
+                    ));
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// The limits on how many connections are accepted.
+    pub fn limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            max_clients: self.max_clients,
+            max_per_host: self.max_per_host,
+            throttle_count: self.throttle_count,
+            throttle_window: Duration::from_secs(self.throttle_window),
+        }
+    }
+}
+
+fn server_name_arg(name: &str) -> Result<String, String> {
+    check_server_name(name)?;
+    Ok(name.to_owned())
+}
+
+fn nick_len_arg(nick_len: &str) -> Result<usize, String> {
+    let nick_len = nick_len.parse().map_err(|err| format!("{err}"))?;
+    check_nick_len(nick_len)?;
+    Ok(nick_len)
+}
+
+fn line_len_arg(line_len: &str) -> Result<usize, String> {
+    let line_len = line_len.parse().map_err(|err| format!("{err}"))?;
+    check_line_len(line_len)?;
+    Ok(line_len)
+}
+
+fn plugin_arg(name: &str) -> Result<String, String> {
+    check_plugin(name)?;
+    Ok(name.to_owned())
+}
+
+/// Where the settings come from, so they can be read again on SIGHUP.
+pub struct Sources {
+    pub matches: ArgMatches,
+    /// The settings given on the command line, and the defaults for the rest.
+    pub command_line: Arguments,
+    /// How much is logged, unless the settings say otherwise.
+    pub default_log_level: LevelFilter,
+}
+
+impl Sources {
+    /// Put the settings together from the command line, and the config file if there is one.
+    pub fn load(&self) -> Result<Arguments> {
+        let mut arguments = self.command_line.clone();
+        if let Some(path) = &self.command_line.config {
+            arguments.merge(&self.matches, ConfigFile::load(path)?);
+        }
+
+        Ok(arguments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    /// The settings from `args`, and the config file with `contents`.
+    fn load(args: &[&str], contents: &str) -> Arguments {
+        let matches = Arguments::command().get_matches_from(args);
+        let mut arguments = Arguments::from_arg_matches(&matches).unwrap();
+        arguments.merge(&matches, toml::from_str(contents).unwrap());
+        arguments
+    }
+
+    const FILE: &str = r#"
+        server_name = "file.example.net"
+        listen = ["[::]:6667"]
+
+        [limits]
+        nick_len = 16
+        max_clients = 100
+    "#;
+
+    #[test]
+    fn test_merge() {
+        // what the command line doesn't give comes from the file, or the defaults
+        let arguments = load(&["iris"], FILE);
+        assert_eq!(arguments.server_name, "file.example.net");
+        assert_eq!(arguments.listeners().len(), 1);
+        assert_eq!(arguments.listeners()[0].address.to_string(), "[::]:6667");
+        assert_eq!(arguments.nick_len, 16);
+        assert_eq!(arguments.max_clients, 100);
+        assert_eq!(arguments.ping_interval, 120);
+
+        // and the command line takes precedence
+        let arguments = load(
+            &[
+                "iris",
+                "--server-name",
+                "cli.example.net",
+                "--max-clients",
+                "5",
+            ],
+            FILE,
+        );
+        assert_eq!(arguments.server_name, "cli.example.net");
+        assert_eq!(arguments.max_clients, 5);
+        assert_eq!(arguments.nick_len, 16);
+
+        // listening on anything there replaces the file's listeners
+        let arguments = load(&["iris", "127.0.0.1", "7000"], FILE);
+        assert_eq!(arguments.listeners().len(), 1);
+        assert_eq!(
+            arguments.listeners()[0].address.to_string(),
+            "127.0.0.1:7000"
+        );
+    }
+
+    #[test]
+    fn test_keep_restart_only() {
+        let running = load(&["iris"], FILE);
+
+        let mut new = load(
+            &["iris", "--line-len", "1024"],
+            r#"
+            server_name = "new.example.net"
+            listen = ["[::]:6697"]
+
+            [limits]
+            nick_len = 20
+            "#,
+        );
+        assert_eq!(
+            new.keep_restart_only(&running),
+            vec!["server name", "listeners", "line length"]
+        );
+        assert_eq!(new.server_name, running.server_name);
+        assert!(new.listeners() == running.listeners());
+        assert_eq!(new.line_len, running.line_len);
+        // the rest changes straight away
+        assert_eq!(new.nick_len, 20);
+        assert_eq!(new.max_clients, 1024);
+
+        let mut same = running.clone();
+        assert!(same.keep_restart_only(&running).is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+//! Accounts
+//!
+//! An account is bound to the SHA-256 fingerprint (certfp) of a TLS client
+//! certificate. A client connecting over TLS with that certificate can log in
+//! to the account with SASL `EXTERNAL`, without sending a password.
+//!
+//! The fingerprint of a certificate can be found with:
+//!
+//! ```text
+//! openssl x509 -in cert.pem -outform der | sha256sum
+//! ```
+//!
+//! An account can instead be bound to a local user id. A process running as
+//! that user is logged in to it as soon as it connects to a Unix socket
+//! listener that trusts its peers' credentials.
+use std::str::FromStr;
+
+/// An account, and the certificate fingerprint or local user that logs in to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub name: String,
+    /// Lowercase hex SHA-256 of the certificate.
+    pub certfp: Option<String>,
+    /// The user id of local processes that are logged in to the account.
+    pub uid: Option<u32>,
+}
+
+impl Account {
+    pub fn matches_certfp(&self, certfp: &str) -> bool {
+        self.certfp
+            .as_ref()
+            .is_some_and(|own| own.eq_ignore_ascii_case(certfp))
+    }
+
+    pub fn matches_uid(&self, uid: u32) -> bool {
+        self.uid == Some(uid)
+    }
+}
+
+/// Parse an account from `<name>:<certificate fingerprint>`, or `<name>:uid=<user id>`.
+///
+/// The fingerprint may be written with colons between the bytes, as `openssl` shows it.
+impl FromStr for Account {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, credential) = s.split_once(':').unwrap_or((s, ""));
+
+        if name.is_empty() {
+            return Err(format!("account has no name: {s}"));
+        }
+
+        if let Some(uid) = credential.strip_prefix("uid=") {
+            return Ok(Account {
+                name: name.to_owned(),
+                certfp: None,
+                uid: Some(
+                    uid.parse()
+                        .map_err(|_| format!("invalid uid for account {name}: {uid}"))?,
+                ),
+            });
+        }
+
+        let certfp = credential.replace(':', "").to_ascii_lowercase();
+        if certfp.len() != 64 || !certfp.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "account {name} needs a hex SHA-256 certificate fingerprint, or a uid"
+            ));
+        }
+
+        Ok(Account {
+            name: name.to_owned(),
+            certfp: Some(certfp),
+            uid: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERTFP: &str = "f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7";
+
+    #[test]
+    fn test_parse() {
+        let account = format!("bot:{CERTFP}").parse::<Account>().unwrap();
+        assert_eq!(account.name, "bot");
+        assert!(account.matches_certfp(CERTFP));
+        assert!(account.matches_certfp(&CERTFP.to_ascii_uppercase()));
+
+        // as `openssl x509 -fingerprint -sha256` shows it
+        let colons = CERTFP
+            .as_bytes()
+            .chunks(2)
+            .map(|byte| std::str::from_utf8(byte).unwrap().to_ascii_uppercase())
+            .collect::<Vec<_>>()
+            .join(":");
+        let account = format!("bot:{colons}").parse::<Account>().unwrap();
+        assert_eq!(account.certfp.as_deref(), Some(CERTFP));
+
+        let account = "bot:uid=1000".parse::<Account>().unwrap();
+        assert!(account.matches_uid(1000));
+        assert!(!account.matches_uid(0));
+        assert!(!account.matches_certfp(CERTFP));
+
+        assert!("bot:abc".parse::<Account>().is_err());
+        assert!("bot:uid=me".parse::<Account>().is_err());
+        assert!(format!(":{CERTFP}").parse::<Account>().is_err());
+    }
+}
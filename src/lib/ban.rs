@@ -0,0 +1,359 @@
+//! Server bans
+//!
+//! K-lines keep users off the server by `user@host` mask. The host part can be
+//! a wildcard mask, an IP address or a CIDR range such as `10.0.0.0/8`. A mask
+//! without a `@` bans every user from that host.
+//!
+//! Bans can expire, and are saved to a file after every change, so they
+//! survive restarts. Each line of the file is one ban:
+//!
+//! ```text
+//! <mask>\t<set at>\t<expires at, 0 if never>\t<set by>\t<reason>
+//! ```
+use crate::mask::{self, HostMask};
+use anyhow::{anyhow, Result};
+use std::{
+    fmt::Display,
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// A `user@host` ban mask.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BanMask {
+    user: String,
+    host: HostMask,
+}
+
+impl BanMask {
+    /// Check whether a connection matches this mask.
+    ///
+    /// `user` is `None` while the username isn't known yet, in which case
+    /// only masks that ban every user of a host can match. `ip` is `None` for
+    /// a client on a Unix socket, which only has the server's own address;
+    /// only its host can match.
+    pub fn matches(&self, user: Option<&str>, ip: Option<IpAddr>, host: &str) -> bool {
+        let user_matches = match user {
+            Some(user) => mask::matches(&self.user, user),
+            None => self.user.chars().all(|c| c == '*'),
+        };
+        let host_matches = match ip {
+            Some(ip) => self.host.matches(ip, host),
+            None => self.host.matches_host(host),
+        };
+
+        user_matches && host_matches
+    }
+}
+
+impl FromStr for BanMask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, host) = s.rsplit_once('@').unwrap_or(("*", s));
+
+        if user.is_empty()
+            || host.is_empty()
+            || user.contains('@')
+            || s.contains(char::is_whitespace)
+        {
+            return Err(format!("invalid ban mask: {s}"));
+        }
+
+        let host = host.parse::<HostMask>()?;
+
+        Ok(BanMask {
+            user: user.to_owned(),
+            host,
+        })
+    }
+}
+
+impl Display for BanMask {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(fmt, "{}@{}", self.user, self.host)
+    }
+}
+
+/// A single server ban.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub mask: BanMask,
+    pub reason: String,
+    /// Who set the ban.
+    pub set_by: String,
+    /// When the ban was set, in seconds since the Unix epoch.
+    pub set_at: u64,
+    /// When the ban expires, in seconds since the Unix epoch, or `None` if it never does.
+    pub expires_at: Option<u64>,
+}
+
+impl Ban {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}",
+            self.mask,
+            self.set_at,
+            self.expires_at.unwrap_or(0),
+            self.set_by,
+            self.reason
+        )
+    }
+
+    fn from_line(line: &str) -> Result<Self> {
+        let mut fields = line.splitn(5, '\t');
+        let mut next = || fields.next().ok_or(anyhow!("missing field in ban: {line}"));
+
+        let mask = next()?.parse::<BanMask>().map_err(|err| anyhow!(err))?;
+        let set_at = next()?.parse::<u64>()?;
+        let expires_at = next()?.parse::<u64>()?;
+        let set_by = next()?.to_owned();
+        let reason = next()?.to_owned();
+
+        Ok(Ban {
+            mask,
+            reason,
+            set_by,
+            set_at,
+            expires_at: (expires_at != 0).then_some(expires_at),
+        })
+    }
+}
+
+struct BanListInner {
+    bans: Vec<Ban>,
+    path: Option<PathBuf>,
+}
+
+impl BanListInner {
+    fn purge_expired(&mut self) {
+        let now = unix_time();
+        self.bans.retain(|ban| !ban.is_expired(now));
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut contents = String::new();
+        for ban in &self.bans {
+            contents.push_str(&ban.to_line());
+            contents.push('\n');
+        }
+
+        // write to a temporary file first, so a crash can't leave half a ban list
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, contents)?;
+        std::fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+}
+
+/// The list of server bans, shared between the connection manager
+/// and the message handlers.
+pub struct BanList {
+    inner: Arc<Mutex<BanListInner>>,
+}
+
+impl Clone for BanList {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BanList {
+    /// Create an empty ban list that isn't saved anywhere.
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(BanListInner {
+                bans: Vec::new(),
+                path: None,
+            })),
+        }
+    }
+
+    /// Load the ban list from a file, which is created when the first ban is added.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+
+        let bans = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(Ban::from_line)
+                .collect::<Result<Vec<_>>>()?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut inner = BanListInner {
+            bans,
+            path: Some(path),
+        };
+        inner.purge_expired();
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(inner)),
+        })
+    }
+
+    /// Add a ban, replacing any existing ban with the same mask.
+    pub fn add(&self, ban: Ban) -> Result<()> {
+        let mut inner = self.inner.lock().expect("Failed to lock bans");
+        inner.purge_expired();
+        inner.bans.retain(|other| other.mask != ban.mask);
+        inner.bans.push(ban);
+        inner.save()
+    }
+
+    /// Remove the ban with the given mask, returning whether there was one.
+    pub fn remove(&self, mask: &BanMask) -> Result<bool> {
+        let mut inner = self.inner.lock().expect("Failed to lock bans");
+        inner.purge_expired();
+        let len = inner.bans.len();
+        inner.bans.retain(|ban| ban.mask != *mask);
+        let removed = inner.bans.len() != len;
+        inner.save()?;
+        Ok(removed)
+    }
+
+    /// Every ban that hasn't expired.
+    pub fn list(&self) -> Vec<Ban> {
+        let mut inner = self.inner.lock().expect("Failed to lock bans");
+        inner.purge_expired();
+        inner.bans.clone()
+    }
+
+    /// Find a ban matching a connection, see `BanMask::matches`.
+    pub fn find(&self, user: Option<&str>, ip: Option<IpAddr>, host: &str) -> Option<Ban> {
+        let now = unix_time();
+        let inner = self.inner.lock().expect("Failed to lock bans");
+        inner
+            .bans
+            .iter()
+            .find(|ban| !ban.is_expired(now) && ban.mask.matches(user, ip, host))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(mask: &str, expires_at: Option<u64>) -> Ban {
+        Ban {
+            mask: mask.parse().unwrap(),
+            reason: "spam".to_owned(),
+            set_by: "admin".to_owned(),
+            set_at: unix_time(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn test_parse_mask() {
+        assert_eq!(
+            "10.0.0.1".parse::<BanMask>().unwrap().to_string(),
+            "*@10.0.0.1"
+        );
+        assert_eq!(
+            "bot*@10.1.2.3/8".parse::<BanMask>().unwrap().to_string(),
+            "bot*@10.0.0.0/8"
+        );
+        assert_eq!(
+            "*@*.Example.com".parse::<BanMask>().unwrap().to_string(),
+            "*@*.example.com"
+        );
+        assert!("@host".parse::<BanMask>().is_err());
+        assert!("user@".parse::<BanMask>().is_err());
+        assert!("bad@@host".parse::<BanMask>().is_err());
+        assert!("*@10.0.0.0/99".parse::<BanMask>().is_err());
+    }
+
+    #[test]
+    fn test_match() {
+        let ip = Some("10.1.2.3".parse().unwrap());
+        let mask = "bot*@10.0.0.0/8".parse::<BanMask>().unwrap();
+        assert!(mask.matches(Some("bot1"), ip, "10.1.2.3"));
+        assert!(!mask.matches(Some("alice"), ip, "10.1.2.3"));
+        // the username isn't known yet, so only whole-host bans apply
+        assert!(!mask.matches(None, ip, "10.1.2.3"));
+
+        let mask = "2001:db8::/64".parse::<BanMask>().unwrap();
+        assert!(mask.matches(None, "2001:db8::1".parse().ok(), "2001:db8::1"));
+        assert!(!mask.matches(None, "2001:db8:0:1::1".parse().ok(), "2001:db8:0:1::1"));
+
+        let mask = "*@10.1.*".parse::<BanMask>().unwrap();
+        assert!(mask.matches(None, ip, "10.1.2.3"));
+
+        // a client on a Unix socket is only known by its host
+        let mask = "*@127.0.0.1".parse::<BanMask>().unwrap();
+        assert!(!mask.matches(None, None, "uid-1000.localhost"));
+        let mask = "*@uid-1000.localhost".parse::<BanMask>().unwrap();
+        assert!(mask.matches(None, None, "uid-1000.localhost"));
+        assert!(!mask.matches(None, None, "uid-1001.localhost"));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let ban_list = BanList::new();
+        ban_list
+            .add(ban("10.0.0.1", Some(unix_time() - 1)))
+            .unwrap();
+        ban_list
+            .add(ban("10.0.0.2", Some(unix_time() + 60)))
+            .unwrap();
+
+        assert!(ban_list
+            .find(None, "10.0.0.1".parse().ok(), "10.0.0.1")
+            .is_none());
+        assert!(ban_list
+            .find(None, "10.0.0.2".parse().ok(), "10.0.0.2")
+            .is_some());
+        assert_eq!(ban_list.list().len(), 1);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = std::env::temp_dir().join(format!("iris-bans-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let ban_list = BanList::load(&path).unwrap();
+        ban_list.add(ban("bot@10.0.0.0/8", None)).unwrap();
+        ban_list
+            .add(ban("*@*.example.com", Some(unix_time() + 60)))
+            .unwrap();
+        ban_list.remove(&"bot@10.0.0.0/8".parse().unwrap()).unwrap();
+        ban_list.add(ban("10.0.0.1", None)).unwrap();
+
+        let loaded = BanList::load(&path).unwrap();
+        assert_eq!(loaded.list(), ban_list.list());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
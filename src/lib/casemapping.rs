@@ -0,0 +1,116 @@
+//! Casemapping
+//!
+//! IRC compares nicks and channel names case-insensitively. Which characters
+//! count as "the same" depends on the casemapping the server advertises to
+//! clients in `RPL_ISUPPORT` (`CASEMAPPING=...`).
+//!
+//! The casemapping is chosen once at startup with `set_casemapping`, and every
+//! `Nick` and `Channel` comparison goes through `casemapping()`.
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// The casemappings supported by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CaseMapping {
+    /// `A-Z` map to `a-z`, and `[]\~` map to `{}|^`.
+    #[default]
+    Rfc1459,
+    /// Only `A-Z` map to `a-z`.
+    Ascii,
+}
+
+static CASEMAPPING: AtomicU8 = AtomicU8::new(CaseMapping::Rfc1459 as u8);
+
+/// Set the casemapping used by the whole server.
+///
+/// This should be called before any connection is accepted, because changing it
+/// afterwards would change the identity of nicks and channels already in use.
+pub fn set_casemapping(casemapping: CaseMapping) {
+    CASEMAPPING.store(casemapping as u8, Ordering::Relaxed);
+}
+
+/// Get the casemapping used by the whole server.
+pub fn casemapping() -> CaseMapping {
+    match CASEMAPPING.load(Ordering::Relaxed) {
+        x if x == CaseMapping::Ascii as u8 => CaseMapping::Ascii,
+        _ => CaseMapping::Rfc1459,
+    }
+}
+
+impl CaseMapping {
+    /// Lowercase a single character.
+    pub fn to_lower(self, c: char) -> char {
+        match (self, c) {
+            (_, 'A'..='Z') => c.to_ascii_lowercase(),
+            (CaseMapping::Rfc1459, '[') => '{',
+            (CaseMapping::Rfc1459, ']') => '}',
+            (CaseMapping::Rfc1459, '\\') => '|',
+            (CaseMapping::Rfc1459, '~') => '^',
+            _ => c,
+        }
+    }
+
+    /// Fold a string, so that two strings which are equal under this
+    /// casemapping fold to the same value.
+    pub fn fold(self, value: &str) -> String {
+        value.chars().map(|c| self.to_lower(c)).collect()
+    }
+
+    /// Check whether two strings are equal under this casemapping.
+    pub fn equals(self, a: &str, b: &str) -> bool {
+        a.len() == b.len()
+            && a
+                .chars()
+                .zip(b.chars())
+                .all(|(a, b)| self.to_lower(a) == self.to_lower(b))
+    }
+}
+
+impl std::fmt::Display for CaseMapping {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            CaseMapping::Rfc1459 => write!(fmt, "rfc1459"),
+            CaseMapping::Ascii => write!(fmt, "ascii"),
+        }
+    }
+}
+
+impl FromStr for CaseMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rfc1459" => Ok(CaseMapping::Rfc1459),
+            "ascii" => Ok(CaseMapping::Ascii),
+            _ => Err(format!("unknown casemapping: {s}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ascii_fold() {
+        assert_eq!(CaseMapping::Ascii.fold("AlIcE[]"), "alice[]");
+        assert!(CaseMapping::Ascii.equals("Alice", "aLICE"));
+        assert!(!CaseMapping::Ascii.equals("a[b]", "a{b}"));
+    }
+
+    #[test]
+    fn test_rfc1459_fold() {
+        assert_eq!(CaseMapping::Rfc1459.fold("Al[]\\~"), "al{}|^");
+        assert!(CaseMapping::Rfc1459.equals("a[b]", "A{B}"));
+        assert!(!CaseMapping::Rfc1459.equals("alice", "alicE2"));
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("RFC1459".parse(), Ok(CaseMapping::Rfc1459));
+        assert_eq!("ascii".parse(), Ok(CaseMapping::Ascii));
+        assert!("strict-rfc1459".parse::<CaseMapping>().is_err());
+    }
+}
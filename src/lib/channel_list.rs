@@ -1,10 +1,21 @@
 //! This module contains the ChannelList struct which is used to 
 //! keep track of which users are in which channels.
-use std::collections::HashMap;
+//!
+//! Channel names are compared using the server's casemapping,
+//! so `#Team` and `#team` are the same channel. Members are kept by
+//! their `UserId`, which stays the same when they change nick.
+use crate::{casemapping::casemapping, types::UserId};
+use std::collections::{HashMap, HashSet};
+
+/// A single channel: its name as it was first given, and its members.
+struct ChannelEntry {
+    name: String,
+    users: HashSet<UserId>,
+}
 
 /// This struct is used to keep track of which users are in which channels.
 pub struct ChannelList {
-    channels: HashMap<String, Vec<String>>,
+    channels: HashMap<String, ChannelEntry>,
 }
 
 impl Default for ChannelList {
@@ -20,18 +31,24 @@ impl ChannelList {
         }
     }
 
+    fn key(channel_name: &str) -> String {
+        casemapping().fold(channel_name)
+    }
+
     pub fn has_channel(&self, channel_name: &str) -> bool {
-        self.channels.contains_key(channel_name)
+        self.channels.contains_key(&Self::key(channel_name))
     }
 
-    pub fn has_user(&self, channel_name: &str, user_id: &str) -> bool {
-        if !self.has_channel(channel_name) {
-            return false;
-        }
-        
-        // it's ok to use unwrap here because we already checked that the channel exists
-        let channel = self.channels.get(channel_name).unwrap();
-        channel.contains(&user_id.to_owned())
+    /// Get the name of a channel in the case it was created with.
+    pub fn get_name(&self, channel_name: &str) -> Option<&str> {
+        self.channels
+            .get(&Self::key(channel_name))
+            .map(|channel| channel.name.as_str())
+    }
+
+    pub fn has_user(&self, channel_name: &str, user_id: UserId) -> bool {
+        self.get_users(channel_name)
+            .is_some_and(|users| users.contains(&user_id))
     }
 
     pub fn add_channel(&mut self, channel_name: String) {
@@ -39,36 +56,57 @@ impl ChannelList {
             return;
         }
 
-        self.channels.insert(channel_name, Vec::new());
+        self.channels.insert(
+            Self::key(&channel_name),
+            ChannelEntry {
+                name: channel_name,
+                users: HashSet::new(),
+            },
+        );
     }
 
-    pub fn get_users_mut(&mut self, channel_name: &str) -> Option<&mut Vec<String>> {
-        self.channels.get_mut(channel_name)
+    /// The ids of a channel's members.
+    pub fn get_users(&self, channel_name: &str) -> Option<&HashSet<UserId>> {
+        self.channels
+            .get(&Self::key(channel_name))
+            .map(|channel| &channel.users)
     }
 
-    pub fn join_channel(&mut self, channel_name: &str, user_id: &str) {
+    pub fn join_channel(&mut self, channel_name: &str, user_id: UserId) {
         if !self.has_channel(channel_name) {
             self.add_channel(channel_name.to_owned());
         }
 
         // it's ok to use unwrap here because we already checked that the channel exists
-        let channel_users = self.get_users_mut(channel_name).unwrap();
-        channel_users.push(user_id.to_owned());
+        let channel = self.channels.get_mut(&Self::key(channel_name)).unwrap();
+        channel.users.insert(user_id);
     }
 
-    pub fn part_channel(&mut self, channel_name: &str, user_id: &str) {
-        if !self.has_channel(channel_name) {
+    pub fn part_channel(&mut self, channel_name: &str, user_id: UserId) {
+        let key = Self::key(channel_name);
+        let Some(channel) = self.channels.get_mut(&key) else {
             return;
+        };
+
+        channel.users.remove(&user_id);
+
+        // nobody is left to talk in an empty channel
+        if channel.users.is_empty() {
+            self.channels.remove(&key);
         }
+    }
 
-        // it's ok to use unwrap here because we already checked that the channel exists
-        let channel_users = self.get_users_mut(channel_name).unwrap();
-        channel_users.retain(|id| id != user_id);
+    /// Every channel, by the name it was created with, with its members.
+    pub fn channels(&self) -> impl Iterator<Item = (&str, &HashSet<UserId>)> {
+        self.channels
+            .values()
+            .map(|channel| (channel.name.as_str(), &channel.users))
     }
 
-    pub fn remove_user(&mut self, user_id: &str) {
-        for (_, channel_users) in self.channels.iter_mut() {
-            channel_users.retain(|id| id != user_id);
+    /// Remove a user from the given channels, dropping channels that become empty.
+    pub fn remove_user(&mut self, user_id: UserId, channel_names: &[String]) {
+        for channel_name in channel_names {
+            self.part_channel(channel_name, user_id);
         }
     }
 }
@@ -89,33 +127,49 @@ mod tests {
     #[test]
     fn test_join_channel() {
         let mut channel_list = ChannelList::new();
-        channel_list.join_channel("channel1", "user1");
+        channel_list.join_channel("channel1", UserId(1));
 
         assert!(channel_list.has_channel("channel1"));
-        assert!(channel_list.has_user("channel1", "user1"));
+        assert!(channel_list.has_user("channel1", UserId(1)));
     }
 
     #[test]
     fn test_part_channel() {
         let mut channel_list = ChannelList::new();
-        channel_list.join_channel("channel1", "user1");
-        channel_list.join_channel("channel1", "user2");
-        channel_list.part_channel("channel1", "user1");
+        channel_list.join_channel("channel1", UserId(1));
+        channel_list.join_channel("channel1", UserId(2));
+        channel_list.part_channel("channel1", UserId(1));
 
         assert!(channel_list.has_channel("channel1"));
-        assert!(!channel_list.has_user("channel1", "user1"));
-        assert!(channel_list.has_user("channel1", "user2"));
+        assert!(!channel_list.has_user("channel1", UserId(1)));
+        assert!(channel_list.has_user("channel1", UserId(2)));
     }
 
     #[test]
     fn test_remove_user() {
         let mut channel_list = ChannelList::new();
-        channel_list.join_channel("channel1", "user1");
-        channel_list.join_channel("channel2", "user1");
-        channel_list.remove_user("user1");
+        channel_list.join_channel("channel1", UserId(1));
+        channel_list.join_channel("channel2", UserId(1));
+        channel_list.remove_user(UserId(1), &["channel1".to_owned(), "channel2".to_owned()]);
 
-        assert!(!channel_list.has_user("channel1", "user1"));
-        assert!(!channel_list.has_user("channel2", "user1"));
+        assert!(!channel_list.has_user("channel1", UserId(1)));
+        assert!(!channel_list.has_user("channel2", UserId(1)));
+    }
+
+    #[test]
+    fn test_empty_channels_are_dropped() {
+        let mut channel_list = ChannelList::new();
+        channel_list.join_channel("channel1", UserId(1));
+        channel_list.join_channel("channel2", UserId(1));
+        channel_list.join_channel("channel2", UserId(2));
+
+        channel_list.part_channel("channel1", UserId(1));
+        assert!(!channel_list.has_channel("channel1"));
+
+        channel_list.remove_user(UserId(2), &["channel2".to_owned()]);
+        assert!(channel_list.has_channel("channel2"));
+        channel_list.remove_user(UserId(1), &["channel2".to_owned()]);
+        assert!(!channel_list.has_channel("channel2"));
     }
 
     #[test]
@@ -130,9 +184,24 @@ mod tests {
     #[test]
     fn test_has_user() {
         let mut channel_list = ChannelList::new();
-        channel_list.join_channel("channel1", "user1");
+        channel_list.join_channel("channel1", UserId(1));
+
+        assert!(channel_list.has_user("channel1", UserId(1)));
+        assert!(!channel_list.has_user("channel1", UserId(2)));
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let mut channel_list = ChannelList::new();
+        channel_list.join_channel("#Team", UserId(1));
+        channel_list.join_channel("#team", UserId(1));
+
+        assert!(channel_list.has_channel("#TEAM"));
+        assert_eq!(channel_list.get_name("#team"), Some("#Team"));
+        assert!(channel_list.has_user("#team", UserId(1)));
+        assert_eq!(channel_list.get_users("#team").unwrap().len(), 1);
 
-        assert!(channel_list.has_user("channel1", "user1"));
-        assert!(!channel_list.has_user("channel1", "user2"));
+        channel_list.part_channel("#tEaM", UserId(1));
+        assert!(!channel_list.has_user("#Team", UserId(1)));
     }
 }
@@ -0,0 +1,93 @@
+//! Host cloaking
+//!
+//! Users are never shown each other's IP addresses. Instead, every user gets a
+//! cloaked host such as `3F2A81C0.9D04E6B7.5A1C2E88.IP`, made from a keyed
+//! HMAC of their IP. The same IP always gets the same cloak for the same key,
+//! so bans and ignore lists keep working, but the IP can't be worked out from
+//! the cloak without the key.
+//!
+//! Operators can still see a user's real address with `WHOIS`.
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::IpAddr;
+
+/// How many bytes a randomly generated key has.
+const RANDOM_KEY_LEN: usize = 32;
+
+/// Turns IP addresses into cloaked hosts.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Cloak {
+    key: Vec<u8>,
+}
+
+impl std::fmt::Debug for Cloak {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the key is secret, so keep it out of logs
+        f.debug_struct("Cloak").finish_non_exhaustive()
+    }
+}
+
+impl Default for Cloak {
+    fn default() -> Self {
+        Self::random()
+    }
+}
+
+impl Cloak {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        Self { key: key.into() }
+    }
+
+    /// Use a random key. Cloaks will change whenever the server restarts.
+    pub fn random() -> Self {
+        let mut key = vec![0; RANDOM_KEY_LEN];
+        getrandom::getrandom(&mut key).expect("Failed to generate a cloak key");
+        Self { key }
+    }
+
+    /// The cloaked host for `ip`.
+    pub fn cloak(&self, ip: IpAddr) -> String {
+        // an IPv4 address mapped into IPv6 is the same host as the IPv4 address
+        let ip = match ip {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+            ip => ip,
+        };
+
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(ip.to_string().as_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        let parts = hash[..12]
+            .chunks(4)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .map(|byte| format!("{byte:02X}"))
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>();
+
+        format!("{}.IP", parts.join("."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cloak() {
+        let cloak = Cloak::new("secret");
+        let ip = "10.0.0.1".parse().unwrap();
+
+        let host = cloak.cloak(ip);
+        assert_eq!(host, cloak.cloak(ip));
+        assert!(host.ends_with(".IP"));
+        assert!(!host.contains("10.0.0.1"));
+
+        assert_ne!(host, cloak.cloak("10.0.0.2".parse().unwrap()));
+        assert_ne!(host, Cloak::new("other").cloak(ip));
+        assert_eq!(host, cloak.cloak("::ffff:10.0.0.1".parse().unwrap()));
+    }
+}
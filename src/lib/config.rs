@@ -0,0 +1,418 @@
+//! Server configuration
+//!
+//! This module contains the settings the message handlers need
+//! while the server is running.
+use crate::{
+    account::Account, cloak::Cloak, connect::DEFAULT_SENDQ, flood::FloodConfig,
+    keepalive::KeepaliveConfig, mask::HostMask, operator::Operator, split::LongMessages,
+    webirc::WebircGateway,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+/// Settings used by the message handlers.
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    /// Everyone who may become an IRC operator with `OPER`.
+    pub operators: Vec<Operator>,
+    /// Accounts users can log in to with SASL `EXTERNAL`, or by connecting
+    /// to a trusting Unix socket.
+    pub accounts: Vec<Account>,
+    /// Connection classes, checked in order. Connections that match none
+    /// get the default class.
+    pub classes: Vec<ConnectionClass>,
+    pub flood: FloodConfig,
+    /// Gateways trusted to say who their users are with `WEBIRC`.
+    pub gateways: Vec<WebircGateway>,
+    /// Turns users' IP addresses into the hosts shown to others.
+    pub cloak: Cloak,
+    /// The password clients must give with `PASS` to register, if any.
+    pub password: Option<String>,
+    /// What to do with messages that would be too long once relayed.
+    pub long_messages: LongMessages,
+    /// The STS policy advertised to clients, if any.
+    pub sts: Option<StsPolicy>,
+    /// How long a shutdown waits for clients to be sent what they have left
+    /// to receive, before exiting anyway.
+    pub shutdown_grace: Duration,
+    /// Where plugins save their state when the server shuts down, if anywhere.
+    pub plugin_state_file: Option<PathBuf>,
+    /// The plugins users may use, by name.
+    pub plugins: Vec<String>,
+    /// The lines of the message of the day, if there is one.
+    pub motd: Option<Vec<String>>,
+    /// When to ping idle users, and drop connections that time out.
+    pub keepalive: KeepaliveConfig,
+}
+
+/// An IRCv3 Strict Transport Security policy: clients that see it
+/// should only connect to the server over TLS from then on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StsPolicy {
+    /// The TLS port plaintext clients should move to.
+    pub port: u16,
+    /// How long clients should remember the policy.
+    pub duration: Duration,
+}
+
+impl StsPolicy {
+    /// The `sts` capability, with the value for a client connected over TLS or not.
+    pub fn capability(&self, secure: bool) -> String {
+        // plaintext clients reconnect over TLS, and only take the policy from there
+        if secure {
+            format!("sts=duration={}", self.duration.as_secs())
+        } else {
+            format!("sts=port={}", self.port)
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn find_operator(&self, name: &str) -> Option<&Operator> {
+        self.operators.iter().find(|oper| oper.name == name)
+    }
+
+    /// Find the account bound to a client certificate.
+    pub fn find_account_by_certfp(&self, certfp: &str) -> Option<&Account> {
+        self.accounts
+            .iter()
+            .find(|account| account.matches_certfp(certfp))
+    }
+
+    pub fn find_gateway(&self, name: &str) -> Option<&WebircGateway> {
+        self.gateways.iter().find(|gateway| gateway.name == name)
+    }
+
+    /// Find the account bound to a local user id.
+    pub fn find_account_by_uid(&self, uid: u32) -> Option<&Account> {
+        self.accounts
+            .iter()
+            .find(|account| account.matches_uid(uid))
+    }
+
+    /// Find the connection class for a connection from `ip`, known as `host`.
+    pub fn class_for(&self, ip: IpAddr, host: &str) -> ConnectionClass {
+        self.classes
+            .iter()
+            .find(|class| class.host_mask.matches(ip, host))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Find a connection class by name.
+    pub fn find_class(&self, name: &str) -> Option<&ConnectionClass> {
+        self.classes.iter().find(|class| class.name == name)
+    }
+}
+
+/// The server configuration, which can be replaced while the server runs.
+///
+/// Everyone takes the current configuration with `get` and keeps it for
+/// whatever they are doing, so a reload never takes effect halfway through.
+#[derive(Debug, Clone, Default)]
+pub struct SharedConfig(Arc<RwLock<Arc<ServerConfig>>>);
+
+impl SharedConfig {
+    pub fn new(config: ServerConfig) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    /// The current configuration.
+    pub fn get(&self) -> Arc<ServerConfig> {
+        self.0.read().expect("Failed to lock config").clone()
+    }
+
+    /// Replace the configuration, for everything that takes it from now on.
+    pub fn replace(&self, config: ServerConfig) {
+        *self.0.write().expect("Failed to lock config") = Arc::new(config);
+    }
+}
+
+/// An address the server listens for clients on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerConfig {
+    pub address: ListenAddress,
+    /// Whether clients connect over TLS.
+    pub tls: bool,
+    /// Whether clients connect over WebSocket.
+    pub websocket: bool,
+    /// The origins WebSocket clients may connect from. Empty allows any.
+    pub origins: Vec<String>,
+    /// The connection class everyone connecting here is put in, instead of
+    /// the one their host matches.
+    pub class: Option<String>,
+    /// Only accept IPv6 clients on an IPv6 address. Otherwise the unspecified
+    /// address `[::]` takes IPv4 clients too.
+    pub ipv6_only: bool,
+    /// The permissions of a Unix socket, e.g. `0o660`. If not given, they
+    /// follow the umask.
+    pub mode: Option<u32>,
+    /// Log clients of a Unix socket in to the account bound to their user id.
+    pub trust_peer: bool,
+    /// Connections start with a PROXY protocol header giving the client's
+    /// address. Only for listeners that just a trusted proxy can reach.
+    pub proxy: bool,
+}
+
+/// Where a listener listens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    /// The path of a Unix socket.
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for ListenAddress {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ListenAddress::Tcp(address) => write!(fmt, "{address}"),
+            ListenAddress::Unix(path) => write!(fmt, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Parse a listener from `<address>:<port>[,<option>...]`, with IPv6
+/// addresses in brackets, e.g. `[::]:6697,tls`, or from
+/// `unix:<path>[,<option>...]` for a Unix socket.
+///
+/// The options are:
+/// - `tls`: clients connect over TLS.
+/// - `websocket`: clients connect over WebSocket, after TLS if there is any.
+/// - `origin=<origin>`: an origin WebSocket clients may connect from, e.g.
+///   `https://irc.example.com`. Can be repeated; without it, any is allowed.
+/// - `class=<name>`: the connection class of the listener.
+/// - `ipv6-only`: don't accept IPv4 clients on an IPv6 address.
+/// - `proxy`: connections start with a PROXY protocol (v1 or v2) header.
+/// - `mode=<octal>`: the permissions of a Unix socket.
+/// - `trust-peer`: log clients of a Unix socket in by their user id.
+impl FromStr for ListenerConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = s.split(',');
+        let address = options.next().unwrap_or_default();
+
+        let mut listener = ListenerConfig {
+            address: match address.strip_prefix("unix:") {
+                Some("") => return Err("unix listener needs a path".to_owned()),
+                Some(path) => ListenAddress::Unix(path.into()),
+                None => ListenAddress::Tcp(
+                    address
+                        .parse()
+                        .map_err(|_| format!("invalid listen address: {address}"))?,
+                ),
+            },
+            tls: false,
+            websocket: false,
+            origins: Vec::new(),
+            class: None,
+            ipv6_only: false,
+            mode: None,
+            trust_peer: false,
+            proxy: false,
+        };
+        let unix = matches!(listener.address, ListenAddress::Unix(_));
+
+        for option in options {
+            match option {
+                "tls" if !unix => listener.tls = true,
+                "websocket" if !unix => listener.websocket = true,
+                "ipv6-only" if !unix => listener.ipv6_only = true,
+                "proxy" if !unix => listener.proxy = true,
+                "trust-peer" if unix => listener.trust_peer = true,
+                _ if !unix && option.starts_with("origin=") => {
+                    listener.origins.push(option["origin=".len()..].to_owned());
+                }
+                _ if option.starts_with("class=") => {
+                    listener.class = Some(option["class=".len()..].to_owned());
+                }
+                _ if unix && option.starts_with("mode=") => {
+                    listener.mode = Some(
+                        u32::from_str_radix(&option["mode=".len()..], 8)
+                            .map_err(|_| format!("invalid socket mode: {option}"))?,
+                    );
+                }
+                _ => return Err(format!("unknown {address} listener option: {option}")),
+            }
+        }
+
+        if !listener.origins.is_empty() && !listener.websocket {
+            return Err(format!("only websocket listeners have origins: {s}"));
+        }
+
+        Ok(listener)
+    }
+}
+
+/// A group of connections, chosen by host, that share the same limits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionClass {
+    pub name: String,
+    pub host_mask: HostMask,
+    /// Whether connections in this class are exempt from flood control.
+    pub flood_exempt: bool,
+    /// How many bytes may wait to be sent to a connection before it is disconnected.
+    pub sendq: usize,
+}
+
+impl Default for ConnectionClass {
+    fn default() -> Self {
+        Self {
+            name: "default".to_owned(),
+            host_mask: HostMask::Wildcard("*".to_owned()),
+            flood_exempt: false,
+            sendq: DEFAULT_SENDQ,
+        }
+    }
+}
+
+/// Parse a connection class from `<name>=<host mask>[,<option>...]`.
+///
+/// The options are:
+/// - `flood-exempt`: exempt the class from flood control.
+/// - `sendq=<bytes>`: the SendQ limit of the class.
+impl FromStr for ConnectionClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rest) = s
+            .split_once('=')
+            .ok_or_else(|| format!("connection class needs a host mask: {s}"))?;
+        let mut options = rest.split(',');
+
+        let mut class = ConnectionClass {
+            name: name.to_owned(),
+            host_mask: options.next().unwrap_or_default().parse()?,
+            ..Default::default()
+        };
+
+        for option in options {
+            match option {
+                "flood-exempt" => class.flood_exempt = true,
+                _ if option.starts_with("sendq=") => {
+                    class.sendq = option["sendq=".len()..]
+                        .parse()
+                        .map_err(|_| format!("invalid sendq: {option}"))?;
+                }
+                _ => return Err(format!("unknown connection class option: {option}")),
+            }
+        }
+
+        Ok(class)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_class() {
+        let class = "bots=2001:db8::/64,flood-exempt,sendq=1024"
+            .parse::<ConnectionClass>()
+            .unwrap();
+        assert_eq!(class.name, "bots");
+        assert_eq!(class.host_mask.to_string(), "2001:db8::/64");
+        assert!(class.flood_exempt);
+        assert_eq!(class.sendq, 1024);
+        assert_eq!(
+            "bots=*".parse::<ConnectionClass>().unwrap().sendq,
+            DEFAULT_SENDQ
+        );
+
+        assert!("bots".parse::<ConnectionClass>().is_err());
+        assert!("bots=*,fast".parse::<ConnectionClass>().is_err());
+        assert!("bots=*,sendq=lots".parse::<ConnectionClass>().is_err());
+    }
+
+    #[test]
+    fn test_class_for() {
+        let config = ServerConfig {
+            classes: vec!["local=127.0.0.0/8,flood-exempt".parse().unwrap()],
+            ..Default::default()
+        };
+
+        let local = config.class_for("127.0.0.1".parse().unwrap(), "127.0.0.1");
+        assert_eq!(local.name, "local");
+        let other = config.class_for("10.0.0.1".parse().unwrap(), "10.0.0.1");
+        assert_eq!(other.name, "default");
+    }
+
+    #[test]
+    fn test_parse_listener() {
+        let listener = "[::]:6697,tls,class=secure"
+            .parse::<ListenerConfig>()
+            .unwrap();
+        assert_eq!(
+            listener.address,
+            ListenAddress::Tcp("[::]:6697".parse().unwrap())
+        );
+        assert!(listener.tls);
+        assert_eq!(listener.class.as_deref(), Some("secure"));
+        assert!(!listener.ipv6_only);
+
+        let listener = "127.0.0.1:6667".parse::<ListenerConfig>().unwrap();
+        assert!(!listener.tls);
+        assert!(!listener.proxy);
+        assert!(
+            "127.0.0.1:6667,proxy"
+                .parse::<ListenerConfig>()
+                .unwrap()
+                .proxy
+        );
+        assert_eq!(listener.class, None);
+        assert!(
+            "[::1]:6667,ipv6-only"
+                .parse::<ListenerConfig>()
+                .unwrap()
+                .ipv6_only
+        );
+
+        assert!("::1:6667".parse::<ListenerConfig>().is_err());
+        assert!("127.0.0.1".parse::<ListenerConfig>().is_err());
+        assert!("127.0.0.1:6667,ssl".parse::<ListenerConfig>().is_err());
+    }
+
+    #[test]
+    fn test_parse_websocket_listener() {
+        let listener = "[::]:8097,tls,websocket,origin=https://a.example,origin=https://b.example"
+            .parse::<ListenerConfig>()
+            .unwrap();
+        assert!(listener.tls);
+        assert!(listener.websocket);
+        assert_eq!(listener.origins, ["https://a.example", "https://b.example"]);
+
+        assert!("[::]:8097,origin=https://a.example"
+            .parse::<ListenerConfig>()
+            .is_err());
+        assert!("unix:iris.sock,websocket"
+            .parse::<ListenerConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_unix_listener() {
+        let listener = "unix:/run/iris/bots.sock,mode=660,trust-peer,class=bots"
+            .parse::<ListenerConfig>()
+            .unwrap();
+        assert_eq!(
+            listener.address,
+            ListenAddress::Unix("/run/iris/bots.sock".into())
+        );
+        assert_eq!(listener.mode, Some(0o660));
+        assert!(listener.trust_peer);
+        assert_eq!(listener.class.as_deref(), Some("bots"));
+
+        assert!("unix:".parse::<ListenerConfig>().is_err());
+        assert!("unix:iris.sock,tls".parse::<ListenerConfig>().is_err());
+        assert!("unix:iris.sock,mode=rw".parse::<ListenerConfig>().is_err());
+        assert!("127.0.0.1:6667,trust-peer"
+            .parse::<ListenerConfig>()
+            .is_err());
+    }
+}
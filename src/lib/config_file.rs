@@ -0,0 +1,280 @@
+//! Configuration file
+//!
+//! Instead of giving everything on the command line, the server can be given
+//! a TOML file with its settings. It is read when the server starts, and again
+//! on SIGHUP, when whatever can change while the server runs is applied.
+//!
+//! ```toml
+//! server_name = "irc.example.net"
+//! log_level = "info"
+//! motd = "/etc/iris/motd.txt"
+//! listen = ["[::]:6667", "[::]:6697,tls"]
+//! operators = ["admin:<sha256 password hash>:*@10.0.0.0/8"]
+//!
+//! [limits]
+//! nick_len = 16
+//! max_clients = 500
+//! ping_interval = 180
+//!
+//! [plugins]
+//! enabled = ["reminder"]
+//! state_file = "/var/lib/iris/plugin.state"
+//! ```
+//!
+//! Lists are written the same way as the command-line options they match,
+//! and durations are in seconds. Everything may be left out, and anything
+//! given on the command line takes precedence over the file.
+use crate::{
+    account::Account,
+    config::{ConnectionClass, ListenerConfig},
+    operator::Operator,
+    plugin::PLUGINS,
+    split::MAX_LINE_LEN,
+    webirc::WebircGateway,
+};
+use anyhow::{anyhow, Context, Result};
+use log::LevelFilter;
+use serde::{de::Error, Deserialize, Deserializer};
+use std::{
+    fmt::Display,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// The lengths a nick may be limited to.
+pub const NICK_LEN_RANGE: RangeInclusive<usize> = 1..=32;
+
+/// The lengths a line from a client may be limited to. Clients can't be
+/// expected to keep to anything shorter than the standard 512 bytes.
+pub const LINE_LEN_RANGE: RangeInclusive<usize> = MAX_LINE_LEN..=8192;
+
+/// The settings in a configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Can only be set when the server starts.
+    pub server_name: Option<String>,
+    #[serde(default, deserialize_with = "parse")]
+    pub log_level: Option<LevelFilter>,
+    /// A file with the message of the day.
+    pub motd: Option<PathBuf>,
+    /// Can only be set when the server starts.
+    #[serde(default, rename = "listen", deserialize_with = "parse_list")]
+    pub listeners: Option<Vec<ListenerConfig>>,
+    #[serde(default, deserialize_with = "parse_list")]
+    pub operators: Option<Vec<Operator>>,
+    #[serde(default, deserialize_with = "parse_list")]
+    pub accounts: Option<Vec<Account>>,
+    #[serde(default, rename = "webirc", deserialize_with = "parse_list")]
+    pub gateways: Option<Vec<WebircGateway>>,
+    #[serde(default, deserialize_with = "parse_list")]
+    pub classes: Option<Vec<ConnectionClass>>,
+    #[serde(default)]
+    pub limits: LimitsSection,
+    #[serde(default)]
+    pub plugins: PluginsSection,
+}
+
+/// The `[limits]` section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsSection {
+    pub nick_len: Option<usize>,
+    /// Can only be set when the server starts.
+    pub line_len: Option<usize>,
+    pub max_clients: Option<usize>,
+    pub max_per_host: Option<usize>,
+    pub throttle_count: Option<usize>,
+    pub throttle_window: Option<u64>,
+    pub ping_interval: Option<u64>,
+    pub ping_timeout: Option<u64>,
+    pub registration_timeout: Option<u64>,
+    pub flood_burst: Option<u64>,
+    pub recvq: Option<usize>,
+}
+
+/// The `[plugins]` section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginsSection {
+    /// The plugins users may use, by name.
+    pub enabled: Option<Vec<String>>,
+    pub state_file: Option<PathBuf>,
+}
+
+impl ConfigFile {
+    /// Read and check the configuration file at `path`.
+    ///
+    /// The error says what is wrong, and where in the file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        let file = toml::from_str::<ConfigFile>(&contents)
+            .with_context(|| format!("Invalid configuration in {}", path.display()))?;
+        file.check()
+            .with_context(|| format!("Invalid configuration in {}", path.display()))?;
+
+        Ok(file)
+    }
+
+    /// Check the settings that can be read but still make no sense.
+    fn check(&self) -> Result<()> {
+        if let Some(name) = &self.server_name {
+            check_server_name(name).map_err(|err| anyhow!("server_name: {err}"))?;
+        }
+        if let Some(nick_len) = self.limits.nick_len {
+            check_nick_len(nick_len).map_err(|err| anyhow!("limits.nick_len: {err}"))?;
+        }
+        if let Some(line_len) = self.limits.line_len {
+            check_line_len(line_len).map_err(|err| anyhow!("limits.line_len: {err}"))?;
+        }
+        for plugin in self.plugins.enabled.iter().flatten() {
+            check_plugin(plugin).map_err(|err| anyhow!("plugins.enabled: {err}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Check that `name` can be used as the name of the server.
+pub fn check_server_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.len() <= 63
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+    if !valid {
+        return Err(format!(
+            "{name:?} is not a valid server name, it should look like a host name"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that nicks may be limited to `nick_len`.
+pub fn check_nick_len(nick_len: usize) -> Result<(), String> {
+    if !NICK_LEN_RANGE.contains(&nick_len) {
+        return Err(format!(
+            "the nick length must be between {} and {}",
+            NICK_LEN_RANGE.start(),
+            NICK_LEN_RANGE.end()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that lines from clients may be limited to `line_len`.
+pub fn check_line_len(line_len: usize) -> Result<(), String> {
+    if !LINE_LEN_RANGE.contains(&line_len) {
+        return Err(format!(
+            "the line length must be between {} and {}",
+            LINE_LEN_RANGE.start(),
+            LINE_LEN_RANGE.end()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that there is a plugin called `name`.
+pub fn check_plugin(name: &str) -> Result<(), String> {
+    if !PLUGINS.contains(&name) {
+        return Err(format!(
+            "unknown plugin {name:?}, expected one of {}",
+            PLUGINS.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Deserialize a string with the type's `FromStr`.
+fn parse<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let Some(value) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+
+    value
+        .parse()
+        .map(Some)
+        .map_err(|err| D::Error::custom(format!("invalid value {value:?}: {err}")))
+}
+
+/// Deserialize a list of strings with the type's `FromStr`.
+fn parse_list<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let Some(values) = Option::<Vec<String>>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+
+    values
+        .iter()
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|err| D::Error::custom(format!("invalid value {value:?}: {err}")))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config_file() {
+        let file = toml::from_str::<ConfigFile>(
+            r#"
+            server_name = "irc.example.net"
+            log_level = "debug"
+            listen = ["127.0.0.1:6667", "[::]:6697,tls"]
+            operators = ["admin:f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7"]
+
+            [limits]
+            nick_len = 16
+
+            [plugins]
+            enabled = ["reminder"]
+            "#,
+        )
+        .unwrap();
+        file.check().unwrap();
+
+        assert_eq!(file.server_name.as_deref(), Some("irc.example.net"));
+        assert_eq!(file.log_level, Some(LevelFilter::Debug));
+        assert_eq!(file.listeners.unwrap().len(), 2);
+        assert_eq!(file.operators.unwrap()[0].name, "admin");
+        assert_eq!(file.limits.nick_len, Some(16));
+        assert_eq!(file.limits.max_clients, None);
+        assert_eq!(file.plugins.enabled, Some(vec!["reminder".to_owned()]));
+        assert_eq!(file.motd, None);
+    }
+
+    #[test]
+    fn test_invalid_config_file() {
+        // a misspelt setting is an error, rather than being ignored
+        assert!(toml::from_str::<ConfigFile>("nick_len = 16").is_err());
+        assert!(toml::from_str::<ConfigFile>("listen = [\"nowhere\"]").is_err());
+        assert!(toml::from_str::<ConfigFile>("log_level = \"loud\"").is_err());
+
+        let check = |contents| toml::from_str::<ConfigFile>(contents).unwrap().check();
+        assert!(check("server_name = \"my server\"").is_err());
+        assert!(check("[limits]\nnick_len = 0").is_err());
+        assert!(check("[limits]\nline_len = 100").is_err());
+        assert!(check("[plugins]\nenabled = [\"weather\"]").is_err());
+    }
+}
@@ -1,62 +1,884 @@
 //! This module contains the code for managing connections to clients.
+//!
+//! Connections are served by an async runtime, so thousands of them don't
+//! need thousands of threads. Every connection has a task reading from it
+//! and a task writing to it. Messages sent to a client are put in its send
+//! queue (SendQ) and written out by its writer task, so a client that reads
+//! slowly only ever holds up itself. A client that lets more than its SendQ
+//! limit pile up is disconnected with `ERROR :SendQ exceeded`.
+//!
+//! Closing a connection stops its reader straight away, wherever it is
+//! waiting; the writer still writes out whatever is queued before it
+//! shuts the socket down.
+//!
+//! The server can listen on any number of addresses, IPv4 or IPv6, each
+//! with its own options: clients connect in plaintext or over TLS, and may be
+//! put in a connection class of the listener's. Every listener has a task
+//! accepting connections on it.
+//!
+//! Browser clients can connect over WebSocket, on a listener of its own. The
+//! frames are turned back into lines, so nothing past the connection knows
+//! the difference.
+//!
+//! Behind a load balancer, a listener can take the client's real address from
+//! the PROXY protocol header every connection starts with.
+//!
+//! Local clients, like bots, can connect to a Unix socket instead. They have
+//! no address of their own, so they are known by their user id, and a
+//! listener can be set to log them in to the account bound to it. Who may
+//! connect is up to the socket's permissions: IP bans and the per-host limits
+//! don't apply to them.
+//!
+//! For an upgrade, plaintext connections can be handed over to a new copy of
+//! the server, see `upgrade`: they stop reading and writing, and their
+//! sockets, along with the listening ones, are kept open across the exec.
+use crate::{
+    ban::BanList,
+    config::{ListenAddress, ListenerConfig},
+    limits::{ConnectionCounter, ConnectionLimits, ConnectionSlot, LimitError},
+    proxy,
+    tls::{self, TlsAcceptor},
+    types::UserId,
+    websocket::{self, WebSocketReader, WebSocketWriter},
+};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 use std::{
+    collections::VecDeque,
     error::Error,
     fmt::{Debug, Display},
-    io::{Read, Write},
-    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+        unix::{
+            fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+            net::UnixStream,
+        },
+    },
+    path::Path,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::{TcpListener, TcpStream, UnixListener},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot, Notify, Semaphore,
+    },
+    task::JoinHandle,
+    time::timeout,
 };
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+/// How many bytes may wait in a connection's send queue, unless set otherwise.
+pub const DEFAULT_SENDQ: usize = 64 * 1024;
+
+/// How long a single write may take before the client is given up on.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a proxy has to send the PROXY header.
+const PROXY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many connections to a listener may be waiting to send their PROXY
+/// header at once. Anyone who can reach the listener can open those, and
+/// none of them is held to the bans or limits until the header arrives.
+const MAX_PENDING_PROXY: usize = 64;
+
+/// How long a client has to finish the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct ConnectionManager {
-    listener: TcpListener,
+    /// Connections accepted by the listener tasks.
+    incoming: UnboundedReceiver<Accepted>,
+    ban_list: BanList,
+    counter: ConnectionCounter,
+    /// The number of the next connection, see `ConnectionRead::id`.
+    next_id: u64,
+    /// The options of every listener, by address, for connections handed over
+    /// by the server that ran before.
+    listeners: Vec<(String, Listener)>,
+    line_len: usize,
+    /// Copies of the listening sockets, to hand over to a new copy of the server.
+    listener_fds: Vec<(String, OwnedFd)>,
+    listener_tasks: Vec<JoinHandle<()>>,
+    stop_listening: CancellationToken,
+    /// Stops the listener tasks once the connection manager is dropped.
+    _stop_listening: DropGuard,
+}
+
+/// A listening socket handed over to a new copy of the server, see `upgrade`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListenerState {
+    /// The address it listens at, as written in the settings.
+    pub address: String,
+    /// The socket, which is kept open across the exec.
+    pub fd: RawFd,
+}
+
+/// A connection handed over to a new copy of the server, see `upgrade`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionState {
+    /// The socket, which is kept open across the exec.
+    pub fd: RawFd,
+    pub addr: SocketAddr,
+    /// The address of the listener the client connected to.
+    pub listener: String,
+    /// The user id of a client on a Unix socket.
+    pub peer_uid: Option<u32>,
+    /// Lines read from the client that weren't handled yet.
+    pub input: Vec<String>,
+    /// What was read after them, not split into lines yet.
+    pub partial_input: Vec<u8>,
+    /// Messages waiting to be written to the client.
+    pub output: Vec<String>,
 }
 
 impl ConnectionManager {
-    pub fn launch(address: impl Into<IpAddr>, port: u16) -> Self {
-        let address = address.into();
-        let listener = TcpListener::bind((address, port))
-            .unwrap_or_else(|_| panic!("failed to bind to {address}:{port}"));
+    /// Start listening for clients on all of `listeners`, with `tls` for the
+    /// TLS ones. Clients matching a ban in `ban_list`, or going over `limits`,
+    /// are turned away. Clients may send lines of up to `line_len` bytes,
+    /// including the `\r\n`.
+    ///
+    /// Fails if there is nothing to listen on, a listener can't be bound, or
+    /// a listener wants TLS without `tls`. Then no listener is started.
+    ///
+    /// The listeners run on the async runtime, so this must be called from it.
+    /// They stop listening when the connection manager is dropped.
+    ///
+    /// Listening sockets handed over by the server that ran before, in
+    /// `inherited`, are used instead of binding again. Those no listener
+    /// wants anymore are closed.
+    pub fn launch(
+        listeners: &[ListenerConfig],
+        tls: Option<&TlsAcceptor>,
+        ban_list: BanList,
+        limits: ConnectionLimits,
+        line_len: usize,
+        inherited: Vec<ListenerState>,
+    ) -> io::Result<Self> {
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no addresses to listen on",
+            ));
+        }
+
+        // SAFETY: the server that ran before handed these over for us to own
+        let mut inherited = inherited
+            .into_iter()
+            .map(|listener| {
+                (listener.address, unsafe {
+                    OwnedFd::from_raw_fd(listener.fd)
+                })
+            })
+            .collect::<Vec<_>>();
+        // they were kept open for us, but not for whatever we start
+        for (_, fd) in &inherited {
+            SockRef::from(fd).set_cloexec(true)?;
+        }
+
+        let mut bound = Vec::new();
+        for config in listeners {
+            let tls = match (config.tls, tls) {
+                (false, _) => None,
+                (true, Some(tls)) => Some(tls.clone()),
+                (true, None) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("no TLS certificate to listen on {} with", config.address),
+                    ))
+                }
+            };
+
+            let address = config.address.to_string();
+            let listener = match inherited.iter().position(|(other, _)| *other == address) {
+                Some(index) => adopt(config, inherited.swap_remove(index).1),
+                None => bind(config),
+            }
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("failed to bind to {}: {err}", config.address),
+                )
+            })?;
+
+            bound.push((
+                address.clone(),
+                listener,
+                Listener {
+                    address,
+                    tls,
+                    websocket: config
+                        .websocket
+                        .then(|| Arc::from(config.origins.as_slice())),
+                    class: config.class.clone(),
+                    trust_peer: config.trust_peer,
+                    proxy: config.proxy,
+                    line_len,
+                },
+            ));
+        }
+
+        let (incoming_sender, incoming) = mpsc::unbounded_channel();
+        let stop_listening = CancellationToken::new();
+        let mut options = Vec::new();
+        let mut listener_fds = Vec::new();
+        let mut listener_tasks = Vec::new();
+        for (address, listener, listener_options) in bound {
+            listener_fds.push((address.clone(), listener.as_fd().try_clone_to_owned()?));
+            options.push((address, listener_options.clone()));
+            listener_tasks.push(spawn_listener(
+                listener,
+                listener_options,
+                incoming_sender.clone(),
+                stop_listening.clone(),
+            ));
+        }
 
-        Self { listener }
+        Ok(Self {
+            incoming,
+            ban_list,
+            counter: ConnectionCounter::new(limits),
+            next_id: 0,
+            listeners: options,
+            line_len,
+            listener_fds,
+            listener_tasks,
+            stop_listening: stop_listening.clone(),
+            _stop_listening: stop_listening.drop_guard(),
+        })
     }
 
-    pub fn accept_new_connection(&mut self) -> (ConnectionRead, ConnectionWrite) {
+    /// Change the limits new connections are held to.
+    pub fn set_limits(&mut self, limits: ConnectionLimits) {
+        self.counter.set_limits(limits);
+    }
+
+    /// Wait for the next client that may connect.
+    ///
+    /// The TLS and WebSocket handshakes are left to the connection's reader,
+    /// so a client that is slow to finish them doesn't hold up the others.
+    ///
+    /// Dropping the future before it is done doesn't lose a client.
+    pub async fn accept_new_connection(&mut self) -> (ConnectionRead, ConnectionWrite) {
         loop {
-            match self.listener.accept() {
-                Ok((socket, addr)) => {
-                    let (socket_read, socket_write) = (
-                        match socket.try_clone() {
-                            Ok(socket) => socket,
-                            Err(err) => {
-                                eprintln!("[WARN] Failed to clone socket: {err}");
-                                continue;
-                            }
-                        },
-                        socket,
-                    );
+            let accepted = self
+                .incoming
+                .recv()
+                .await
+                .expect("The listener tasks have stopped!");
 
-                    return (
-                        ConnectionRead::from_socket(socket_read, addr),
-                        ConnectionWrite::from_socket(socket_write, addr),
-                    );
+            if let Some(connection) = self.admit(accepted) {
+                return connection;
+            }
+        }
+    }
+
+    /// Take the next client that may connect, if one is already waiting.
+    pub fn try_accept_new_connection(&mut self) -> Option<(ConnectionRead, ConnectionWrite)> {
+        while let Ok(accepted) = self.incoming.try_recv() {
+            if let Some(connection) = self.admit(accepted) {
+                return Some(connection);
+            }
+        }
+
+        None
+    }
+
+    /// Let a client in, unless it is banned or goes over the limits.
+    fn admit(&mut self, accepted: Accepted) -> Option<(ConnectionRead, ConnectionWrite)> {
+        let Accepted {
+            socket,
+            fd,
+            mut addr,
+            peer_uid,
+            listener,
+        } = accepted;
+
+        // IPv4 clients of a dual-stack listener show up as `::ffff:a.b.c.d`
+        addr.set_ip(addr.ip().to_canonical());
+
+        // unless a proxy in front of it said where the client is
+        let local = peer_uid.is_some() && !listener.proxy;
+
+        let ip = addr.ip();
+        let ban = if local {
+            None
+        } else {
+            self.ban_list.find(None, Some(ip), &ip.to_string())
+        };
+        let slot = match ban {
+            Some(ban) => {
+                eprintln!(
+                    "[INFO] Refused banned connection from {addr}: {}",
+                    ban.reason
+                );
+                Err(format!("You are banned from this server ({})", ban.reason))
+            }
+            None if local => self.counter.acquire_local().map_err(|err| {
+                eprintln!("[INFO] Refused local connection: {err}");
+                err.to_string()
+            }),
+            None => self.counter.acquire(ip).map_err(|err| {
+                eprintln!("[INFO] Refused connection from {addr}: {err}");
+                err.to_string()
+            }),
+        };
+
+        let slot = match slot {
+            Ok(slot) => slot,
+            Err(message) => {
+                // a TLS or WebSocket client can't read anything before the handshake
+                if listener.tls.is_none() && listener.websocket.is_none() {
+                    refuse(socket, message);
                 }
+                return None;
+            }
+        };
+
+        self.next_id += 1;
+        let id = UserId(self.next_id);
+
+        // only a plaintext connection can be picked up by a new copy of the server
+        let fd = Some(fd).filter(|_| listener.tls.is_none() && listener.websocket.is_none());
+        let (conn_write, send_queue, writer) =
+            ConnectionWrite::from_socket(addr, id, listener.tls.is_some(), local, fd, slot);
+
+        Some((
+            ConnectionRead::from_socket(socket, addr, id, listener, peer_uid, writer, send_queue),
+            conn_write,
+        ))
+    }
+
+    /// Stop listening, so the listening sockets can be handed over to a new
+    /// copy of the server. Clients that connect meanwhile wait in the backlog.
+    ///
+    /// Clients that were already accepted can still be taken with
+    /// `try_accept_new_connection`.
+    pub async fn hand_over_listeners(&mut self) -> Vec<ListenerState> {
+        self.stop_listening.cancel();
+        for task in self.listener_tasks.drain(..) {
+            let _ = task.await;
+        }
+
+        self.listener_fds
+            .drain(..)
+            .map(|(address, fd)| ListenerState {
+                address,
+                fd: fd.into_raw_fd(),
+            })
+            .collect()
+    }
+
+    /// The id given to the last client that connected.
+    pub fn last_id(&self) -> UserId {
+        UserId(self.next_id)
+    }
+
+    /// Don't give out ids up to `last_id`, which the server that ran before did.
+    pub fn skip_ids(&mut self, last_id: UserId) {
+        self.next_id = self.next_id.max(last_id.0);
+    }
+
+    /// Pick up a connection the server that ran before handed over, as `id`.
+    ///
+    /// It counts towards the connection limits, but isn't held to them.
+    pub fn resume(
+        &mut self,
+        id: UserId,
+        state: ConnectionState,
+    ) -> io::Result<(ConnectionRead, ConnectionWrite)> {
+        // SAFETY: the server that ran before handed the socket over for us to own
+        let fd = unsafe { OwnedFd::from_raw_fd(state.fd) };
+        SockRef::from(&fd).set_cloexec(true)?;
+        let raw_fd = fd.as_raw_fd();
+        let socket: Stream = if SockRef::from(&fd).local_addr()?.as_socket().is_some() {
+            let socket = std::net::TcpStream::from(fd);
+            socket.set_nonblocking(true)?;
+            Box::new(TcpStream::from_std(socket)?)
+        } else {
+            let socket = UnixStream::from(fd);
+            socket.set_nonblocking(true)?;
+            Box::new(tokio::net::UnixStream::from_std(socket)?)
+        };
+
+        // the listener may be gone from the settings since
+        let listener = self
+            .listeners
+            .iter()
+            .find(|(address, _)| *address == state.listener)
+            .map(|(_, listener)| listener.clone())
+            .unwrap_or_else(|| Listener {
+                address: state.listener.clone(),
+                tls: None,
+                websocket: None,
+                class: None,
+                trust_peer: false,
+                proxy: false,
+                line_len: self.line_len,
+            });
+
+        let local = state.peer_uid.is_some() && !listener.proxy;
+        let slot = self.counter.add(Some(state.addr.ip()).filter(|_| !local));
+
+        let (conn_write, send_queue, writer) =
+            ConnectionWrite::from_socket(state.addr, id, false, local, Some(raw_fd), slot);
+        {
+            // it all fit in the SendQ before, so it isn't held to it again
+            let mut queue = send_queue.lock();
+            queue.bytes = state.output.iter().map(String::len).sum();
+            queue.messages = state.output.into();
+            send_queue.queued.notify_one();
+        }
+
+        let mut conn_read = ConnectionRead::from_socket(
+            socket,
+            state.addr,
+            id,
+            listener,
+            state.peer_uid,
+            writer,
+            send_queue,
+        );
+        let partial_input = &state.partial_input;
+        if partial_input.len() > conn_read.buffer.len() {
+            conn_read.buffer = vec![0; partial_input.len()].into_boxed_slice();
+        }
+        conn_read.buffer[..partial_input.len()].copy_from_slice(partial_input);
+        conn_read.buflen = partial_input.len();
+
+        Ok((conn_read, conn_write))
+    }
+}
+
+/// The options of a listener that the connections it accepts need.
+#[derive(Clone)]
+struct Listener {
+    /// The address it listens at, as written in the settings.
+    address: String,
+    tls: Option<TlsAcceptor>,
+    /// The origins WebSocket clients may connect from, if it is a WebSocket listener.
+    websocket: Option<Arc<[String]>>,
+    class: Option<String>,
+    trust_peer: bool,
+    /// Connections start with a PROXY protocol header.
+    proxy: bool,
+    /// The longest line a client may send, including the `\r\n`.
+    line_len: usize,
+}
+
+/// A connection a listener has accepted, on its way to the connection manager.
+struct Accepted {
+    socket: Stream,
+    fd: RawFd,
+    addr: SocketAddr,
+    /// The user id of a client on a Unix socket.
+    peer_uid: Option<u32>,
+    listener: Listener,
+}
+
+/// What a connection can be carried over: TCP or a Unix socket, maybe with
+/// TLS on top.
+trait AsyncStream: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Sync + Unpin> AsyncStream for S {}
+
+type Stream = Box<dyn AsyncStream>;
+
+/// A bound listening socket.
+enum BoundListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl BoundListener {
+    /// Wait for a client, giving its address, and its user id if it is on this host.
+    async fn accept(&self) -> io::Result<(Stream, RawFd, SocketAddr, Option<u32>)> {
+        match self {
+            BoundListener::Tcp(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                let fd = socket.as_raw_fd();
+                Ok((Box::new(socket), fd, addr, None))
+            }
+            // the client is on this host, and has no address of its own
+            BoundListener::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                let fd = socket.as_raw_fd();
+                let uid = socket.peer_cred()?.uid();
+                Ok((
+                    Box::new(socket),
+                    fd,
+                    SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+                    Some(uid),
+                ))
+            }
+        }
+    }
+
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            BoundListener::Tcp(listener) => listener.as_fd(),
+            BoundListener::Unix(listener) => listener.as_fd(),
+        }
+    }
+}
+
+/// Listen for `config` on a socket the server that ran before handed over.
+fn adopt(config: &ListenerConfig, fd: OwnedFd) -> io::Result<BoundListener> {
+    match &config.address {
+        ListenAddress::Tcp(_) => {
+            let listener = std::net::TcpListener::from(fd);
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener).map(BoundListener::Tcp)
+        }
+        ListenAddress::Unix(_) => {
+            let listener = std::os::unix::net::UnixListener::from(fd);
+            listener.set_nonblocking(true)?;
+            UnixListener::from_std(listener).map(BoundListener::Unix)
+        }
+    }
+}
+
+/// Bind a listening socket for `config`.
+fn bind(config: &ListenerConfig) -> io::Result<BoundListener> {
+    match &config.address {
+        ListenAddress::Tcp(address) => {
+            let listener = bind_tcp(*address, config.ipv6_only)?;
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener).map(BoundListener::Tcp)
+        }
+        ListenAddress::Unix(path) => {
+            let listener = bind_unix(path, config.mode)?;
+            listener.set_nonblocking(true)?;
+            UnixListener::from_std(listener).map(BoundListener::Unix)
+        }
+    }
+}
+
+fn bind_tcp(address: SocketAddr, ipv6_only: bool) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+
+    // whether `[::]` takes IPv4 clients too would otherwise be up to the system
+    if address.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+
+    // like `TcpListener::bind`
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(128)?;
+
+    Ok(socket.into())
+}
+
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<std::os::unix::net::UnixListener> {
+    // A socket left behind by a server that has stopped would keep us from binding,
+    // but one that is still being listened on belongs to someone else
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() || UnixStream::connect(path).is_ok() {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        fs::remove_file(path)?;
+    }
+
+    let Some(mode) = mode else {
+        return std::os::unix::net::UnixListener::bind(path);
+    };
+
+    // Until its permissions are set, the socket is bound in a directory only
+    // we can get into, so no one can connect to it meanwhile
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(io::ErrorKind::InvalidInput.into());
+    };
+    let mut dir_name = std::ffi::OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}", std::process::id()));
+    let dir = parent.join(dir_name);
+    if fs::symlink_metadata(&dir).is_ok() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let bound = dir.join(name);
+    let result = std::os::unix::net::UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, fs::Permissions::from_mode(mode))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&dir);
+
+    result
+}
+
+/// Accept connections on `listener`, handing them to the connection manager,
+/// until `stop` is cancelled.
+fn spawn_listener(
+    listener: BoundListener,
+    options: Listener,
+    incoming: UnboundedSender<Accepted>,
+    stop: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let pending_proxy = Arc::new(Semaphore::new(MAX_PENDING_PROXY));
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                // dropping the listener closes its socket
+                () = stop.cancelled() => break,
+            };
+
+            let (socket, fd, addr, peer_uid) = match accepted {
+                Ok(accepted) => accepted,
                 Err(err) => {
                     eprintln!("[WARN] failed to connect to client: {err}");
+                    continue;
                 }
+            };
+
+            if options.proxy {
+                let Ok(permit) = pending_proxy.clone().try_acquire_owned() else {
+                    eprintln!(
+                        "[INFO] Refused connection from {addr}: too many waiting for a PROXY header"
+                    );
+                    // like in `admit`, only a plaintext client can be told why
+                    if options.tls.is_none() && options.websocket.is_none() {
+                        refuse(
+                            socket,
+                            "Too many connections waiting, please try again later".to_owned(),
+                        );
+                    }
+                    continue;
+                };
+
+                // waiting for the header mustn't hold up the other connections
+                let options = options.clone();
+                let incoming = incoming.clone();
+                tokio::spawn(async move {
+                    let header = read_proxy_header(socket, addr).await;
+                    drop(permit);
+                    if let Some((socket, addr)) = header {
+                        let _ = incoming.send(Accepted {
+                            socket,
+                            fd,
+                            addr,
+                            peer_uid,
+                            listener: options,
+                        });
+                    }
+                });
+            } else if incoming
+                .send(Accepted {
+                    socket,
+                    fd,
+                    addr,
+                    peer_uid,
+                    listener: options.clone(),
+                })
+                .is_err()
+            {
+                // the connection manager is gone
+                break;
+            }
+        }
+    })
+}
+
+/// Read the PROXY header of a connection from `addr`, giving back the
+/// connection with the client's address, or `None` if the header is bad.
+async fn read_proxy_header(mut socket: Stream, addr: SocketAddr) -> Option<(Stream, SocketAddr)> {
+    let header = timeout(PROXY_TIMEOUT, proxy::read_header(&mut socket))
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+
+    match header {
+        Ok(client) => Some((socket, client.unwrap_or(addr))),
+        Err(err) => {
+            eprintln!("[WARN] Bad PROXY header from {addr}: {err}");
+            None
+        }
+    }
+}
+
+/// Tell a plaintext client why it is turned away, without holding up the
+/// connection manager.
+fn refuse(mut socket: Stream, message: String) {
+    tokio::spawn(async move {
+        let message = format!("ERROR :{message}\r\n");
+        let _ = timeout(WRITE_TIMEOUT, socket.write_all(message.as_bytes())).await;
+    });
+}
+
+/// What a connection needs before it can be used. The handshakes are done by
+/// `ConnectionRead::start`, in the connection's own task.
+struct Pending {
+    socket: Stream,
+    tls: Option<TlsAcceptor>,
+    websocket: Option<Arc<[String]>>,
+    /// Hands the writing half to the writer task.
+    writer: oneshot::Sender<Writer>,
+}
+
+/// The reading half of a connection.
+enum Reader {
+    Lines(ReadHalf<Stream>),
+    WebSocket(WebSocketReader<ReadHalf<Stream>, WriteHalf<Stream>>),
+}
+
+impl Reader {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Reader::Lines(socket) => socket.read(buf).await,
+            Reader::WebSocket(stream) => stream.read(buf).await,
+        }
+    }
+}
+
+/// The writing half of a connection.
+enum Writer {
+    Lines(WriteHalf<Stream>),
+    WebSocket(WebSocketWriter<WriteHalf<Stream>>),
+}
+
+impl Writer {
+    async fn write(&mut self, message: &str) -> io::Result<()> {
+        match self {
+            Writer::Lines(socket) => {
+                socket.write_all(message.as_bytes()).await?;
+                socket.flush().await
             }
+            Writer::WebSocket(stream) => stream.write(message.as_bytes()).await,
+        }
+    }
+
+    /// Shut the connection down, telling the client first over TLS or WebSocket.
+    async fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Lines(socket) => socket.shutdown().await,
+            Writer::WebSocket(stream) => stream.close().await,
         }
     }
 }
 
 pub struct ConnectionRead {
-    socket: TcpStream,
+    /// The connection, until it is started.
+    pending: Option<Pending>,
+    reader: Option<Reader>,
     socket_addr: SocketAddr,
-    buffer: Box<[u8; 512]>,
+    id: UserId,
+    /// The address of the listener the client connected to.
+    listener: String,
+    /// The connection class of the listener the client connected to, if it has one.
+    class: Option<String>,
+    /// The user id of a client on a Unix socket.
+    peer_uid: Option<u32>,
+    /// Whether the listener trusts `peer_uid` to log the client in.
+    trust_peer: bool,
+    buffer: Box<[u8]>,
     buflen: usize,
+    /// The rest of a line that was too long is being skipped.
+    discarding: bool,
+    /// How long `read_message` waits for a message.
+    read_timeout: Option<Duration>,
+    send_queue: Arc<SendQueue>,
 }
 
 pub struct ConnectionWrite {
-    socket: TcpStream,
     socket_addr: SocketAddr,
+    id: UserId,
+    /// The client connected over TLS.
+    tls: bool,
+    /// The client is on a Unix socket, so `socket_addr` isn't its own.
+    local: bool,
+    send_queue: Arc<SendQueue>,
+}
+
+/// The messages waiting to be written to a client, shared with its writer task.
+struct SendQueue {
+    state: Mutex<SendQueueState>,
+    /// Wakes the writer task up when there is something to do.
+    queued: Notify,
+    /// Signalled once the connection is closed.
+    changed: Condvar,
+    /// Cancelled once the connection is closing, which stops the reader.
+    stop_reading: CancellationToken,
+    /// The connection's place in the connection limits, given back once
+    /// both the reader and the writer are done with it.
+    slot: Mutex<ConnectionSlot>,
+    /// The socket of a plaintext connection, which can be handed over to a
+    /// new copy of the server.
+    fd: Option<RawFd>,
+    /// Cancelled once the connection is being handed over, which stops the reader.
+    hand_over: CancellationToken,
+}
+
+struct SendQueueState {
+    messages: VecDeque<String>,
+    bytes: usize,
+    limit: usize,
+    /// The connection is closing: nothing more is queued, and the writer
+    /// shuts the socket down once the queue is empty.
+    closing: bool,
+    /// The writer has shut the socket down.
+    closed: bool,
+    /// Why the server closed the connection, if it was the server's decision.
+    close_reason: Option<String>,
+    /// The fingerprint of the client's TLS certificate, once the handshake is done.
+    certfp: Option<String>,
+    /// The connection is being handed over: the writer stops once it has
+    /// written the message it is writing.
+    handing_over: bool,
+    /// The reader has stopped for the handover.
+    reader_stopped: bool,
+    /// The writer has stopped for the handover, leaving the rest queued.
+    writer_stopped: bool,
+    /// What the reader left behind for the handover.
+    handed_over: Option<HandedOverInput>,
+}
+
+/// What the reader of a connection leaves behind for a new copy of the server.
+struct HandedOverInput {
+    /// A copy of the socket, which outlives the connection.
+    fd: OwnedFd,
+    listener: String,
+    peer_uid: Option<u32>,
+    lines: Vec<String>,
+    partial: Vec<u8>,
+}
+
+impl SendQueue {
+    fn new(fd: Option<RawFd>, slot: ConnectionSlot) -> Arc<Self> {
+        Arc::new(SendQueue {
+            state: Mutex::new(SendQueueState {
+                messages: VecDeque::new(),
+                bytes: 0,
+                limit: DEFAULT_SENDQ,
+                closing: false,
+                closed: false,
+                close_reason: None,
+                certfp: None,
+                handing_over: false,
+                reader_stopped: false,
+                writer_stopped: false,
+                handed_over: None,
+            }),
+            queued: Notify::new(),
+            changed: Condvar::new(),
+            stop_reading: CancellationToken::new(),
+            slot: Mutex::new(slot),
+            fd,
+            hand_over: CancellationToken::new(),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SendQueueState> {
+        self.state.lock().expect("Failed to lock send queue")
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -65,6 +887,10 @@ pub enum ConnectionError {
     ConnectionClosed,
     MessageTooLong,
     MessageInvalidUtf8,
+    /// No complete message arrived within the read timeout.
+    TimedOut,
+    /// The connection is being handed over to a new copy of the server.
+    HandedOver,
 }
 
 impl Display for ConnectionError {
@@ -76,15 +902,77 @@ impl Display for ConnectionError {
 impl Error for ConnectionError {}
 
 impl ConnectionRead {
-    fn from_socket(socket: TcpStream, socket_addr: SocketAddr) -> Self {
+    fn from_socket(
+        socket: Stream,
+        socket_addr: SocketAddr,
+        id: UserId,
+        listener: Listener,
+        peer_uid: Option<u32>,
+        writer: oneshot::Sender<Writer>,
+        send_queue: Arc<SendQueue>,
+    ) -> Self {
         Self {
-            socket,
+            pending: Some(Pending {
+                socket,
+                tls: listener.tls,
+                websocket: listener.websocket,
+                writer,
+            }),
+            reader: None,
             socket_addr,
-            buffer: Box::from([0; 512]),
+            id,
+            listener: listener.address,
+            class: listener.class,
+            peer_uid,
+            trust_peer: listener.trust_peer,
+            buffer: vec![0; listener.line_len].into_boxed_slice(),
             buflen: 0,
+            discarding: false,
+            read_timeout: None,
+            send_queue,
         }
     }
 
+    /// Do the TLS handshake, if the connection is over TLS, and hand the
+    /// writing half to the writer task.
+    async fn start(&mut self) -> io::Result<()> {
+        let Some(Pending {
+            socket,
+            tls,
+            websocket,
+            writer,
+        }) = self.pending.take()
+        else {
+            return Ok(());
+        };
+
+        let socket: Stream = match tls {
+            Some(acceptor) => {
+                let stream = timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket))
+                    .await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))?;
+                self.send_queue.lock().certfp = tls::peer_certfp(&stream);
+                Box::new(stream)
+            }
+            None => socket,
+        };
+
+        let (socket_read, socket_write) = io::split(socket);
+        let (reader, socket_write) = match websocket {
+            Some(origins) => {
+                let (reader, writer) = websocket::accept(socket_read, socket_write, origins);
+                (Reader::WebSocket(reader), Writer::WebSocket(writer))
+            }
+            None => (Reader::Lines(socket_read), Writer::Lines(socket_write)),
+        };
+
+        // the writer task is only gone if the connection already closed
+        let _ = writer.send(socket_write);
+        self.reader = Some(reader);
+
+        Ok(())
+    }
+
     fn buffer_crlf(&self) -> Option<usize> {
         self.buffer[..self.buflen]
             .windows(2)
@@ -93,69 +981,925 @@ impl ConnectionRead {
             .map(|(index, _)| index)
     }
 
-    pub fn read_message(&mut self) -> Result<String, ConnectionError> {
-        use std::io::ErrorKind;
-
-        if self.buffer_crlf().is_none() {
-            let n_bytes = loop {
-                break match self.socket.read(&mut self.buffer[self.buflen..]) {
-                    Ok(0) => return Err(ConnectionError::ConnectionClosed),
-                    Ok(n_bytes) => n_bytes,
-                    Err(err) => {
-                        match err.kind() {
-                            // Retry `read` if interrupted...
-                            ErrorKind::Interrupted => continue,
-                            _ => return Err(ConnectionError::ConnectionLost),
-                        }
+    /// Wait for the next message from the client.
+    ///
+    /// Returns `ConnectionError::ConnectionClosed` as soon as the connection
+    /// is closed, whoever closed it, and `ConnectionError::HandedOver` once it
+    /// is being handed over, see `hand_over`.
+    pub async fn read_message(&mut self) -> Result<String, ConnectionError> {
+        let stop_reading = self.send_queue.stop_reading.clone();
+        let hand_over = self.send_queue.hand_over.clone();
+
+        tokio::select! {
+            biased;
+            _ = hand_over.cancelled() => Err(ConnectionError::HandedOver),
+            message = self.read_line() => message,
+            _ = stop_reading.cancelled() => Err(ConnectionError::ConnectionClosed),
+        }
+    }
+
+    /// Stop reading for good, once `read_message` returned `ConnectionError::HandedOver`.
+    ///
+    /// The `lines` read from the client but not handled yet, and whatever is
+    /// still in the buffer, go with the connection to the new copy of the server.
+    pub fn hand_over(self, lines: Vec<String>) {
+        let mut state = self.send_queue.lock();
+
+        // The socket is open as long as its reading half is around, and the
+        // copy outlives it. A connection that never started can't have been
+        // written to, so it doesn't matter that its writer is gone.
+        let open = self.pending.is_some() || self.reader.is_some();
+        if let Some(fd) = self.send_queue.fd.filter(|_| open) {
+            // SAFETY: see above
+            match unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned() {
+                Ok(fd) => {
+                    state.handed_over = Some(HandedOverInput {
+                        fd,
+                        listener: self.listener.clone(),
+                        peer_uid: self.peer_uid,
+                        lines,
+                        partial: self.buffer[..self.buflen].to_vec(),
+                    })
+                }
+                Err(err) => eprintln!(
+                    "[WARN] Failed to hand over the connection from {}: {err}",
+                    self.socket_addr
+                ),
+            }
+        }
+
+        state.reader_stopped = true;
+        self.send_queue.changed.notify_all();
+    }
+
+    /// Drop the first `len` bytes of the buffer.
+    fn consume(&mut self, len: usize) {
+        self.buffer.copy_within(len..self.buflen, 0);
+        self.buflen -= len;
+    }
+
+    async fn read_line(&mut self) -> Result<String, ConnectionError> {
+        if let Err(err) = self.start().await {
+            eprintln!("[WARN] Failed to start TLS for {}: {err}", self.socket_addr);
+            return Err(ConnectionError::ConnectionLost);
+        }
+
+        // a line may come in several pieces, but must fit in the buffer
+        let deadline = self
+            .read_timeout
+            .map(|read_timeout| tokio::time::Instant::now() + read_timeout);
+        let end = loop {
+            match self.buffer_crlf() {
+                Some(end) if self.discarding => {
+                    self.discarding = false;
+                    self.consume(end + 2);
+                    continue;
+                }
+                Some(end) => break end,
+                None if self.buflen == self.buffer.len() => {
+                    // Clear out their data, and the rest of the line as it comes
+                    self.buflen = 0;
+                    if !std::mem::replace(&mut self.discarding, true) {
+                        return Err(ConnectionError::MessageTooLong);
                     }
-                };
+                }
+                None => (),
+            }
+
+            let Some(reader) = &mut self.reader else {
+                return Err(ConnectionError::ConnectionLost);
             };
 
-            self.buflen += n_bytes;
-        }
+            let read = reader.read(&mut self.buffer[self.buflen..]);
+            let result = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, read)
+                    .await
+                    .map_err(|_| ConnectionError::TimedOut)?,
+                None => read.await,
+            };
+
+            let n_bytes = match result {
+                Ok(0) => return Err(ConnectionError::ConnectionClosed),
+                Ok(n_bytes) => n_bytes,
+                Err(_) => return Err(ConnectionError::ConnectionLost),
+            };
 
-        let end = self.buffer_crlf().ok_or_else(|| {
-            // Clear out their data...
-            self.buflen = 0;
-            ConnectionError::MessageTooLong
-        })?;
+            self.buflen += n_bytes;
+        };
 
         let bytes = Vec::from(&self.buffer[0..end]);
 
         // end + '\r' + '\n'
-        let after_crlf = end + 2;
-
-        self.buffer.copy_within(after_crlf..self.buflen, 0);
-        self.buflen -= after_crlf;
+        self.consume(end + 2);
 
         let message = String::from_utf8(bytes).map_err(|_| ConnectionError::MessageInvalidUtf8)?;
 
         Ok(message)
     }
 
-    pub fn id(&self) -> String {
-        self.socket_addr.to_string()
+    /// The id of the user on this connection, unique while the server runs.
+    ///
+    /// It doesn't give away the client's address, and is shown starting
+    /// with `*`, so it can never be mistaken for a nick.
+    pub fn id(&self) -> UserId {
+        self.id
+    }
+
+    /// The IP address of the client.
+    pub fn ip(&self) -> IpAddr {
+        self.socket_addr.ip()
+    }
+
+    /// The connection class of the listener the client connected to, if it
+    /// has one.
+    pub fn listener_class(&self) -> Option<&str> {
+        self.class.as_deref()
+    }
+
+    /// The user id of the process on the other end, for a client on a Unix socket.
+    pub fn peer_uid(&self) -> Option<u32> {
+        self.peer_uid
+    }
+
+    /// The user id of a client on a Unix socket whose listener trusts it to
+    /// log the client in.
+    pub fn trusted_uid(&self) -> Option<u32> {
+        self.peer_uid.filter(|_| self.trust_peer)
+    }
+
+    /// Make `read_message` give up with `ConnectionError::TimedOut` if no message
+    /// arrives within `timeout`. `None` waits forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Why the server closed the connection, e.g. `SendQ exceeded`, or `None`
+    /// if it didn't.
+    pub fn close_reason(&self) -> Option<String> {
+        self.send_queue.lock().close_reason.clone()
+    }
+}
+
+impl ConnectionWrite {
+    /// Start the writer task for a connection. It starts writing once it is
+    /// sent the writing half of the connection.
+    fn from_socket(
+        socket_addr: SocketAddr,
+        id: UserId,
+        tls: bool,
+        local: bool,
+        fd: Option<RawFd>,
+        slot: ConnectionSlot,
+    ) -> (Self, Arc<SendQueue>, oneshot::Sender<Writer>) {
+        let send_queue = SendQueue::new(fd, slot);
+
+        let (writer, ready) = oneshot::channel();
+        tokio::spawn(write_messages(ready, socket_addr, send_queue.clone()));
+
+        (
+            Self {
+                socket_addr,
+                id,
+                tls,
+                local,
+                send_queue: send_queue.clone(),
+            },
+            send_queue,
+            writer,
+        )
+    }
+
+    /// Queue a message to be written to the client.
+    ///
+    /// This never blocks on the client. If the message doesn't fit in the
+    /// SendQ, the client is disconnected instead. Neither that nor the
+    /// connection already closing is an error for whoever sent the message,
+    /// so one client leaving can't interrupt a broadcast; the message is dropped.
+    pub fn write_message(&mut self, message: &str) -> Result<(), ConnectionError> {
+        let mut state = self.send_queue.lock();
+        if state.closing {
+            return Ok(());
+        }
+
+        if state.bytes + message.len() > state.limit {
+            eprintln!("[INFO] SendQ exceeded for {}", self.socket_addr);
+            // nothing queued will reach them in time, so only tell them why they're leaving
+            state.messages.clear();
+            state.bytes = 0;
+            state
+                .messages
+                .push_back("ERROR :SendQ exceeded\r\n".to_owned());
+            state.close_reason = Some("SendQ exceeded".to_owned());
+            drop(state);
+            self.shutdown();
+            return Ok(());
+        }
+
+        state.bytes += message.len();
+        state.messages.push_back(message.to_owned());
+        self.send_queue.queued.notify_one();
+
+        Ok(())
+    }
+
+    /// Set how many bytes may wait in the send queue before the client is disconnected.
+    pub fn set_sendq(&mut self, limit: usize) {
+        self.send_queue.lock().limit = limit;
+    }
+
+    /// The IP address of the client.
+    pub fn ip(&self) -> IpAddr {
+        self.socket_addr.ip()
+    }
+
+    /// The IP address of the client, unless it is on a Unix socket, and only
+    /// has the server's own.
+    pub fn remote_ip(&self) -> Option<IpAddr> {
+        Some(self.ip()).filter(|_| !self.local)
+    }
+
+    /// Record that the client is really at `ip`, e.g. as a WEBIRC gateway
+    /// says, so the connection counts towards that host in the limits.
+    ///
+    /// Fails if that host has no room, and then nothing changes.
+    pub fn set_ip(&mut self, ip: IpAddr) -> Result<(), LimitError> {
+        self.send_queue
+            .slot
+            .lock()
+            .expect("Failed to lock connection slot")
+            .move_to(ip)?;
+        self.socket_addr.set_ip(ip);
+        self.local = false;
+
+        Ok(())
+    }
+
+    /// Whether the client connected over TLS.
+    pub fn is_tls(&self) -> bool {
+        self.tls
+    }
+
+    /// The fingerprint of the client's TLS certificate, if it showed one.
+    pub fn certfp(&self) -> Option<String> {
+        self.send_queue.lock().certfp.clone()
+    }
+
+    /// Close the connection.
+    ///
+    /// Reading stops straight away, so a waiting `read_message` returns. Whatever
+    /// is already queued is still written, then the socket is shut down.
+    pub fn shutdown(&mut self) {
+        self.send_queue.lock().closing = true;
+        self.send_queue.stop_reading.cancel();
+        self.send_queue.queued.notify_one();
+    }
+
+    /// Something to wait for the connection to be closed with, see `Closed::wait`.
+    pub fn closed(&self) -> Closed {
+        Closed(self.send_queue.clone())
+    }
+
+    /// Start handing the connection over to a new copy of the server.
+    ///
+    /// The reader stops: `read_message` returns `ConnectionError::HandedOver`.
+    /// The writer of a plaintext connection stops once it has written the
+    /// message it is writing, leaving the rest queued. Other connections
+    /// can't be handed over, and keep writing until they are closed.
+    pub fn start_handover(&mut self) -> Handover {
+        if self.send_queue.fd.is_some() {
+            self.send_queue.lock().handing_over = true;
+            self.send_queue.queued.notify_one();
+        }
+        self.send_queue.hand_over.cancel();
+
+        Handover(self.send_queue.clone())
+    }
+
+    /// Take what the new copy of the server needs to pick the connection up,
+    /// once `Handover::wait` says it is ready.
+    pub fn take_handover(&mut self) -> Option<ConnectionState> {
+        let mut state = self.send_queue.lock();
+        let input = state.handed_over.take()?;
+
+        let output = state.messages.drain(..).collect();
+        state.bytes = 0;
+
+        Some(ConnectionState {
+            fd: input.fd.into_raw_fd(),
+            addr: self.socket_addr,
+            listener: input.listener,
+            peer_uid: input.peer_uid,
+            input: input.lines,
+            partial_input: input.partial,
+            output,
+        })
+    }
+
+    pub fn id(&self) -> UserId {
+        self.id
     }
 }
 
+#[cfg(test)]
 impl ConnectionWrite {
-    fn from_socket(socket: TcpStream, socket_addr: SocketAddr) -> Self {
+    /// A connection from `socket_addr` with no client or writer behind it,
+    /// so tests can see what is sent to it with `take_queued`.
+    pub(crate) fn for_test(socket_addr: SocketAddr, id: UserId) -> Self {
+        let slot = ConnectionCounter::new(ConnectionLimits::default()).add(Some(socket_addr.ip()));
+
         Self {
-            socket,
             socket_addr,
+            id,
+            tls: false,
+            local: false,
+            send_queue: SendQueue::new(None, slot),
         }
     }
 
-    pub fn write_message(&mut self, message: &str) -> Result<(), ConnectionError> {
-        self.socket
-            .write_all(message.as_bytes())
-            .map_err(|_| ConnectionError::ConnectionClosed)?;
-        let _ = self.socket.flush();
+    /// Pretend the client showed a TLS certificate with the fingerprint `certfp`.
+    pub(crate) fn set_certfp(&mut self, certfp: &str) {
+        self.send_queue.lock().certfp = Some(certfp.to_owned());
+    }
 
-        Ok(())
+    /// Take the messages queued so far.
+    pub(crate) fn take_queued(&mut self) -> Vec<String> {
+        let mut state = self.send_queue.lock();
+        state.bytes = 0;
+        state.messages.drain(..).collect()
+    }
+}
+
+/// Waits for a connection to be closed, without holding on to its user.
+pub struct Closed(Arc<SendQueue>);
+
+impl Closed {
+    /// Wait until everything queued before the connection was shut down has
+    /// been written, or `deadline` has passed.
+    ///
+    /// This blocks the thread, so it isn't for the async runtime's threads.
+    pub fn wait(&self, deadline: Instant) {
+        let mut state = self.0.lock();
+        while !state.closed {
+            let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                return;
+            };
+            state = self
+                .0
+                .changed
+                .wait_timeout(state, timeout)
+                .expect("Failed to lock send queue")
+                .0;
+        }
+    }
+}
+
+/// Waits for a connection to be ready to hand over, without holding on to its user.
+pub struct Handover(Arc<SendQueue>);
+
+impl Handover {
+    /// Wait until the reader and the writer have stopped, or `deadline` has
+    /// passed. Returns whether the connection can be handed over, see
+    /// `ConnectionWrite::take_handover`; if not, it should be closed.
+    ///
+    /// This blocks the thread, so it isn't for the async runtime's threads.
+    pub fn wait(&self, deadline: Instant) -> bool {
+        if self.0.fd.is_none() {
+            return false;
+        }
+
+        let mut state = self.0.lock();
+        while !(state.reader_stopped && (state.writer_stopped || state.closed)) {
+            let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                return false;
+            };
+            state = self
+                .0
+                .changed
+                .wait_timeout(state, timeout)
+                .expect("Failed to lock send queue")
+                .0;
+        }
+
+        state.handed_over.is_some() && !state.closing
+    }
+}
+
+/// The writer task of a connection: write out queued messages until the connection closes.
+async fn write_messages(
+    ready: oneshot::Receiver<Writer>,
+    socket_addr: SocketAddr,
+    send_queue: Arc<SendQueue>,
+) {
+    // The reader hands the connection over once it is started. If it never
+    // is, e.g. because the TLS handshake failed, there is nothing to write to.
+    if let Ok(mut writer) = ready.await {
+        loop {
+            let (message, closing) = {
+                let mut state = send_queue.lock();
+                // a connection that is closing anyway is finished as usual
+                if state.handing_over && !state.closing {
+                    state.writer_stopped = true;
+                    send_queue.changed.notify_all();
+                    return;
+                }
+
+                let message = state.messages.pop_front();
+                if let Some(message) = &message {
+                    state.bytes -= message.len().min(state.bytes);
+                }
+                (message, state.closing)
+            };
+
+            let message = match message {
+                Some(message) => message,
+                None if closing => break,
+                None => {
+                    send_queue.queued.notified().await;
+                    continue;
+                }
+            };
+
+            let result = timeout(WRITE_TIMEOUT, writer.write(&message))
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+            if let Err(err) = result {
+                eprintln!("[WARN] Failed to write to {socket_addr}: {err}");
+                break;
+            }
+        }
+
+        let _ = timeout(WRITE_TIMEOUT, writer.shutdown()).await;
+    }
+
+    // Also stops the reader, if the connection wasn't already closing
+    send_queue.stop_reading.cancel();
+
+    let mut state = send_queue.lock();
+    state.closing = true;
+    state.closed = true;
+    state.messages.clear();
+    state.bytes = 0;
+    send_queue.changed.notify_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::task::spawn_blocking;
+
+    fn launch(address: &str, inherited: Vec<ListenerState>) -> ConnectionManager {
+        ConnectionManager::launch(
+            &[address.parse().unwrap()],
+            None,
+            BanList::new(),
+            ConnectionLimits::default(),
+            512,
+            inherited,
+        )
+        .unwrap()
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("iris-connect-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn local_addr(connection_manager: &ConnectionManager) -> SocketAddr {
+        let (_, fd) = &connection_manager.listener_fds[0];
+        SockRef::from(fd).local_addr().unwrap().as_socket().unwrap()
+    }
+
+    /// Read from `client` until `expected` has arrived, or it stops sending.
+    async fn read_until(client: &mut TcpStream, expected: &[u8]) -> Vec<u8> {
+        let mut received = Vec::new();
+        let mut buffer = [0; 512];
+        while !received.ends_with(expected) {
+            match timeout(Duration::from_secs(5), client.read(&mut buffer)).await {
+                Ok(Ok(n_bytes)) if n_bytes > 0 => received.extend_from_slice(&buffer[..n_bytes]),
+                _ => break,
+            }
+        }
+        received
+    }
+
+    /// Connect a client to `connection_manager`, listening on `addr`.
+    async fn connect(
+        connection_manager: &mut ConnectionManager,
+        addr: SocketAddr,
+    ) -> (TcpStream, ConnectionRead, ConnectionWrite) {
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (conn_read, conn_write) = connection_manager.accept_new_connection().await;
+        (client, conn_read, conn_write)
+    }
+
+    #[tokio::test]
+    async fn test_refuse() {
+        let ban_list = BanList::new();
+        let limits = ConnectionLimits {
+            max_per_host: 1,
+            ..Default::default()
+        };
+        let mut connection_manager = ConnectionManager::launch(
+            &["127.0.0.1:0".parse().unwrap()],
+            None,
+            ban_list.clone(),
+            limits,
+            512,
+            Vec::new(),
+        )
+        .unwrap();
+        let addr = local_addr(&connection_manager);
+
+        let (_client, conn_read, conn_write) = connect(&mut connection_manager, addr).await;
+        assert_eq!(conn_read.ip(), conn_write.ip());
+        assert_eq!(conn_write.remote_ip(), Some("127.0.0.1".parse().unwrap()));
+
+        // one more from the same host is told why it is turned away
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let accepted = timeout(
+            Duration::from_millis(100),
+            connection_manager.accept_new_connection(),
+        )
+        .await;
+        assert!(accepted.is_err());
+        assert_eq!(
+            read_until(&mut client, b"\r\n").await,
+            b"ERROR :Too many connections from your host\r\n"
+        );
+
+        // and a ban goes before the limits
+        ban_list
+            .add(crate::ban::Ban {
+                mask: "127.0.0.1".parse().unwrap(),
+                reason: "spam".to_owned(),
+                set_by: "admin".to_owned(),
+                set_at: 0,
+                expires_at: None,
+            })
+            .unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let accepted = timeout(
+            Duration::from_millis(100),
+            connection_manager.accept_new_connection(),
+        )
+        .await;
+        assert!(accepted.is_err());
+        assert_eq!(
+            read_until(&mut client, b"\r\n").await,
+            b"ERROR :You are banned from this server (spam)\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_message() {
+        let mut connection_manager = launch("127.0.0.1:0", Vec::new());
+        let addr = local_addr(&connection_manager);
+        let (mut client, mut conn_read, _conn_write) = connect(&mut connection_manager, addr).await;
+
+        client.write_all(b"PING a\r\nPING b\r\nPI").await.unwrap();
+        assert_eq!(conn_read.read_message().await.unwrap(), "PING a");
+        assert_eq!(conn_read.read_message().await.unwrap(), "PING b");
+
+        // the rest of a line is waited for
+        let (message, _) = tokio::join!(conn_read.read_message(), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.write_all(b"NG c\r\n").await.unwrap();
+        });
+        assert_eq!(message.unwrap(), "PING c");
+
+        client.write_all(b"\xff\r\n").await.unwrap();
+        assert_eq!(
+            conn_read.read_message().await,
+            Err(ConnectionError::MessageInvalidUtf8)
+        );
+
+        // a line that doesn't fit is dropped, all of it
+        let long_line = format!("PRIVMSG #a :{}\r\nPING d\r\n", "a".repeat(1000));
+        client.write_all(long_line.as_bytes()).await.unwrap();
+        assert_eq!(
+            conn_read.read_message().await,
+            Err(ConnectionError::MessageTooLong)
+        );
+        assert_eq!(conn_read.read_message().await.unwrap(), "PING d");
+
+        drop(client);
+        assert_eq!(
+            conn_read.read_message().await,
+            Err(ConnectionError::ConnectionClosed)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        let mut connection_manager = launch("127.0.0.1:0", Vec::new());
+        let addr = local_addr(&connection_manager);
+        let (mut client, mut conn_read, _conn_write) = connect(&mut connection_manager, addr).await;
+
+        // it is for the whole line, however it comes
+        conn_read.set_read_timeout(Some(Duration::from_millis(200)));
+        let (message, _) = tokio::join!(conn_read.read_message(), async {
+            for _ in 0..10 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                client.write_all(b"a").await.unwrap();
+            }
+        });
+        assert_eq!(message, Err(ConnectionError::TimedOut));
     }
 
-    pub fn id(&self) -> String {
-        self.socket_addr.to_string()
+    #[tokio::test(start_paused = true)]
+    async fn test_tls_handshake_timeout() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = temp_dir("tls");
+        fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+        let acceptor = TlsAcceptor::load(dir.join("cert.pem"), dir.join("key.pem")).unwrap();
+
+        let mut connection_manager = ConnectionManager::launch(
+            &["127.0.0.1:0,tls".parse().unwrap()],
+            Some(&acceptor),
+            BanList::new(),
+            ConnectionLimits::default(),
+            512,
+            Vec::new(),
+        )
+        .unwrap();
+        let addr = local_addr(&connection_manager);
+
+        // a client that never starts the handshake is given up on
+        let (_client, mut conn_read, _conn_write) = connect(&mut connection_manager, addr).await;
+        let started = tokio::time::Instant::now();
+        assert_eq!(
+            conn_read.read_message().await,
+            Err(ConnectionError::ConnectionLost)
+        );
+        assert!(started.elapsed() >= TLS_HANDSHAKE_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_write_message() {
+        let mut connection_manager = launch("127.0.0.1:0", Vec::new());
+        let addr = local_addr(&connection_manager);
+        let (mut client, mut conn_read, mut conn_write) =
+            connect(&mut connection_manager, addr).await;
+        // the writer starts along with the reader, once the connection is set up
+        let reader = tokio::spawn(async move { conn_read.read_message().await });
+
+        conn_write.write_message("hello\r\n").unwrap();
+        conn_write.write_message("world\r\n").unwrap();
+        assert_eq!(
+            read_until(&mut client, b"world\r\n").await,
+            b"hello\r\nworld\r\n"
+        );
+
+        // what is queued is still written, then the connection closes
+        conn_write.write_message("bye\r\n").unwrap();
+        conn_write.shutdown();
+        conn_write.write_message("too late\r\n").unwrap();
+        assert_eq!(
+            reader.await.unwrap(),
+            Err(ConnectionError::ConnectionClosed)
+        );
+        let mut received = Vec::new();
+        timeout(Duration::from_secs(5), client.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, b"bye\r\n");
+
+        let closed = conn_write.closed();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        spawn_blocking(move || closed.wait(deadline)).await.unwrap();
+        assert!(Instant::now() < deadline);
+    }
+
+    #[tokio::test]
+    async fn test_sendq_exceeded() {
+        let mut connection_manager = launch("127.0.0.1:0", Vec::new());
+        let addr = local_addr(&connection_manager);
+        let (mut client, mut conn_read, mut conn_write) =
+            connect(&mut connection_manager, addr).await;
+        conn_write.set_sendq(100);
+
+        // nothing is written before the reader starts, so it all waits in the SendQ
+        let line = format!(":server NOTICE you :{}\r\n", "a".repeat(20));
+        for _ in 0..3 {
+            conn_write.write_message(&line).unwrap();
+        }
+        assert_eq!(
+            conn_read.read_message().await,
+            Err(ConnectionError::ConnectionClosed)
+        );
+        assert_eq!(conn_read.close_reason().as_deref(), Some("SendQ exceeded"));
+
+        // only told why, and nothing after
+        conn_write.write_message(&line).unwrap();
+        let mut received = Vec::new();
+        timeout(Duration::from_secs(5), client.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, b"ERROR :SendQ exceeded\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_pending_proxy_headers() {
+        let mut connection_manager = launch("127.0.0.1:0,proxy", Vec::new());
+        let addr = local_addr(&connection_manager);
+
+        // connections that never send their header only take up so many places
+        let mut pending = Vec::new();
+        for _ in 0..MAX_PENDING_PROXY {
+            pending.push(TcpStream::connect(addr).await.unwrap());
+        }
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            read_until(&mut client, b"\r\n").await,
+            b"ERROR :Too many connections waiting, please try again later\r\n"
+        );
+
+        // and one that goes away makes room, once its header read gives up
+        drop(pending.pop());
+        let accept = async {
+            loop {
+                let mut client = TcpStream::connect(addr).await.unwrap();
+                client
+                    .write_all(b"PROXY TCP4 192.0.2.1 127.0.0.1 56324 6667\r\n")
+                    .await
+                    .unwrap();
+                let accepted = timeout(
+                    Duration::from_millis(100),
+                    connection_manager.accept_new_connection(),
+                )
+                .await;
+                if let Ok((conn_read, _)) = accepted {
+                    return (client, conn_read);
+                }
+            }
+        };
+        let (_client, conn_read) = timeout(Duration::from_secs(5), accept).await.unwrap();
+        assert_eq!(conn_read.ip(), "192.0.2.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_hand_over() {
+        let mut connection_manager = launch("127.0.0.1:0", Vec::new());
+        let addr = local_addr(&connection_manager);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut conn_read, mut conn_write) = connection_manager.accept_new_connection().await;
+        let id = conn_read.id();
+
+        conn_write.write_message("hello\r\n").unwrap();
+        client.write_all(b"PING a\r\nPART").await.unwrap();
+        assert_eq!(conn_read.read_message().await.unwrap(), "PING a");
+
+        // Stop everything, like for an upgrade
+        let listeners = connection_manager.hand_over_listeners().await;
+        let handover = conn_write.start_handover();
+        conn_write.write_message("after\r\n").unwrap();
+        assert!(matches!(
+            conn_read.read_message().await,
+            Err(ConnectionError::HandedOver)
+        ));
+        conn_read.hand_over(vec!["QUEUED".to_owned()]);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert!(spawn_blocking(move || handover.wait(deadline))
+            .await
+            .unwrap());
+        let state = conn_write.take_handover().unwrap();
+        assert_eq!(state.addr, client.local_addr().unwrap());
+        assert_eq!(state.input, vec!["QUEUED".to_owned()]);
+        assert_eq!(state.partial_input, b"PART");
+        let last_id = connection_manager.last_id();
+        drop((conn_write, connection_manager));
+
+        // and pick it up again, with a new connection manager
+        let mut connection_manager = launch("127.0.0.1:0", listeners);
+        assert_eq!(local_addr(&connection_manager), addr);
+        connection_manager.skip_ids(last_id);
+        let (mut conn_read, _conn_write) = connection_manager.resume(id, state).unwrap();
+
+        client.write_all(b"ING\r\n").await.unwrap();
+        assert_eq!(conn_read.read_message().await.unwrap(), "PARTING");
+        assert_eq!(
+            read_until(&mut client, b"after\r\n").await,
+            b"hello\r\nafter\r\n"
+        );
+
+        // the listener carries on too
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (conn_read, _) = connection_manager.accept_new_connection().await;
+        assert_ne!(conn_read.id(), id);
+    }
+
+    #[tokio::test]
+    async fn test_stop_listening() {
+        let connection_manager = launch("127.0.0.1:0", Vec::new());
+        let addr = local_addr(&connection_manager);
+        let _client = TcpStream::connect(addr).await.unwrap();
+
+        // like on shutdown, nobody else can connect once it is gone
+        drop(connection_manager);
+        let refused = timeout(Duration::from_secs(5), async {
+            loop {
+                match TcpStream::connect(addr).await {
+                    Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => break,
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await;
+        assert!(refused.is_ok());
+    }
+
+    #[test]
+    fn test_bind_unix() {
+        let dir = temp_dir("bind");
+        let path = dir.join("iris.sock");
+
+        let listener = bind_unix(&path, Some(0o600)).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        // nothing is left of where it was bound
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // a socket that is still listened on isn't taken over, one left behind is
+        assert_eq!(
+            bind_unix(&path, None).unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+        drop(listener);
+        let _listener = bind_unix(&path, Some(0o660)).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o660
+        );
+
+        // and anything else is left alone
+        let file = dir.join("file");
+        fs::write(&file, "not a socket").unwrap();
+        assert_eq!(
+            bind_unix(&file, None).unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+        assert_eq!(fs::read_to_string(&file).unwrap(), "not a socket");
+    }
+
+    #[tokio::test]
+    async fn test_unix_peer_uid() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = temp_dir("peer-uid");
+        let uid = fs::metadata(&dir).unwrap().uid();
+        let trusting = dir.join("trusting.sock");
+        let other = dir.join("other.sock");
+
+        // neither the limits on a host nor bans on the server's address are for local clients
+        let ban_list = BanList::new();
+        ban_list
+            .add(crate::ban::Ban {
+                mask: "127.0.0.1".parse().unwrap(),
+                reason: "spam".to_owned(),
+                set_by: "admin".to_owned(),
+                set_at: 0,
+                expires_at: None,
+            })
+            .unwrap();
+        let limits = ConnectionLimits {
+            max_per_host: 1,
+            throttle_count: 1,
+            ..Default::default()
+        };
+        let mut connection_manager = ConnectionManager::launch(
+            &[
+                format!("unix:{},trust-peer", trusting.display())
+                    .parse()
+                    .unwrap(),
+                format!("unix:{}", other.display()).parse().unwrap(),
+            ],
+            None,
+            ban_list,
+            limits,
+            512,
+            Vec::new(),
+        )
+        .unwrap();
+
+        for _ in 0..2 {
+            let _client = tokio::net::UnixStream::connect(&trusting).await.unwrap();
+            let (conn_read, conn_write) = connection_manager.accept_new_connection().await;
+            assert_eq!(conn_read.peer_uid(), Some(uid));
+            assert_eq!(conn_read.trusted_uid(), Some(uid));
+            assert_eq!(conn_write.remote_ip(), None);
+        }
+
+        let _client = tokio::net::UnixStream::connect(&other).await.unwrap();
+        let (conn_read, _) = connection_manager.accept_new_connection().await;
+        assert_eq!(conn_read.peer_uid(), Some(uid));
+        assert_eq!(conn_read.trusted_uid(), None);
     }
 }
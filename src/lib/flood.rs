@@ -0,0 +1,179 @@
+//! Flood control
+//!
+//! Every connection has its own flood control, modelled on the penalties
+//! used by classic ircds.
+//!
+//! Lines read from a client go into its receive queue (recvq). A line is only
+//! handed on once the client has enough budget: every command has a penalty
+//! which moves the client's "message time" forward, and the client may only
+//! run up to `burst` ahead of the real time. While a client is over budget its
+//! lines wait in the recvq; if the recvq grows past `recvq` bytes, the client
+//! is flooding and is disconnected with `ERROR :Excess Flood`.
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Limits for flood control.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FloodConfig {
+    /// How far ahead of real time a client's penalties may run before it is throttled.
+    pub burst: Duration,
+    /// How many bytes of unprocessed lines may wait for a throttled client.
+    pub recvq: usize,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        Self {
+            burst: Duration::from_secs(10),
+            recvq: 8192,
+        }
+    }
+}
+
+/// The penalty for a line, based on its command and length.
+pub fn penalty(line: &str) -> Duration {
+    let command = line
+        .split(' ')
+        .find(|word| !word.is_empty() && !word.starts_with(':'))
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+
+    let base = match command.as_str() {
+        // answering the server, or leaving, should never be held back
+        "PONG" | "QUIT" => return Duration::ZERO,
+        "PING" => Duration::from_millis(500),
+        "JOIN" | "PART" | "MODE" | "STATS" => Duration::from_secs(2),
+        "NICK" => Duration::from_secs(3),
+        _ => Duration::from_secs(1),
+    };
+
+    // long lines cost a little more
+    base + Duration::from_millis(line.len() as u64 * 4)
+}
+
+/// The client sent more than its recvq can hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExcessFlood;
+
+/// Flood control for a single connection.
+#[derive(Debug)]
+pub struct FloodControl {
+    config: FloodConfig,
+    message_time: Instant,
+    queue: VecDeque<String>,
+    queued_bytes: usize,
+}
+
+impl FloodControl {
+    pub fn new(config: FloodConfig) -> Self {
+        Self {
+            config,
+            message_time: Instant::now(),
+            queue: VecDeque::new(),
+            queued_bytes: 0,
+        }
+    }
+
+    /// Add a line read from the client to the recvq.
+    pub fn push(&mut self, line: String) -> Result<(), ExcessFlood> {
+        self.queued_bytes += line.len();
+        self.queue.push_back(line);
+
+        if self.queued_bytes > self.config.recvq {
+            return Err(ExcessFlood);
+        }
+
+        Ok(())
+    }
+
+    /// Take the next line from the recvq, if the client has the budget for it.
+    ///
+    /// Exempt clients are never held back, but their penalties are still counted.
+    pub fn pop(&mut self, exempt: bool) -> Option<String> {
+        let now = Instant::now();
+        let message_time = self.message_time.max(now);
+
+        let line = self.queue.front()?;
+        if !exempt && message_time > now + self.config.burst {
+            return None;
+        }
+
+        self.message_time = message_time + penalty(line);
+        self.queued_bytes -= line.len();
+        self.queue.pop_front()
+    }
+
+    /// Take every line left in the recvq, e.g. to hand them over with the connection.
+    pub fn take_queue(&mut self) -> Vec<String> {
+        self.queued_bytes = 0;
+        self.queue.drain(..).collect()
+    }
+
+    /// How long until the next line in the recvq may be taken, or `None` if it's empty.
+    pub fn wait_time(&self) -> Option<Duration> {
+        if self.queue.is_empty() {
+            return None;
+        }
+
+        let ready_at = self.message_time.checked_sub(self.config.burst);
+        Some(ready_at.map_or(Duration::ZERO, |ready_at| {
+            ready_at.saturating_duration_since(Instant::now())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> FloodConfig {
+        FloodConfig {
+            burst: Duration::from_secs(5),
+            recvq: 120,
+        }
+    }
+
+    #[test]
+    fn test_penalty() {
+        assert_eq!(penalty("PONG :iris-server"), Duration::ZERO);
+        assert!(penalty("NICK tom") > penalty("PRIVMSG tom :hi"));
+        assert!(penalty(&format!("PRIVMSG tom :{}", "a".repeat(400))) > penalty("PRIVMSG tom :hi"));
+    }
+
+    #[test]
+    fn test_burst_then_throttle() {
+        let mut flood = FloodControl::new(config());
+        for _ in 0..10 {
+            flood.push("PRIVMSG a :b".to_owned()).unwrap();
+        }
+
+        // the burst allows about five one-second messages through at once
+        let mut released = 0;
+        while flood.pop(false).is_some() {
+            released += 1;
+        }
+        assert!((5..=6).contains(&released));
+        assert!(flood.wait_time().unwrap() > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_exempt() {
+        let mut flood = FloodControl::new(config());
+        for _ in 0..8 {
+            flood.push("PRIVMSG a :b".to_owned()).unwrap();
+        }
+        while flood.pop(true).is_some() {}
+        assert_eq!(flood.wait_time(), None);
+    }
+
+    #[test]
+    fn test_excess_flood() {
+        let mut flood = FloodControl::new(config());
+        for _ in 0..10 {
+            flood.push("PRIVMSG a :b".to_owned()).unwrap();
+        }
+        assert_eq!(flood.push("PRIVMSG a :b".to_owned()), Err(ExcessFlood));
+    }
+}
@@ -0,0 +1,204 @@
+//! Keepalive
+//!
+//! This module checks every connection regularly, so that half-open TCP
+//! connections and clients that never register don't live forever.
+//!
+//! Registered users that have been idle for `ping_interval` are sent a `PING`.
+//! If they don't send anything back within `ping_timeout`, they are told
+//! `ERROR :Ping timeout` and a `QUIT` is handed to the message handler, which
+//! tells their channels and removes them.
+use crate::{
+    config::SharedConfig,
+    types::{server_name, ErrorType, Message, ParsedMessage, QuitMsg, Reply, UserId},
+    user::UserList,
+};
+use log::info;
+use std::{sync::mpsc::Sender, time::Duration};
+use tokio::task::JoinHandle;
+
+/// How often connections are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Timeouts used to decide when to ping or drop a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    /// How long a registered user may be idle before they are sent a `PING`.
+    pub ping_interval: Duration,
+    /// How long a user has to answer a `PING`.
+    pub ping_timeout: Duration,
+    /// How long a connection has to complete `NICK`/`USER` registration.
+    pub registration_timeout: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(120),
+            ping_timeout: Duration::from_secs(60),
+            registration_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Spawn the task that pings idle users and drops timed out connections.
+///
+/// Timed out users are handed to the message handler through `sender` as a `QUIT`.
+/// The timeouts are taken from the server configuration on every check.
+pub fn spawn_keepalive(
+    user_list: UserList,
+    sender: Sender<Result<ParsedMessage, (ErrorType, UserId)>>,
+    config: SharedConfig,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let config = config.get().keepalive;
+
+            for user in user_list.all_users() {
+                let mut user = user.lock().expect("Failed to lock user");
+                let reason = if user.is_quitting() {
+                    // the message handler hasn't got to them yet
+                    continue;
+                } else if !user.is_registered() {
+                    if user.connected_for() < config.registration_timeout {
+                        continue;
+                    }
+                    "Registration timeout"
+                } else if let Some(ping_sent_for) = user.ping_sent_for() {
+                    if ping_sent_for < config.ping_timeout {
+                        continue;
+                    }
+                    "Ping timeout"
+                } else {
+                    if user.idle_for() >= config.ping_interval {
+                        // A failed write means the connection is already gone,
+                        // the timeout will clean it up.
+                        let _ = user.send(Reply::Ping(server_name().to_owned()));
+                        user.ping_sent();
+                    }
+                    continue;
+                };
+
+                info!("Disconnecting {}: {}", user.get_nick(), reason);
+                let _ = user.send(Reply::ErrorMsg(reason.to_owned()));
+
+                sender
+                    .send(Ok(ParsedMessage {
+                        sender: user.get_id(),
+                        message: Message::Quit(QuitMsg {
+                            message: Some(reason.to_owned()),
+                        }),
+                    }))
+                    .expect("The channel is closed!");
+                user.set_quitting();
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::ServerConfig,
+        connect::ConnectionWrite,
+        user::{RegistrationState, User},
+    };
+    use std::sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    };
+    use tokio::time::sleep;
+
+    type Received = Receiver<Result<ParsedMessage, (ErrorType, UserId)>>;
+
+    /// A user that just connected, checked with the default timeouts.
+    fn connect() -> (Arc<Mutex<User>>, Received, JoinHandle<()>) {
+        let user_list = UserList::new();
+        let id = UserId(1);
+        let conn_write = ConnectionWrite::for_test("10.0.0.1:50000".parse().unwrap(), id);
+        user_list.add_user(User::new(id, conn_write, "host".to_owned()));
+        let user = user_list.get(id).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let keepalive = spawn_keepalive(
+            user_list,
+            sender,
+            SharedConfig::new(ServerConfig::default()),
+        );
+        (user, receiver, keepalive)
+    }
+
+    /// The reasons of the `QUIT`s handed to the message handler so far.
+    fn quits(receiver: &Received) -> Vec<String> {
+        receiver
+            .try_iter()
+            .map(|parsed_msg| match parsed_msg {
+                Ok(ParsedMessage {
+                    message:
+                        Message::Quit(QuitMsg {
+                            message: Some(reason),
+                        }),
+                    ..
+                }) => reason,
+                other => panic!("Not a QUIT: {other:?}"),
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_registration_timeout() {
+        let (user, receiver, _keepalive) = connect();
+
+        sleep(Duration::from_secs(59)).await;
+        assert!(quits(&receiver).is_empty());
+
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(quits(&receiver), vec!["Registration timeout"]);
+        assert_eq!(
+            user.lock().unwrap().take_sent(),
+            vec!["ERROR :Registration timeout\r\n"]
+        );
+
+        // only once, however long the message handler takes to remove them
+        sleep(Duration::from_secs(10)).await;
+        assert!(quits(&receiver).is_empty());
+        assert!(user.lock().unwrap().take_sent().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ping_timeout() {
+        let (user, receiver, _keepalive) = connect();
+        user.lock()
+            .unwrap()
+            .set_registration(RegistrationState::Registered);
+
+        sleep(Duration::from_secs(119)).await;
+        assert!(user.lock().unwrap().take_sent().is_empty());
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(
+            user.lock().unwrap().take_sent(),
+            vec![format!("PING :{}\r\n", server_name())]
+        );
+
+        // an answer, or anything else, puts the next PING off
+        user.lock().unwrap().touch();
+        sleep(Duration::from_secs(100)).await;
+        assert!(user.lock().unwrap().take_sent().is_empty());
+        sleep(Duration::from_secs(21)).await;
+        assert_eq!(user.lock().unwrap().take_sent().len(), 1);
+
+        sleep(Duration::from_secs(59)).await;
+        assert!(quits(&receiver).is_empty());
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(quits(&receiver), vec!["Ping timeout"]);
+        assert_eq!(
+            user.lock().unwrap().take_sent(),
+            vec!["ERROR :Ping timeout\r\n"]
+        );
+
+        sleep(Duration::from_secs(10)).await;
+        assert!(quits(&receiver).is_empty());
+    }
+}
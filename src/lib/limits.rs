@@ -0,0 +1,418 @@
+//! Connection limits
+//!
+//! The connection manager keeps count of who is connected, so that no single
+//! host can take up the whole server:
+//!
+//! - at most `max_clients` connections in total,
+//! - at most `max_per_host` connections per host, where every IPv6 address in
+//!   the same /64 counts as one host, since that is usually one machine,
+//! - at most `throttle_count` new connections per host within `throttle_window`.
+//!
+//! Every accepted connection holds a `ConnectionSlot`, which gives its place
+//! back when dropped, so however a connection ends, it is no longer counted.
+//! A slot can move to another host, when a trusted gateway says who the
+//! client really is.
+//!
+//! Clients on a Unix socket are on the server's own host, and have no address
+//! of their own to tell them apart, so they only count towards the total.
+use ipnet::IpNet;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How many bits of an IPv6 address identify a host.
+const IPV6_HOST_PREFIX: u8 = 64;
+
+/// Limits on how many connections are accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// How many connections the server accepts in total.
+    pub max_clients: usize,
+    /// How many connections a single host may have open.
+    pub max_per_host: usize,
+    /// How many times a single host may connect within `throttle_window`.
+    pub throttle_count: usize,
+    pub throttle_window: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_clients: 1024,
+            max_per_host: 10,
+            throttle_count: 10,
+            throttle_window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Why a connection was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    ServerFull,
+    TooManyFromHost,
+    Throttled,
+}
+
+impl Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitError::ServerFull => write!(f, "The server is full"),
+            LimitError::TooManyFromHost => write!(f, "Too many connections from your host"),
+            LimitError::Throttled => write!(f, "Reconnecting too fast, please wait"),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+/// The host a connection counts towards.
+fn host_of(ip: IpAddr) -> IpNet {
+    let prefix_len = match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => IPV6_HOST_PREFIX,
+    };
+    IpNet::new(ip, prefix_len)
+        .expect("prefix length is valid")
+        .trunc()
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    per_host: HashMap<IpNet, usize>,
+    recent_connects: HashMap<IpNet, VecDeque<Instant>>,
+}
+
+impl Counts {
+    /// Count a connect from `host` towards the throttle, failing if it has connected too often.
+    fn throttle(&mut self, host: IpNet, limits: &ConnectionLimits) -> Result<(), LimitError> {
+        let now = Instant::now();
+
+        // forget connects that have left the throttle window, and hosts with none left
+        let window = limits.throttle_window;
+        self.recent_connects.retain(|_, connects| {
+            while connects
+                .front()
+                .is_some_and(|connected_at| now.duration_since(*connected_at) >= window)
+            {
+                connects.pop_front();
+            }
+            !connects.is_empty()
+        });
+
+        // every attempt counts towards the throttle, so retrying doesn't get around it
+        let connects = self.recent_connects.entry(host).or_default();
+        connects.push_back(now);
+        if connects.len() > limits.throttle_count {
+            return Err(LimitError::Throttled);
+        }
+
+        Ok(())
+    }
+
+    /// Count a connection from `host`, if it doesn't have too many already.
+    fn add_to_host(&mut self, host: IpNet, limits: &ConnectionLimits) -> Result<(), LimitError> {
+        let per_host = self.per_host.entry(host).or_default();
+        if *per_host >= limits.max_per_host {
+            return Err(LimitError::TooManyFromHost);
+        }
+        *per_host += 1;
+
+        Ok(())
+    }
+
+    fn remove_from_host(&mut self, host: IpNet) {
+        if let Some(per_host) = self.per_host.get_mut(&host) {
+            *per_host -= 1;
+            if *per_host == 0 {
+                self.per_host.remove(&host);
+            }
+        }
+    }
+}
+
+/// Counts the open connections, handing out a `ConnectionSlot` to each one.
+#[derive(Debug, Clone)]
+pub struct ConnectionCounter {
+    limits: ConnectionLimits,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl ConnectionCounter {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            counts: Arc::new(Mutex::new(Counts::default())),
+        }
+    }
+
+    /// Take a slot for a new connection from `ip`, if it is within the limits.
+    pub fn acquire(&self, ip: IpAddr) -> Result<ConnectionSlot, LimitError> {
+        let host = host_of(ip);
+        let mut counts = self
+            .counts
+            .lock()
+            .expect("Failed to lock connection counts");
+
+        counts.throttle(host, &self.limits)?;
+
+        if counts.total >= self.limits.max_clients {
+            return Err(LimitError::ServerFull);
+        }
+
+        counts.add_to_host(host, &self.limits)?;
+        counts.total += 1;
+
+        Ok(ConnectionSlot {
+            host: Some(host),
+            limits: self.limits,
+            counts: self.counts.clone(),
+        })
+    }
+
+    /// Take a slot for a new connection on a Unix socket, if the server isn't full.
+    pub fn acquire_local(&self) -> Result<ConnectionSlot, LimitError> {
+        let mut counts = self
+            .counts
+            .lock()
+            .expect("Failed to lock connection counts");
+
+        if counts.total >= self.limits.max_clients {
+            return Err(LimitError::ServerFull);
+        }
+        counts.total += 1;
+
+        Ok(ConnectionSlot {
+            host: None,
+            limits: self.limits,
+            counts: self.counts.clone(),
+        })
+    }
+
+    /// Count a connection from `ip`, or on a Unix socket if `None`, that is
+    /// already open, whatever the limits, e.g. one handed over by the server
+    /// that ran before.
+    pub fn add(&self, ip: Option<IpAddr>) -> ConnectionSlot {
+        let host = ip.map(host_of);
+        let mut counts = self
+            .counts
+            .lock()
+            .expect("Failed to lock connection counts");
+
+        if let Some(host) = host {
+            *counts.per_host.entry(host).or_default() += 1;
+        }
+        counts.total += 1;
+
+        ConnectionSlot {
+            host,
+            limits: self.limits,
+            counts: self.counts.clone(),
+        }
+    }
+
+    /// Change the limits new connections are held to. Connections that are
+    /// already open stay, even if there are more of them than the new limits allow.
+    pub fn set_limits(&mut self, limits: ConnectionLimits) {
+        self.limits = limits;
+    }
+
+    /// How many connections are open.
+    pub fn total(&self) -> usize {
+        self.counts
+            .lock()
+            .expect("Failed to lock connection counts")
+            .total
+    }
+}
+
+/// A connection's place in the counts, given back when dropped.
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    /// The host the connection counts towards, unless it is on a Unix socket.
+    host: Option<IpNet>,
+    limits: ConnectionLimits,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl ConnectionSlot {
+    /// Count the connection towards `ip` instead, if that host is within the limits.
+    /// Otherwise it stays where it is.
+    pub fn move_to(&mut self, ip: IpAddr) -> Result<(), LimitError> {
+        let host = host_of(ip);
+        let mut counts = self
+            .counts
+            .lock()
+            .expect("Failed to lock connection counts");
+
+        counts.throttle(host, &self.limits)?;
+        if Some(host) == self.host {
+            return Ok(());
+        }
+
+        counts.add_to_host(host, &self.limits)?;
+        if let Some(old_host) = self.host {
+            counts.remove_from_host(old_host);
+        }
+        self.host = Some(host);
+
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self
+            .counts
+            .lock()
+            .expect("Failed to lock connection counts");
+        counts.total -= 1;
+        if let Some(host) = self.host {
+            counts.remove_from_host(host);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> ConnectionLimits {
+        ConnectionLimits {
+            max_clients: 3,
+            max_per_host: 2,
+            throttle_count: 100,
+            throttle_window: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn test_per_host() {
+        let counter = ConnectionCounter::new(limits());
+        let ip = "10.0.0.1".parse().unwrap();
+
+        let first = counter.acquire(ip).unwrap();
+        let _second = counter.acquire(ip).unwrap();
+        assert_eq!(
+            counter.acquire(ip).unwrap_err(),
+            LimitError::TooManyFromHost
+        );
+
+        // giving a slot back makes room again
+        drop(first);
+        assert!(counter.acquire(ip).is_ok());
+    }
+
+    #[test]
+    fn test_set_limits() {
+        let mut counter = ConnectionCounter::new(limits());
+        let ip = "10.0.0.1".parse().unwrap();
+
+        let _first = counter.acquire(ip).unwrap();
+        let _second = counter.acquire(ip).unwrap();
+
+        counter.set_limits(ConnectionLimits {
+            max_per_host: 1,
+            ..limits()
+        });
+        assert_eq!(counter.total(), 2);
+        assert_eq!(
+            counter.acquire(ip).unwrap_err(),
+            LimitError::TooManyFromHost
+        );
+        assert!(counter.acquire("10.0.0.2".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_ipv6_host() {
+        let counter = ConnectionCounter::new(limits());
+
+        let _first = counter.acquire("2001:db8::1".parse().unwrap()).unwrap();
+        let _second = counter.acquire("2001:db8::2".parse().unwrap()).unwrap();
+        assert_eq!(
+            counter.acquire("2001:db8::3".parse().unwrap()).unwrap_err(),
+            LimitError::TooManyFromHost
+        );
+        assert!(counter.acquire("2001:db8:0:1::1".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let counter = ConnectionCounter::new(limits());
+
+        let slots = ["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+            .map(|ip| counter.acquire(ip.parse().unwrap()).unwrap());
+        assert_eq!(
+            counter.acquire("10.0.0.4".parse().unwrap()).unwrap_err(),
+            LimitError::ServerFull
+        );
+
+        drop(slots);
+        assert_eq!(counter.total(), 0);
+    }
+
+    #[test]
+    fn test_throttle() {
+        let counter = ConnectionCounter::new(ConnectionLimits {
+            throttle_count: 2,
+            ..limits()
+        });
+        let ip = "10.0.0.1".parse().unwrap();
+
+        drop(counter.acquire(ip).unwrap());
+        drop(counter.acquire(ip).unwrap());
+        assert_eq!(counter.acquire(ip).unwrap_err(), LimitError::Throttled);
+        assert!(counter.acquire("10.0.0.2".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_move_to() {
+        let counter = ConnectionCounter::new(limits());
+        let gateway = "10.0.0.1".parse().unwrap();
+        let client = "10.0.0.2".parse().unwrap();
+
+        let mut first = counter.acquire(gateway).unwrap();
+        let mut second = counter.acquire(gateway).unwrap();
+        first.move_to(client).unwrap();
+        second.move_to(client).unwrap();
+
+        // the gateway has room again, and the client has none
+        let mut third = counter.acquire(gateway).unwrap();
+        assert_eq!(
+            third.move_to(client).unwrap_err(),
+            LimitError::TooManyFromHost
+        );
+        assert_eq!(counter.total(), 3);
+
+        drop((first, second));
+        assert!(third.move_to(client).is_ok());
+    }
+
+    #[test]
+    fn test_local() {
+        let counter = ConnectionCounter::new(ConnectionLimits {
+            throttle_count: 1,
+            ..limits()
+        });
+
+        // however often they connect, local clients only count towards the total
+        let mut first = counter.acquire_local().unwrap();
+        let _second = counter.acquire_local().unwrap();
+        let _third = counter.acquire_local().unwrap();
+        assert_eq!(counter.acquire_local().unwrap_err(), LimitError::ServerFull);
+
+        // unless a gateway on the socket says where they are
+        first.move_to("10.0.0.1".parse().unwrap()).unwrap();
+        drop(first);
+        assert_eq!(counter.total(), 2);
+        assert_eq!(
+            counter.acquire("10.0.0.1".parse().unwrap()).unwrap_err(),
+            LimitError::Throttled
+        );
+    }
+}
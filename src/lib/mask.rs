@@ -0,0 +1,158 @@
+//! Masks
+//!
+//! Hosts are matched against masks such as `*.example.com` or `10.0.0.?`,
+//! where `*` matches any number of characters and `?` matches exactly one.
+//! Matching ignores ASCII case.
+//!
+//! `HostMask` also accepts IP addresses and CIDR ranges such as `10.0.0.0/8`.
+use ipnet::IpNet;
+use std::{fmt::Display, net::IpAddr, str::FromStr};
+
+/// Check whether `text` matches the wildcard `mask`.
+pub fn matches(mask: &str, text: &str) -> bool {
+    let mask = mask.to_ascii_lowercase().chars().collect::<Vec<_>>();
+    let text = text.to_ascii_lowercase().chars().collect::<Vec<_>>();
+
+    // Where to resume if the current attempt fails: the last `*` seen in the mask,
+    // and the position in the text it was matched up to.
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut m, mut t) = (0, 0);
+
+    while t < text.len() {
+        match mask.get(m) {
+            Some('*') => {
+                backtrack = Some((m, t));
+                m += 1;
+            }
+            Some('?') => {
+                m += 1;
+                t += 1;
+            }
+            Some(c) if *c == text[t] => {
+                m += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // let the last `*` swallow one more character
+                Some((star_m, star_t)) => {
+                    backtrack = Some((star_m, star_t + 1));
+                    m = star_m + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    mask[m..].iter().all(|c| *c == '*')
+}
+
+/// A mask for the host part of a connection: an IP address or CIDR range,
+/// or a wildcard mask.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostMask {
+    /// An IP address or CIDR range.
+    Net(IpNet),
+    /// A wildcard mask.
+    Wildcard(String),
+}
+
+impl HostMask {
+    /// Check whether a connection from `ip`, known as `host`, matches this mask.
+    pub fn matches(&self, ip: IpAddr, host: &str) -> bool {
+        match self {
+            HostMask::Net(net) => net.contains(&ip),
+            HostMask::Wildcard(mask) => matches(mask, host) || matches(mask, &ip.to_string()),
+        }
+    }
+
+    /// Check whether a connection with no IP address of its own, known as
+    /// `host`, matches this mask.
+    pub fn matches_host(&self, host: &str) -> bool {
+        match self {
+            HostMask::Net(_) => false,
+            HostMask::Wildcard(mask) => matches(mask, host),
+        }
+    }
+}
+
+impl FromStr for HostMask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.contains(char::is_whitespace) {
+            Err(format!("invalid host mask: {s}"))
+        } else if let Ok(net) = s.parse::<IpNet>() {
+            Ok(HostMask::Net(net.trunc()))
+        } else if let Ok(ip) = s.parse::<IpAddr>() {
+            Ok(HostMask::Net(IpNet::from(ip)))
+        } else if s.contains('/') {
+            Err(format!("invalid CIDR range: {s}"))
+        } else {
+            Ok(HostMask::Wildcard(s.to_ascii_lowercase()))
+        }
+    }
+}
+
+impl Display for HostMask {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            // single addresses are shown without a prefix length
+            HostMask::Net(net) if net.prefix_len() == net.max_prefix_len() => {
+                write!(fmt, "{}", net.addr())
+            }
+            HostMask::Net(net) => write!(fmt, "{}", net),
+            HostMask::Wildcard(mask) => write!(fmt, "{}", mask),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact() {
+        assert!(matches("127.0.0.1", "127.0.0.1"));
+        assert!(!matches("127.0.0.1", "127.0.0.10"));
+        assert!(matches("Host.Example.COM", "host.example.com"));
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("*.example.com", "irc.example.com"));
+        assert!(!matches("*.example.com", "example.com"));
+        assert!(matches("10.0.0.?", "10.0.0.7"));
+        assert!(!matches("10.0.0.?", "10.0.0.77"));
+        assert!(matches("a*b*c", "aXXbYYbZZc"));
+        assert!(!matches("a*b*c", "aXXbYYbZZ"));
+    }
+
+    #[test]
+    fn test_host_mask() {
+        let ip = "10.1.2.3".parse().unwrap();
+        assert!("10.0.0.0/8"
+            .parse::<HostMask>()
+            .unwrap()
+            .matches(ip, "10.1.2.3"));
+        assert!("10.1.2.3"
+            .parse::<HostMask>()
+            .unwrap()
+            .matches(ip, "10.1.2.3"));
+        assert!("*.example.com"
+            .parse::<HostMask>()
+            .unwrap()
+            .matches(ip, "a.example.com"));
+        assert!(!"10.1.2.4"
+            .parse::<HostMask>()
+            .unwrap()
+            .matches(ip, "10.1.2.3"));
+        assert!("10.0.0.0/99".parse::<HostMask>().is_err());
+        assert_eq!(
+            "10.1.2.3/8".parse::<HostMask>().unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+    }
+}
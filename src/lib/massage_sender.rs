@@ -1,57 +1,161 @@
 //! This module contains the functions that send messages to the users.
 use crate::{
+    ban::{unix_time, Ban, BanList, BanMask},
     channel_list::ChannelList,
+    config::ServerConfig,
+    password,
     types::{
-        self, Channel, ErrorType, JoinMsg, JoinReply, Nick, NickMsg, PartMsg, PartReply, PrivMsg,
-        PrivReply, QuitReply, Reply, Target, WelcomeReply,
+        self, nick_len, AuthenticateMsg, CapMsg, CapReply, Channel, EndOfStatsReply,
+        EndOfWhoisReply, ErrorType, ISupportReply, JoinMsg, JoinReply, KillMsg, KlineMsg,
+        LoggedInReply, Message, ModeMsg, ModeReply, MotdReply, Nick, NickMsg, NickReply,
+        NoticeReply, OperMsg, PartMsg, PartReply, PassMsg, Prefix, PrivMsg, PrivReply, QuitMsg,
+        QuitReply, Reply, SaslMechsReply, SaslSuccessReply, StatsKlineReply, StatsMsg, Target,
+        UModeIsReply, UnklineMsg, UserId, WallopsReply, WebircMsg, WelcomeReply, WhoisAccountReply,
+        WhoisActuallyReply, WhoisCertfpReply, WhoisChannelsReply, WhoisMsg, WhoisOperatorReply,
+        WhoisServerReply, WhoisUserReply, YoureOperReply, CAPABILITIES, SASL_MECHANISMS,
     },
-    user::UserList, plugin,
+    user::{RegistrationState, UserList}, plugin,
+    casemapping::casemapping,
+    split::{split_text, LongMessages, MAX_LINE_LEN},
 };
 use anyhow::{anyhow, Error, Result};
-use log::error;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use log::{error, info, warn};
+use std::{collections::HashSet, net::IpAddr, time::Instant};
 
 /// Send a message to a user.
 pub fn global_msg_sender(
     user_list: &mut UserList,
     channel_list: &mut ChannelList,
+    config: &ServerConfig,
+    ban_list: &BanList,
     parsed_msg: types::ParsedMessage,
 ) -> Result<()> {
-    match parsed_msg.message {
-        types::Message::Nick(nick_msg) => {
-            nick_msg_sender(user_list, nick_msg, parsed_msg.sender_nick)
+    {
+        let registered = user_list
+            .get(parsed_msg.sender)
+            .is_some_and(|user| user.lock().expect("Failed to lock user").is_registered());
+        if !registered && !allowed_before_registration(&parsed_msg.message) {
+            return Err(anyhow!(ErrorType::NotRegistered));
         }
-        types::Message::User(user_msg) => {
-            user_msg_sender(user_list, user_msg, parsed_msg.sender_nick)
+    }
+
+    match parsed_msg.message {
+        types::Message::Nick(nick_msg) => nick_msg_sender(
+            user_list,
+            channel_list,
+            config,
+            ban_list,
+            nick_msg,
+            parsed_msg.sender,
+        ),
+        types::Message::User(user_msg) => user_msg_sender(
+            user_list,
+            channel_list,
+            config,
+            ban_list,
+            user_msg,
+            parsed_msg.sender,
+        ),
+        types::Message::Pass(pass_msg) => pass_msg_sender(user_list, pass_msg, parsed_msg.sender),
+        types::Message::Webirc(webirc_msg) => webirc_msg_sender(
+            user_list,
+            channel_list,
+            config,
+            webirc_msg,
+            parsed_msg.sender,
+        ),
+        types::Message::Authenticate(authenticate_msg) => {
+            authenticate_msg_sender(user_list, config, authenticate_msg, parsed_msg.sender)
         }
-        types::Message::Ping(ping_msg) => {
-            ping_msg_sender(user_list, ping_msg, parsed_msg.sender_nick)
+        types::Message::Cap(cap_msg) => cap_msg_sender(
+            user_list,
+            channel_list,
+            config,
+            ban_list,
+            cap_msg,
+            parsed_msg.sender,
+        ),
+        types::Message::Ping(ping_msg) => ping_msg_sender(user_list, ping_msg, parsed_msg.sender),
+        types::Message::Pong(_) => {
+            // The reader thread already recorded the activity, nothing else to do.
+            Ok(())
         }
         types::Message::Quit(quit_msg) => {
-            quit_msg_sender(user_list, channel_list, quit_msg, parsed_msg.sender_nick)
+            quit_msg_sender(user_list, channel_list, quit_msg, parsed_msg.sender)
         }
         types::Message::PrivMsg(priv_msg) => {
-            priv_msg_sender(user_list, channel_list, priv_msg, parsed_msg.sender_nick)
+            priv_msg_sender(user_list, channel_list, config, priv_msg, parsed_msg.sender)
         }
         types::Message::Join(join_msg) => {
-            join_msg_sender(user_list, channel_list, join_msg, parsed_msg.sender_nick)
+            join_msg_sender(user_list, channel_list, join_msg, parsed_msg.sender)
         }
         types::Message::Part(part_msg) => {
-            part_msg_sender(user_list, channel_list, part_msg, parsed_msg.sender_nick)
+            part_msg_sender(user_list, channel_list, part_msg, parsed_msg.sender)
+        }
+        types::Message::Mode(mode_msg) => {
+            mode_msg_sender(user_list, channel_list, mode_msg, parsed_msg.sender)
+        }
+        types::Message::Oper(oper_msg) => {
+            oper_msg_sender(user_list, config, oper_msg, parsed_msg.sender)
+        }
+        types::Message::Kill(kill_msg) => {
+            kill_msg_sender(user_list, channel_list, kill_msg, parsed_msg.sender)
+        }
+        types::Message::Wallops(wallops_msg) => {
+            wallops_msg_sender(user_list, wallops_msg, parsed_msg.sender)
+        }
+        types::Message::Die => {
+            die_msg_sender(user_list, channel_list, config, false, parsed_msg.sender)
+        }
+        types::Message::Restart => {
+            die_msg_sender(user_list, channel_list, config, true, parsed_msg.sender)
+        }
+        types::Message::Kline(kline_msg) => kline_msg_sender(
+            user_list,
+            channel_list,
+            ban_list,
+            kline_msg,
+            parsed_msg.sender,
+        ),
+        types::Message::Unkline(unkline_msg) => {
+            unkline_msg_sender(user_list, ban_list, unkline_msg, parsed_msg.sender)
+        }
+        types::Message::Stats(stats_msg) => {
+            stats_msg_sender(user_list, ban_list, stats_msg, parsed_msg.sender)
+        }
+        types::Message::Whois(whois_msg) => {
+            whois_msg_sender(user_list, channel_list, whois_msg, parsed_msg.sender)
         }
     }
 }
 
+/// Whether a user that hasn't registered yet may send `message`.
+fn allowed_before_registration(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::Nick(_)
+            | Message::User(_)
+            | Message::Pass(_)
+            | Message::Cap(_)
+            | Message::Authenticate(_)
+            | Message::Webirc(_)
+            | Message::Ping(_)
+            | Message::Pong(_)
+            | Message::Quit(_)
+    )
+}
+
 /// Handle error messages.
-pub fn error_msg_sender(err: Error, user_list: &UserList, sender_nick: Nick) {
+pub fn error_msg_sender(err: Error, user_list: &UserList, sender: UserId) {
     if let Some(err) = err.downcast_ref::<ErrorType>() {
-        let users = user_list.get_users();
-        let mut users = users.lock().expect("Failed to lock users");
-        let user = users
-            .iter_mut()
-            .find(|user| user.get_nick() == sender_nick)
-            .expect("Failed to find user");
-        user.send_back_error(*err)
-            .expect("Failed to send back error");
+        // the user may have left before their error could be sent
+        if let Some(user) = user_list.get(sender) {
+            let mut user = user.lock().expect("Failed to lock user");
+            if let Err(err) = user.send_back_error(err.clone()) {
+                warn!("Failed to send back error to {}: {}", user.get_nick(), err);
+            }
+        }
     } else {
         error!("Server Error: {}", err);
     }
@@ -59,69 +163,432 @@ pub fn error_msg_sender(err: Error, user_list: &UserList, sender_nick: Nick) {
 
 fn nick_msg_sender(
     user_list: &mut UserList,
+    channel_list: &mut ChannelList,
+    config: &ServerConfig,
+    ban_list: &BanList,
     nick_msg: NickMsg,
-    user_id_as_nick: Nick,
+    sender: UserId,
 ) -> Result<()> {
-    let nick = nick_msg.nick;
+    // Find the user by user id
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let mut user = user.lock().expect("Failed to lock user");
 
-    let users = user_list.get_users();
-    let mut users = users.lock().expect("Failed to lock users");
+    // Check if the nick is valid, if not, return an error
+    let nick = Nick::try_from(nick_msg.nick.0)?;
 
-    // Check if nick exists and if it does, return an error
-    if users.iter().any(|user| user.get_nick() == nick) {
-        return Err(anyhow!(ErrorType::NickCollision));
+    let old_prefix = user.get_prefix();
+    let registered = user.is_registered();
+
+    // Set the nick, unless someone else has it.
+    // Nicks are compared with the server's casemapping, but a user
+    // may still change the case of their own nick.
+    if !user_list.set_nick(&mut user, nick.clone()) {
+        return Err(anyhow!(ErrorType::NickCollision(nick.0)));
     }
 
-    // Find the user by user id
-    let user = users
-        .iter_mut()
-        .find(|user| user.get_nick() == user_id_as_nick)
-        .ok_or(anyhow!("User not found"))?;
+    // nobody else knows a user that hasn't registered yet
+    if !registered {
+        drop(user);
+        return complete_registration(user_list, channel_list, config, ban_list, sender);
+    }
 
-    // Check if the nick is valid, if not, return an error
-    let nick = Nick::try_from(nick.0)?;
-    // Set the nick
-    user.set_nick(nick.0);
+    // tell the user and everyone sharing a channel with them, once each
+    let mut ids_to_tell = HashSet::from([user.get_id()]);
+    for channel in user.get_joined_channels() {
+        ids_to_tell.extend(
+            channel_list
+                .get_users(channel)
+                .into_iter()
+                .flatten()
+                .copied(),
+        );
+    }
+    drop(user);
+
+    for other_user_id in ids_to_tell {
+        let Some(other_user) = user_list.get(other_user_id) else {
+            continue;
+        };
+        let mut other_user = other_user.lock().expect("Failed to lock user");
+
+        if let Err(err) = other_user.send(Reply::Nick(NickReply {
+            sender: old_prefix.clone(),
+            nick: nick.clone(),
+        })) {
+            warn!("Failed to send NICK to {}: {}", other_user.get_nick(), err);
+        }
+    }
 
     Ok(())
 }
 
 fn user_msg_sender(
     user_list: &mut UserList,
+    channel_list: &mut ChannelList,
+    config: &ServerConfig,
+    ban_list: &BanList,
     user_msg: types::UserMsg,
-    user_id_as_nick: Nick,
+    sender: UserId,
 ) -> Result<()> {
-    let users = user_list.get_users();
-    let mut users = users.lock().expect("Failed to lock users");
-    let user = users
-        .iter_mut()
-        .find(|user| user.get_nick() == user_id_as_nick)
-        .ok_or(anyhow!("User not found"))?;
-
-    if user.is_set_nick() && !user.is_set_real_name() {
+    {
+        let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+        let mut user = user.lock().expect("Failed to lock user");
+
+        // USER may only be sent once, even before registering
+        if user.is_set_real_name() {
+            return Err(anyhow!(ErrorType::AlreadyRegistered));
+        }
+
+        user.set_username(user_msg.username);
         user.set_real_name(user_msg.real_name);
+    }
 
-        user.send(Reply::Welcome(WelcomeReply {
-            target_nick: user.get_real_name(),
-            message: format!("Welcome to the server, {}!", user.get_real_name()),
-        }))?;
+    complete_registration(user_list, channel_list, config, ban_list, sender)
+}
+
+fn pass_msg_sender(user_list: &mut UserList, pass_msg: PassMsg, sender: UserId) -> Result<()> {
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let mut user = user.lock().expect("Failed to lock user");
+
+    // the password is only checked while registering, and only once
+    if user.is_registered() || user.get_password().is_some() {
+        return Err(anyhow!(ErrorType::AlreadyRegistered));
     }
 
+    user.set_password(pass_msg.password);
+
     Ok(())
 }
 
-fn ping_msg_sender(user_list: &mut UserList, ping_msg: String, sender_nick: Nick) -> Result<()> {
-    let users = user_list.get_users();
-    let mut users = users.lock().expect("Failed to lock users");
-    let user = users
-        .iter_mut()
-        .find(|user| user.get_nick() == sender_nick)
-        .ok_or(anyhow!("User not found"))?;
+fn cap_msg_sender(
+    user_list: &mut UserList,
+    channel_list: &mut ChannelList,
+    config: &ServerConfig,
+    ban_list: &BanList,
+    cap_msg: CapMsg,
+    sender: UserId,
+) -> Result<()> {
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let mut user = user.lock().expect("Failed to lock user");
+
+    let target_nick = user.get_reply_nick();
+
+    match cap_msg.subcommand.as_str() {
+        "LS" | "REQ" | "LIST" => {
+            // registration waits until the client is done negotiating
+            if user.get_registration() == RegistrationState::Connecting {
+                user.set_registration(RegistrationState::CapNegotiating);
+            }
+        }
+        "END" => {
+            if user.get_registration() == RegistrationState::CapNegotiating {
+                user.set_registration(RegistrationState::Connecting);
+            }
+        }
+        _ => return Err(anyhow!(ErrorType::InvalidCapCmd(cap_msg.subcommand))),
+    }
+
+    match cap_msg.subcommand.as_str() {
+        "LS" => {
+            // capability values are new in CAP version 302
+            let version = cap_msg.arg.and_then(|version| version.parse::<u32>().ok());
+            let with_values = matches!(version, Some(302..));
+
+            let mut caps = CAPABILITIES
+                .iter()
+                .map(|(name, value)| match value {
+                    Some(value) if with_values => format!("{name}={value}"),
+                    _ => name.to_string(),
+                })
+                .collect::<Vec<_>>();
+
+            // `sts` means nothing without its value
+            if let (Some(sts), true) = (config.sts, with_values) {
+                caps.push(sts.capability(user.is_secure()));
+            }
+
+            user.send(Reply::Cap(CapReply {
+                target_nick,
+                subcommand: cap_msg.subcommand,
+                caps: caps.join(" "),
+            }))
+        }
+        "LIST" => {
+            let caps = user.get_caps().join(" ");
+            user.send(Reply::Cap(CapReply {
+                target_nick,
+                subcommand: cap_msg.subcommand,
+                caps,
+            }))
+        }
+        "REQ" => {
+            let requested = cap_msg.arg.unwrap_or_default();
+
+            // a request is taken or refused as a whole
+            let supported = requested.split_whitespace().all(|cap| {
+                CAPABILITIES
+                    .iter()
+                    .any(|(name, _)| *name == cap.trim_start_matches('-'))
+            });
+            if supported {
+                for cap in requested.split_whitespace() {
+                    match cap.strip_prefix('-') {
+                        Some(cap) => user.disable_cap(cap),
+                        None => user.enable_cap(cap),
+                    }
+                }
+            }
+
+            user.send(Reply::Cap(CapReply {
+                target_nick,
+                subcommand: if supported { "ACK" } else { "NAK" }.to_owned(),
+                caps: requested,
+            }))
+        }
+        _ => {
+            // END
+            drop(user);
+            complete_registration(user_list, channel_list, config, ban_list, sender)
+        }
+    }
+}
+
+/// A gateway telling the server who its user really is, before they register.
+///
+/// The user is then shown by a cloak of their own IP address, rather than of
+/// the gateway's; the hostname the gateway sends isn't used. If anything is
+/// wrong, the connection is closed, since the user can't be told apart from
+/// the gateway.
+fn webirc_msg_sender(
+    user_list: &mut UserList,
+    channel_list: &mut ChannelList,
+    config: &ServerConfig,
+    webirc_msg: WebircMsg,
+    sender: UserId,
+) -> Result<()> {
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let mut user = user.lock().expect("Failed to lock user");
+    let sender_nick = user.get_nick();
+
+    if user.is_registered() || user.get_gateway().is_some() {
+        return Err(anyhow!(ErrorType::AlreadyRegistered));
+    }
+
+    let gateway = config.find_gateway(&webirc_msg.gateway).filter(|gateway| {
+        gateway.matches_ip(user.get_ip()) && gateway.check_password(&webirc_msg.password)
+    });
+    let ip = webirc_msg.ip.parse::<IpAddr>().ok();
+
+    let result = match (gateway, ip) {
+        (Some(gateway), Some(ip)) => user
+            .set_gateway(gateway.name.clone(), ip, config.cloak.cloak(ip))
+            .map_err(|err| err.to_string()),
+        _ => Err("WEBIRC failed".to_owned()),
+    };
+
+    match result {
+        Ok(()) => {
+            info!(
+                "{} is {} through gateway {}",
+                sender_nick, webirc_msg.ip, webirc_msg.gateway
+            );
+            Ok(())
+        }
+        Err(reason) => {
+            warn!(
+                "Refused WEBIRC from {} for gateway {}: {}",
+                user.get_ip(),
+                webirc_msg.gateway,
+                reason
+            );
+
+            // the connection is closed right after, so a failed write doesn't matter
+            let _ = user.send(Reply::ErrorMsg(reason.clone()));
+            drop(user);
+
+            disconnect_user(
+                user_list,
+                channel_list,
+                sender,
+                QuitMsg {
+                    message: Some(reason),
+                },
+            )
+        }
+    }
+}
+
+/// SASL authentication, before the user registers.
+///
+/// Only `EXTERNAL` is supported: the user logs in to the account bound to
+/// their TLS client certificate.
+fn authenticate_msg_sender(
+    user_list: &mut UserList,
+    config: &ServerConfig,
+    authenticate_msg: AuthenticateMsg,
+    sender: UserId,
+) -> Result<()> {
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let mut user = user.lock().expect("Failed to lock user");
+    let sender_nick = user.get_nick();
+
+    if user.get_account().is_some() {
+        return Err(anyhow!(ErrorType::SaslAlready));
+    }
+    if user.is_registered() {
+        return Err(anyhow!(ErrorType::AlreadyRegistered));
+    }
+    // only clients that asked for SASL know what to do with its replies
+    if !user.has_cap("sasl") {
+        return Err(anyhow!(ErrorType::SaslFail));
+    }
+
+    let data = authenticate_msg.data;
+    if data == "*" {
+        user.set_authenticating(false);
+        return Err(anyhow!(ErrorType::SaslAborted));
+    }
+
+    // the first step picks the mechanism
+    if !user.is_authenticating() {
+        if !SASL_MECHANISMS
+            .iter()
+            .any(|mechanism| mechanism.eq_ignore_ascii_case(&data))
+        {
+            let target_nick = user.get_reply_nick();
+            user.send(Reply::SaslMechs(SaslMechsReply {
+                target_nick,
+                mechanisms: SASL_MECHANISMS.join(","),
+            }))?;
+            return Err(anyhow!(ErrorType::SaslFail));
+        }
+
+        user.set_authenticating(true);
+        return user.send(Reply::Authenticate("+".to_owned()));
+    }
+
+    user.set_authenticating(false);
+
+    // The client may name the account it wants, or leave it to the certificate
+    let authzid = match data.as_str() {
+        "+" => None,
+        data => Some(
+            BASE64_STANDARD
+                .decode(data)
+                .ok()
+                .and_then(|authzid| String::from_utf8(authzid).ok())
+                .ok_or_else(|| anyhow!(ErrorType::SaslFail))?,
+        ),
+    };
+
+    let account = user
+        .get_certfp()
+        .and_then(|certfp| config.find_account_by_certfp(&certfp))
+        .filter(|account| {
+            authzid
+                .as_ref()
+                .is_none_or(|authzid| *authzid == account.name)
+        })
+        .ok_or_else(|| anyhow!(ErrorType::SaslFail))?;
+
+    info!("{} logged in as {}", sender_nick, account.name);
+    user.set_account(account.name.clone());
+
+    let target_nick = user.get_reply_nick();
+    let prefix = user.get_prefix();
+    user.send(Reply::LoggedIn(LoggedInReply {
+        target_nick: target_nick.clone(),
+        prefix,
+        account: account.name.clone(),
+    }))?;
+    user.send(Reply::SaslSuccess(SaslSuccessReply { target_nick }))
+}
+
+/// Register a user once they have sent both `NICK` and `USER`,
+/// unless they are still negotiating capabilities.
+///
+/// This checks the server password and bans, then welcomes the user.
+fn complete_registration(
+    user_list: &mut UserList,
+    channel_list: &mut ChannelList,
+    config: &ServerConfig,
+    ban_list: &BanList,
+    id: UserId,
+) -> Result<()> {
+    let user = user_list.get(id).ok_or(anyhow!("User not found"))?;
+    let mut user = user.lock().expect("Failed to lock user");
+    let nick = user.get_nick();
 
-    if user.is_set_nick() && user.is_set_real_name() {
-        user.send(Reply::Pong(ping_msg))?;
+    if user.get_registration() != RegistrationState::Connecting
+        || !user.is_set_nick()
+        || !user.is_set_real_name()
+    {
+        return Ok(());
     }
 
+    if let Some(expected) = &config.password {
+        if !user
+            .get_password()
+            .is_some_and(|password| password::check(expected, password))
+        {
+            info!("Disconnecting {} for a bad password", nick);
+
+            // the connection is closed right after, so a failed write doesn't matter
+            let _ = user.send_back_error(ErrorType::PasswdMismatch);
+            let _ = user.send(Reply::ErrorMsg("Bad password".to_owned()));
+            drop(user);
+
+            return disconnect_user(
+                user_list,
+                channel_list,
+                id,
+                QuitMsg {
+                    message: Some("Bad password".to_owned()),
+                },
+            );
+        }
+    }
+
+    // now that the username is known, bans on it apply,
+    // as do bans on the cloaked host
+    if let Some(ban) = ban_list.find(user.get_username(), user.get_remote_ip(), user.get_host()) {
+        drop(user);
+        return disconnect_banned_user(user_list, channel_list, id, &ban);
+    }
+
+    user.set_registration(RegistrationState::Registered);
+
+    let message = format!("Welcome to the server, {}!", user.get_real_name());
+    user.send(Reply::Welcome(WelcomeReply {
+        target_nick: nick.clone(),
+        message,
+    }))?;
+
+    user.send(Reply::ISupport(ISupportReply {
+        target_nick: nick.clone(),
+        tokens: vec![
+            format!("CASEMAPPING={}", casemapping()),
+            format!("NICKLEN={}", nick_len()),
+        ],
+    }))?;
+
+    match &config.motd {
+        Some(lines) => user.send(Reply::Motd(MotdReply {
+            target_nick: nick,
+            lines: lines.clone(),
+        }))?,
+        None => user.send_back_error(ErrorType::NoMotd)?,
+    }
+
+    Ok(())
+}
+
+fn ping_msg_sender(user_list: &mut UserList, ping_msg: String, sender: UserId) -> Result<()> {
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let mut user = user.lock().expect("Failed to lock user");
+
+    user.send(Reply::Pong(ping_msg))?;
+
     Ok(())
 }
 
@@ -129,45 +596,76 @@ fn quit_msg_sender(
     user_list: &mut UserList,
     channel_list: &mut ChannelList,
     quit_msg: types::QuitMsg,
-    sender_nick: Nick,
+    sender: UserId,
 ) -> Result<()> {
-    let users = user_list.get_users();
-    let mut users = users.lock().expect("Failed to lock users");
-    let user = users
-        .iter_mut()
-        .find(|user| user.get_nick() == sender_nick)
-        .ok_or(anyhow!("User not found"))?;
-
-    if !user.is_set_nick() || !user.is_set_real_name() {
+    disconnect_user(user_list, channel_list, sender, quit_msg)
+}
+
+/// Remove a user from the server.
+///
+/// Every way a user can leave (`QUIT`, a lost connection, a timeout, ...) ends up here.
+/// The user's connection is closed, everyone sharing a channel with them is sent
+/// a `QUIT`, and they are removed from every channel and from the user list.
+///
+/// Disconnecting a user that is already gone does nothing.
+pub fn disconnect_user(
+    user_list: &mut UserList,
+    channel_list: &mut ChannelList,
+    id: UserId,
+    quit_msg: types::QuitMsg,
+) -> Result<()> {
+    let Some(user) = user_list.get(id) else {
         return Ok(());
-    }
+    };
+    let mut user = user.lock().expect("Failed to lock user");
+
+    // close the connection, which also stops the user's reader task
+    user.close();
+
+    // remove user from user list
+    user_list.remove_user(&user);
 
+    let nick = user.get_nick();
     let channels = user.get_joined_channels().clone();
+    let prefix = user.get_prefix();
+    drop(user);
+    info!("{} disconnected: {:?}", nick, quit_msg.message);
 
-    // send quit message to all channels
-    for channel_str in channels {
-        let channel_users = channel_list
-            .get_users_mut(&channel_str)
-            .ok_or(anyhow!("channel_users not found"))?;
-
-        for other_user_nick in &mut *channel_users {
-            let other_user = users
-                .iter_mut()
-                .find(|user| user.get_nick() == Nick(other_user_nick.clone()))
-                .ok_or(anyhow!("User not found"))?;
-
-            other_user.send(Reply::Quit(QuitReply {
-                message: quit_msg.clone(),
-                sender_nick: sender_nick.clone(),
-            }))?;
+    // send quit message to everyone sharing a channel with them, once each
+    let mut ids_to_tell = HashSet::new();
+    for channel in &channels {
+        ids_to_tell.extend(
+            channel_list
+                .get_users(channel)
+                .into_iter()
+                .flatten()
+                .copied(),
+        );
+    }
+
+    for other_user_id in ids_to_tell {
+        // the quitting user's connection is already closed
+        if other_user_id == id {
+            continue;
         }
 
-        // remove user from channel
-        channel_users.retain(|user_nick| user_nick != &sender_nick.0);
+        let Some(other_user) = user_list.get(other_user_id) else {
+            continue;
+        };
+        let mut other_user = other_user.lock().expect("Failed to lock user");
+
+        // a failed write means that user is on their way out too,
+        // which must not stop everyone else from being told
+        if let Err(err) = other_user.send(Reply::Quit(QuitReply {
+            message: quit_msg.clone(),
+            sender: prefix.clone(),
+        })) {
+            warn!("Failed to send QUIT to {}: {}", other_user.get_nick(), err);
+        }
     }
 
-    // remove user from user list
-    users.retain(|user| user.get_nick() != sender_nick);
+    // remove user from their channels, dropping the ones left empty
+    channel_list.remove_user(id, &channels);
 
     Ok(())
 }
@@ -175,19 +673,16 @@ fn quit_msg_sender(
 fn priv_msg_sender(
     user_list: &mut UserList,
     channel_list: &mut ChannelList,
+    config: &ServerConfig,
     priv_msg: PrivMsg,
-    sender_nick: Nick,
+    sender: UserId,
 ) -> Result<()> {
-    let users = user_list.get_users();
-    let mut users = users.lock().expect("Failed to lock users");
-    let user = users
-        .iter_mut()
-        .find(|user| user.get_nick() == sender_nick)
-        .ok_or(anyhow!("User not found"))?;
-
-    if !user.is_set_nick() || !user.is_set_real_name() {
-        return Ok(());
-    }
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let user = user.lock().expect("Failed to lock user");
+    let prefix = user.get_prefix();
+
+    // the sender may be among those the message goes to
+    drop(user);
 
     match priv_msg.target {
         Target::User(user_nick) => {
@@ -196,57 +691,68 @@ fn priv_msg_sender(
                 return plugin::plugin_handler(
                     user_list,
                     channel_list,
+                    config,
                     user_nick,
-                    sender_nick,
+                    sender,
                     priv_msg.message.as_str(),
                 );
             }
 
             // Handle normal message
 
-            let other_user_option = users.iter_mut().find(|user| user.get_nick() == user_nick);
+            let other_user = user_list
+                .find(&user_nick)
+                .ok_or_else(|| anyhow!(ErrorType::NoSuchNick(user_nick.0.clone())))?;
+            let mut other_user = other_user.lock().expect("Failed to lock user");
 
-            if let Some(other_user) = other_user_option {
+            let target = Target::User(user_nick);
+            for line in fit_message(config, &prefix, &target, &priv_msg.message)? {
                 other_user.send(Reply::PrivMsg(PrivReply {
                     message: PrivMsg {
-                        target: Target::User(user_nick),
-                        message: priv_msg.message.clone(),
+                        target: target.clone(),
+                        message: line.to_owned(),
                     },
-                    sender_nick: sender_nick.clone(),
+                    sender: prefix.clone(),
                 }))?;
-            } else {
-                return Err(anyhow!(ErrorType::NoSuchNick));
             }
         }
 
         Target::Channel(channel) => {
             // error if channel does not exist
-            if !channel_list.has_channel(&channel.0) {
-                return Err(anyhow!(ErrorType::NoSuchChannel));
-            }
+            let channel = Channel(
+                channel_list
+                    .get_name(&channel.0)
+                    .ok_or_else(|| anyhow!(ErrorType::NoSuchChannel(channel.0.clone())))?
+                    .to_owned(),
+            );
 
-            // ignore if user is not in channel
-            if !channel_list.has_user(&channel.0, &sender_nick.0) {
-                return Ok(());
+            // only members may talk in a channel
+            if !channel_list.has_user(&channel.0, sender) {
+                return Err(anyhow!(ErrorType::CannotSendToChan(channel.0)));
             }
 
+            let target = Target::Channel(channel.clone());
+            let lines = fit_message(config, &prefix, &target, &priv_msg.message)?;
+
             let channel_users = channel_list
-                .get_users_mut(&channel.0)
+                .get_users(&channel.0)
                 .ok_or(anyhow!("channel_users not found"))?;
 
-            for other_user_nick in &mut *channel_users {
-                let other_user = users
-                    .iter_mut()
-                    .find(|user| user.get_nick() == Nick(other_user_nick.clone()))
+            for other_user_id in channel_users {
+                let other_user = user_list
+                    .get(*other_user_id)
                     .ok_or(anyhow!("User not found"))?;
+                let mut other_user = other_user.lock().expect("Failed to lock user");
 
-                other_user.send(Reply::PrivMsg(PrivReply {
-                    message: PrivMsg {
-                        target: Target::Channel(channel.clone()),
-                        message: priv_msg.message.clone(),
-                    },
-                    sender_nick: sender_nick.clone(),
-                }))?;
+                for line in &lines {
+                    other_user.send(Reply::PrivMsg(PrivReply {
+                        message: PrivMsg {
+                            target: target.clone(),
+                            message: (*line).to_owned(),
+                        },
+                        sender: prefix.clone(),
+                    }))?;
+                }
             }
         }
     }
@@ -254,52 +760,91 @@ fn priv_msg_sender(
     Ok(())
 }
 
+/// The text of a message from `prefix` to `target`, as lines that each fit
+/// on the wire once relayed.
+///
+/// Depending on the server's configuration, text that doesn't fit is split up,
+/// or refused with `ERR_INPUTTOOLONG`.
+fn fit_message<'a>(
+    config: &ServerConfig,
+    prefix: &Prefix,
+    target: &Target,
+    text: &'a str,
+) -> Result<Vec<&'a str>> {
+    // everything on the line apart from the text
+    let overhead = Reply::PrivMsg(PrivReply {
+        message: PrivMsg {
+            target: target.clone(),
+            message: String::new(),
+        },
+        sender: prefix.clone(),
+    })
+    .to_string()
+    .len();
+    let max_len = MAX_LINE_LEN.saturating_sub(overhead);
+
+    if text.len() <= max_len {
+        return Ok(vec![text]);
+    }
+
+    match config.long_messages {
+        LongMessages::Split => {
+            split_text(text, max_len).ok_or_else(|| anyhow!(ErrorType::InputTooLong))
+        }
+        LongMessages::Reject => Err(anyhow!(ErrorType::InputTooLong)),
+    }
+}
+
 fn join_msg_sender(
     user_list: &mut UserList,
     channel_list: &mut ChannelList,
     join_msg: JoinMsg,
-    sender_nick: Nick,
+    sender: UserId,
 ) -> Result<()> {
-    let users = user_list.get_users();
-    let mut users = users.lock().expect("Failed to lock users");
-    let user = users
-        .iter_mut()
-        .find(|user| user.get_nick() == sender_nick)
-        .ok_or(anyhow!("User not found"))?;
-
-    if !user.is_set_nick() || !user.is_set_real_name() {
-        return Ok(());
-    }
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let mut user = user.lock().expect("Failed to lock user");
 
-    let channel = join_msg.channel.0;
+    let prefix = user.get_prefix();
 
     // create channel if it does not exist
-    if !channel_list.has_channel(&channel) {
-        channel_list.add_channel(channel.clone());
+    if !channel_list.has_channel(&join_msg.channel.0) {
+        channel_list.add_channel(join_msg.channel.0.clone());
     }
 
+    // ignore if user is already in channel
+    if channel_list.has_user(&join_msg.channel.0, sender) {
+        return Ok(());
+    }
+
+    // use the channel name in the case it was created with
+    let channel = channel_list
+        .get_name(&join_msg.channel.0)
+        .ok_or(anyhow!("channel not found"))?
+        .to_owned();
+
     // add user to channel
-    channel_list.join_channel(&channel, &sender_nick.0);
+    channel_list.join_channel(&channel, sender);
 
     // add channel to user
     user.join_channel(&channel);
+    drop(user);
 
     // send join message to all users in channel
     let channel_users = channel_list
-        .get_users_mut(&channel)
+        .get_users(&channel)
         .ok_or(anyhow!("channel_users not found"))?;
 
-    for other_user_nick in &mut *channel_users {
-        let other_user = users
-            .iter_mut()
-            .find(|user| user.get_nick() == Nick(other_user_nick.clone()))
+    for other_user_id in channel_users {
+        let other_user = user_list
+            .get(*other_user_id)
             .ok_or(anyhow!("User not found"))?;
+        let mut other_user = other_user.lock().expect("Failed to lock user");
 
         other_user.send(Reply::Join(JoinReply {
             message: JoinMsg {
                 channel: Channel(channel.clone()),
             },
-            sender_nick: sender_nick.clone(),
+            sender: prefix.clone(),
         }))?;
     }
 
@@ -310,55 +855,1023 @@ fn part_msg_sender(
     user_list: &mut UserList,
     channel_list: &mut ChannelList,
     part_msg: PartMsg,
-    sender_nick: Nick,
+    sender: UserId,
 ) -> Result<()> {
-    let users = user_list.get_users();
-    let mut users = users.lock().expect("Failed to lock users");
-    let user = users
-        .iter_mut()
-        .find(|user| user.get_nick() == sender_nick)
-        .ok_or(anyhow!("User not found"))?;
-
-    if !user.is_set_nick() || !user.is_set_real_name() {
-        return Ok(());
-    }
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let mut user = user.lock().expect("Failed to lock user");
 
-    let channel = part_msg.channel.0;
+    let prefix = user.get_prefix();
 
     // error if channel does not exist
-    if !channel_list.has_channel(&channel) {
-        return Err(anyhow!(ErrorType::NoSuchChannel));
-    }
+    let channel = channel_list
+        .get_name(&part_msg.channel.0)
+        .ok_or_else(|| anyhow!(ErrorType::NoSuchChannel(part_msg.channel.0.clone())))?
+        .to_owned();
 
-    // error if user is not in channel, do nothing
-    if !channel_list.has_user(&channel, &sender_nick.0) {
-        return Ok(());
+    // error if user is not in channel
+    if !channel_list.has_user(&channel, sender) {
+        return Err(anyhow!(ErrorType::NotOnChannel(channel)));
     }
 
     // remove user from channel
-    channel_list.part_channel(&channel, &sender_nick.0);
+    channel_list.part_channel(&channel, sender);
 
     // remove channel from user
     user.part_channel(&channel);
+    drop(user);
 
-    // send part message to all users in channel
-    let channel_users = channel_list
-        .get_users_mut(&channel)
-        .ok_or(anyhow!("channel_users not found"))?;
+    // send part message to all users in channel,
+    // unless the channel was dropped because nobody is left
+    let Some(channel_users) = channel_list.get_users(&channel) else {
+        return Ok(());
+    };
 
-    for other_user_nick in &mut *channel_users {
-        let other_user = users
-            .iter_mut()
-            .find(|user| user.get_nick() == Nick(other_user_nick.clone()))
+    for other_user_id in channel_users {
+        let other_user = user_list
+            .get(*other_user_id)
             .ok_or(anyhow!("User not found"))?;
+        let mut other_user = other_user.lock().expect("Failed to lock user");
 
         other_user.send(Reply::Part(PartReply {
             message: PartMsg {
                 channel: Channel(channel.clone()),
             },
-            sender_nick: sender_nick.clone(),
+            sender: prefix.clone(),
         }))?;
     }
 
     Ok(())
 }
+
+fn mode_msg_sender(
+    user_list: &mut UserList,
+    channel_list: &mut ChannelList,
+    mode_msg: ModeMsg,
+    sender: UserId,
+) -> Result<()> {
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let mut user = user.lock().expect("Failed to lock user");
+    let sender_nick = user.get_nick();
+
+    let target_nick = match mode_msg.target {
+        Target::Channel(channel) => {
+            let channel = channel_list
+                .get_name(&channel.0)
+                .ok_or_else(|| anyhow!(ErrorType::NoSuchChannel(channel.0.clone())))?;
+
+            // channels have no modes, so there is nothing to show, and nothing can be changed
+            let unknown_mode = mode_msg
+                .modes
+                .unwrap_or_default()
+                .chars()
+                .find(|flag| !matches!(flag, '+' | '-'));
+            return match unknown_mode {
+                Some(mode) => Err(anyhow!(ErrorType::UnknownMode(mode, channel.to_owned()))),
+                None => Ok(()),
+            };
+        }
+        Target::User(target_nick) => target_nick,
+    };
+
+    // users can only see and change their own modes
+    if target_nick != sender_nick {
+        return Err(anyhow!(ErrorType::UsersDontMatch));
+    }
+
+    let Some(modes) = mode_msg.modes else {
+        let modes = user.get_modes();
+        user.send(Reply::UModeIs(UModeIsReply {
+            target_nick: sender_nick,
+            modes,
+        }))?;
+        return Ok(());
+    };
+
+    let before = user.get_modes();
+    let mut adding = true;
+    let mut unknown_flag = false;
+
+    for flag in modes.chars() {
+        match flag {
+            '+' => adding = true,
+            '-' => adding = false,
+            'w' => user.set_wallops(adding),
+            // operator status can be given up, but only gained with OPER
+            'o' if !adding => user.set_oper(None),
+            'o' => {}
+            _ => unknown_flag = true,
+        }
+    }
+
+    let after = user.get_modes();
+    let added = after.chars().skip(1).filter(|flag| !before.contains(*flag));
+    let removed = before.chars().skip(1).filter(|flag| !after.contains(*flag));
+
+    let mut changes = String::new();
+    if added.clone().count() > 0 {
+        changes.push('+');
+        changes.extend(added);
+    }
+    if removed.clone().count() > 0 {
+        changes.push('-');
+        changes.extend(removed);
+    }
+
+    if !changes.is_empty() {
+        let prefix = user.get_prefix();
+        user.send(Reply::Mode(ModeReply {
+            sender: prefix,
+            target_nick: sender_nick,
+            modes: changes,
+        }))?;
+    }
+
+    if unknown_flag {
+        return Err(anyhow!(ErrorType::UModeUnknownFlag));
+    }
+
+    Ok(())
+}
+
+fn oper_msg_sender(
+    user_list: &mut UserList,
+    config: &ServerConfig,
+    oper_msg: OperMsg,
+    sender: UserId,
+) -> Result<()> {
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let mut user = user.lock().expect("Failed to lock user");
+    let sender_nick = user.get_nick();
+
+    let host = user.get_ip().to_string();
+
+    let Some(oper) = config
+        .find_operator(&oper_msg.name)
+        .filter(|oper| oper.matches_host(&host))
+    else {
+        warn!(
+            "Failed OPER attempt by {} ({}) as {}: no matching operator",
+            sender_nick, host, oper_msg.name
+        );
+        return Err(anyhow!(ErrorType::NoOperHost));
+    };
+
+    if !oper.check_password(&oper_msg.password) {
+        warn!(
+            "Failed OPER attempt by {} ({}) as {}: wrong password",
+            sender_nick, host, oper_msg.name
+        );
+        return Err(anyhow!(ErrorType::PasswdMismatch));
+    }
+
+    info!(
+        "{} ({}) is now an operator as {}",
+        sender_nick, host, oper.name
+    );
+    user.set_oper(Some(oper.name.clone()));
+
+    let prefix = user.get_prefix();
+    user.send(Reply::YoureOper(YoureOperReply {
+        target_nick: sender_nick.clone(),
+    }))?;
+    user.send(Reply::Mode(ModeReply {
+        sender: prefix,
+        target_nick: sender_nick,
+        modes: "+o".to_owned(),
+    }))?;
+
+    Ok(())
+}
+
+fn kill_msg_sender(
+    user_list: &mut UserList,
+    channel_list: &mut ChannelList,
+    kill_msg: KillMsg,
+    sender: UserId,
+) -> Result<()> {
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let (sender_nick, is_oper) = {
+        let user = user.lock().expect("Failed to lock user");
+        (user.get_nick(), user.is_oper())
+    };
+
+    if !is_oper {
+        warn!(
+            "{} tried to KILL {} without being an operator",
+            sender_nick, kill_msg.nick
+        );
+        return Err(anyhow!(ErrorType::NoPrivileges));
+    }
+
+    // operators may kill themselves, so the sender isn't locked anymore
+    let target = user_list
+        .find(&kill_msg.nick)
+        .ok_or_else(|| anyhow!(ErrorType::NoSuchNick(kill_msg.nick.0.clone())))?;
+    let mut target = target.lock().expect("Failed to lock user");
+
+    let reason = format!("Killed ({} ({}))", sender_nick, kill_msg.reason);
+    info!(
+        "{} killed {}: {}",
+        sender_nick, kill_msg.nick, kill_msg.reason
+    );
+
+    // the connection is closed right after, so a failed write doesn't matter
+    let _ = target.send(Reply::ErrorMsg(reason.clone()));
+    let target_id = target.get_id();
+
+    // disconnect_user needs the lock
+    drop(target);
+
+    disconnect_user(
+        user_list,
+        channel_list,
+        target_id,
+        QuitMsg {
+            message: Some(reason),
+        },
+    )
+}
+
+fn wallops_msg_sender(user_list: &mut UserList, message: String, sender: UserId) -> Result<()> {
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let user = user.lock().expect("Failed to lock user");
+    let sender_nick = user.get_nick();
+
+    let prefix = user.get_prefix();
+
+    if !user.is_oper() {
+        warn!(
+            "{} tried to send WALLOPS without being an operator",
+            sender_nick
+        );
+        return Err(anyhow!(ErrorType::NoPrivileges));
+    }
+
+    // the sender may see their own WALLOPS
+    drop(user);
+
+    info!("WALLOPS from {}: {}", sender_nick, message);
+
+    for other_user in user_list.all_users() {
+        let mut other_user = other_user.lock().expect("Failed to lock user");
+        if !other_user.is_wallops() {
+            continue;
+        }
+
+        if let Err(err) = other_user.send(Reply::Wallops(WallopsReply {
+            sender: prefix.clone(),
+            message: message.clone(),
+        })) {
+            warn!(
+                "Failed to send WALLOPS to {}: {}",
+                other_user.get_nick(),
+                err
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle `DIE`, or `RESTART` when `restart` is set.
+///
+/// Every user is told the server is going away and disconnected, then the
+/// process exits, or replaces itself with a fresh copy of the same binary.
+fn die_msg_sender(
+    user_list: &mut UserList,
+    channel_list: &mut ChannelList,
+    config: &ServerConfig,
+    restart: bool,
+    sender: UserId,
+) -> Result<()> {
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let user = user.lock().expect("Failed to lock user");
+    let sender_nick = user.get_nick();
+
+    let command = if restart { "RESTART" } else { "DIE" };
+
+    if !user.is_oper() {
+        warn!(
+            "{} tried to {} without being an operator",
+            sender_nick, command
+        );
+        return Err(anyhow!(ErrorType::NoPrivileges));
+    }
+
+    warn!("{} by {}", command, sender_nick);
+    drop(user);
+
+    let reason = if restart {
+        "Server restarting"
+    } else {
+        "Server terminating"
+    };
+
+    shutdown_server(user_list, channel_list, config, reason);
+
+    if restart {
+        use std::os::unix::process::CommandExt;
+
+        // exec only returns if it failed
+        let err = std::process::Command::new(std::env::current_exe()?)
+            .args(std::env::args_os().skip(1))
+            .exec();
+        error!("Failed to restart: {}", err);
+    }
+
+    std::process::exit(if restart { 1 } else { 0 });
+}
+
+/// Disconnect everyone before the server goes away.
+///
+/// Plugins save their state first, while the users are still there.
+/// Then every user is sent an `ERROR` with `reason`, and their channels
+/// a `QUIT`, as they are disconnected. This returns once everyone has been
+/// sent what they had left, or `config.shutdown_grace` has passed.
+///
+/// This waits on the connections, so it isn't for the async runtime's threads.
+pub fn shutdown_server(
+    user_list: &mut UserList,
+    channel_list: &mut ChannelList,
+    config: &ServerConfig,
+    reason: &str,
+) {
+    if let Some(path) = &config.plugin_state_file {
+        if let Err(err) = plugin::save_state(path, user_list) {
+            error!("Failed to save the plugin state: {:#}", err);
+        }
+    }
+
+    let mut closed = Vec::new();
+    for user in user_list.all_users() {
+        let mut user = user.lock().expect("Failed to lock user");
+        // the server is going away, so a failed write doesn't matter
+        let _ = user.send(Reply::ErrorMsg(reason.to_owned()));
+        closed.push(user.closed());
+        let id = user.get_id();
+        drop(user);
+
+        let quit_msg = QuitMsg {
+            message: Some(reason.to_owned()),
+        };
+        if let Err(err) = disconnect_user(user_list, channel_list, id, quit_msg) {
+            warn!("Failed to disconnect {}: {}", id, err);
+        }
+    }
+
+    // give everyone a moment to receive what they have left before the process
+    // goes away, without holding on to the users, which their readers need to finish
+    let deadline = Instant::now() + config.shutdown_grace;
+    for closed in closed {
+        closed.wait(deadline);
+    }
+}
+
+/// Tell a user they are banned, then disconnect them.
+fn disconnect_banned_user(
+    user_list: &mut UserList,
+    channel_list: &mut ChannelList,
+    id: UserId,
+    ban: &Ban,
+) -> Result<()> {
+    let reason = format!("K-lined ({})", ban.reason);
+
+    if let Some(user) = user_list.get(id) {
+        let mut user = user.lock().expect("Failed to lock user");
+        info!(
+            "Disconnecting {} matching K-line {}",
+            user.get_nick(),
+            ban.mask
+        );
+
+        // the connection is closed right after, so a failed write doesn't matter
+        let _ = user.send_back_error(ErrorType::YoureBannedCreep);
+        let _ = user.send(Reply::ErrorMsg(reason.clone()));
+    }
+
+    disconnect_user(
+        user_list,
+        channel_list,
+        id,
+        QuitMsg {
+            message: Some(reason),
+        },
+    )
+}
+
+fn kline_msg_sender(
+    user_list: &mut UserList,
+    channel_list: &mut ChannelList,
+    ban_list: &BanList,
+    kline_msg: KlineMsg,
+    sender: UserId,
+) -> Result<()> {
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let mut user = user.lock().expect("Failed to lock user");
+    let sender_nick = user.get_nick();
+
+    if !user.is_oper() {
+        warn!(
+            "{} tried to KLINE {} without being an operator",
+            sender_nick, kline_msg.mask
+        );
+        return Err(anyhow!(ErrorType::NoPrivileges));
+    }
+
+    let mask = match kline_msg.mask.parse::<BanMask>() {
+        Ok(mask) => mask,
+        Err(err) => {
+            user.send(Reply::Notice(NoticeReply {
+                target_nick: sender_nick,
+                message: err,
+            }))?;
+            return Ok(());
+        }
+    };
+
+    let set_at = unix_time();
+    let expires_at = match kline_msg.duration {
+        Some(minutes) => Some(
+            minutes
+                .checked_mul(60)
+                .and_then(|seconds| set_at.checked_add(seconds))
+                .ok_or_else(|| {
+                    anyhow!(ErrorType::UnknownError(
+                        "KLINE".to_owned(),
+                        format!("Invalid duration: {minutes} minutes"),
+                    ))
+                })?,
+        ),
+        None => None,
+    };
+    let ban = Ban {
+        mask,
+        reason: kline_msg.reason,
+        set_by: user.get_oper_name().unwrap_or_default().to_owned(),
+        set_at,
+        expires_at,
+    };
+    ban_list.add(ban.clone())?;
+
+    let duration = match kline_msg.duration {
+        Some(minutes) => format!("temporary K-line for {minutes} minutes"),
+        None => "K-line".to_owned(),
+    };
+    info!(
+        "{} added {} on {}: {}",
+        sender_nick, duration, ban.mask, ban.reason
+    );
+    user.send(Reply::Notice(NoticeReply {
+        target_nick: sender_nick.clone(),
+        message: format!("Added {} on {} ({})", duration, ban.mask, ban.reason),
+    }))?;
+
+    // disconnect everyone already connected that matches the new ban,
+    // which may well include the operator
+    drop(user);
+    let banned_ids = user_list
+        .all_users()
+        .iter()
+        .map(|user| user.lock().expect("Failed to lock user"))
+        .filter(|user| {
+            ban.mask
+                .matches(user.get_username(), user.get_remote_ip(), user.get_host())
+        })
+        .map(|user| user.get_id())
+        .collect::<Vec<_>>();
+
+    for id in banned_ids {
+        disconnect_banned_user(user_list, channel_list, id, &ban)?;
+    }
+
+    Ok(())
+}
+
+fn unkline_msg_sender(
+    user_list: &mut UserList,
+    ban_list: &BanList,
+    unkline_msg: UnklineMsg,
+    sender: UserId,
+) -> Result<()> {
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let mut user = user.lock().expect("Failed to lock user");
+    let sender_nick = user.get_nick();
+
+    if !user.is_oper() {
+        warn!(
+            "{} tried to UNKLINE {} without being an operator",
+            sender_nick, unkline_msg.mask
+        );
+        return Err(anyhow!(ErrorType::NoPrivileges));
+    }
+
+    let message = match unkline_msg.mask.parse::<BanMask>() {
+        Ok(mask) if ban_list.remove(&mask)? => {
+            info!("{} removed K-line on {}", sender_nick, mask);
+            format!("Removed K-line on {}", mask)
+        }
+        Ok(mask) => format!("No K-line on {}", mask),
+        Err(err) => err,
+    };
+
+    user.send(Reply::Notice(NoticeReply {
+        target_nick: sender_nick,
+        message,
+    }))?;
+
+    Ok(())
+}
+
+fn stats_msg_sender(
+    user_list: &mut UserList,
+    ban_list: &BanList,
+    stats_msg: StatsMsg,
+    sender: UserId,
+) -> Result<()> {
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let mut user = user.lock().expect("Failed to lock user");
+    let sender_nick = user.get_nick();
+
+    if stats_msg.query.eq_ignore_ascii_case("k") {
+        if !user.is_oper() {
+            return Err(anyhow!(ErrorType::NoPrivileges));
+        }
+
+        info!("STATS k by {}", sender_nick);
+
+        for ban in ban_list.list() {
+            let mask = ban.mask.to_string();
+            let (ban_user, host) = mask.split_once('@').unwrap_or(("*", &mask));
+
+            let reason = match ban.expires_at {
+                Some(expires_at) => format!(
+                    "Temporary K-line {} min. - {}",
+                    expires_at.saturating_sub(unix_time()).div_ceil(60),
+                    ban.reason
+                ),
+                None => ban.reason.clone(),
+            };
+
+            user.send(Reply::StatsKline(StatsKlineReply {
+                target_nick: sender_nick.clone(),
+                user: ban_user.to_owned(),
+                host: host.to_owned(),
+                reason,
+            }))?;
+        }
+    }
+
+    user.send(Reply::EndOfStats(EndOfStatsReply {
+        target_nick: sender_nick,
+        query: stats_msg.query,
+    }))?;
+
+    Ok(())
+}
+
+/// Tell a user about another user.
+///
+/// Everyone sees the other user's cloaked host; only operators are also
+/// shown their real address.
+fn whois_msg_sender(
+    user_list: &mut UserList,
+    channel_list: &mut ChannelList,
+    whois_msg: WhoisMsg,
+    sender: UserId,
+) -> Result<()> {
+    let user = user_list.get(sender).ok_or(anyhow!("User not found"))?;
+    let (sender_nick, sender_is_oper) = {
+        let user = user.lock().expect("Failed to lock user");
+        (user.get_nick(), user.is_oper())
+    };
+
+    // users that haven't registered yet can't be looked up,
+    // and users may look themselves up, so the sender isn't locked anymore
+    let target = user_list
+        .find(&whois_msg.nick)
+        .ok_or_else(|| anyhow!(ErrorType::NoSuchNick(whois_msg.nick.0.clone())))?;
+    let target = target.lock().expect("Failed to lock user");
+    if !target.is_registered() {
+        return Err(anyhow!(ErrorType::NoSuchNick(whois_msg.nick.0)));
+    }
+    let nick = target.get_nick();
+
+    let mut replies = vec![Reply::WhoisUser(WhoisUserReply {
+        target_nick: sender_nick.clone(),
+        nick: nick.clone(),
+        username: target.get_username().unwrap_or("*").to_owned(),
+        host: target.get_host().to_owned(),
+        real_name: target.get_real_name().0,
+    })];
+
+    let channels = target
+        .get_joined_channels()
+        .iter()
+        .map(|channel| channel_list.get_name(channel).unwrap_or(channel).to_owned())
+        .collect::<Vec<_>>();
+    if !channels.is_empty() {
+        replies.push(Reply::WhoisChannels(WhoisChannelsReply {
+            target_nick: sender_nick.clone(),
+            nick: nick.clone(),
+            channels,
+        }));
+    }
+
+    replies.push(Reply::WhoisServer(WhoisServerReply {
+        target_nick: sender_nick.clone(),
+        nick: nick.clone(),
+    }));
+
+    if target.is_oper() {
+        replies.push(Reply::WhoisOperator(WhoisOperatorReply {
+            target_nick: sender_nick.clone(),
+            nick: nick.clone(),
+        }));
+    }
+
+    if let Some(account) = target.get_account() {
+        replies.push(Reply::WhoisAccount(WhoisAccountReply {
+            target_nick: sender_nick.clone(),
+            nick: nick.clone(),
+            account: account.to_owned(),
+        }));
+    }
+
+    if sender_is_oper {
+        replies.push(Reply::WhoisActually(WhoisActuallyReply {
+            target_nick: sender_nick.clone(),
+            nick: nick.clone(),
+            ip: target.get_ip(),
+        }));
+    }
+
+    // like the IP, the fingerprint is only for the user themselves and operators
+    if let Some(certfp) = target.get_certfp() {
+        if sender_is_oper || target.get_id() == sender {
+            replies.push(Reply::WhoisCertfp(WhoisCertfpReply {
+                target_nick: sender_nick.clone(),
+                nick: nick.clone(),
+                certfp,
+            }));
+        }
+    }
+
+    replies.push(Reply::EndOfWhois(EndOfWhoisReply {
+        target_nick: sender_nick.clone(),
+        nick,
+    }));
+
+    drop(target);
+
+    let mut user = user.lock().expect("Failed to lock user");
+    for reply in replies {
+        user.send(reply)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connect::ConnectionWrite,
+        types::{ParsedMessage, UnparsedMessage},
+        user::User,
+    };
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    /// Everything the message handlers need, with users that aren't
+    /// connected to anything, so what they are sent can be checked.
+    struct Server {
+        user_list: UserList,
+        channel_list: ChannelList,
+        config: ServerConfig,
+        ban_list: BanList,
+        next_id: u64,
+    }
+
+    impl Server {
+        fn new(config: ServerConfig) -> Self {
+            Self {
+                user_list: UserList::new(),
+                channel_list: ChannelList::new(),
+                config,
+                ban_list: BanList::new(),
+                next_id: 0,
+            }
+        }
+
+        /// A new client from `ip`, that hasn't registered yet.
+        fn connect(&mut self, ip: &str) -> UserId {
+            self.connect_with(ip, |_| ())
+        }
+
+        /// A new client from `ip`, whose connection is set up with `setup` first.
+        fn connect_with(&mut self, ip: &str, setup: impl FnOnce(&mut ConnectionWrite)) -> UserId {
+            self.next_id += 1;
+            let id = UserId(self.next_id);
+            let addr = SocketAddr::new(ip.parse().unwrap(), 50000);
+            let mut conn_write = ConnectionWrite::for_test(addr, id);
+            setup(&mut conn_write);
+            let user = User::new(id, conn_write, ip.to_owned());
+            self.user_list.add_user(user);
+            id
+        }
+
+        /// A new client from `ip` that registered as `nick`.
+        fn register(&mut self, nick: &str, ip: &str) -> UserId {
+            let id = self.connect(ip);
+            self.handle(id, &format!("NICK {nick}")).unwrap();
+            self.handle(id, &format!("USER {nick} 0 * :{nick}"))
+                .unwrap();
+            assert!(self.user(id).lock().unwrap().is_registered());
+            self.sent(id);
+            id
+        }
+
+        /// Handle a line from `sender`, returning the error sent back to them, if any.
+        fn handle(&mut self, sender: UserId, line: &str) -> Result<()> {
+            let message = UnparsedMessage {
+                sender,
+                message: line,
+            };
+            let parsed_msg = ParsedMessage::try_from(message)?;
+            global_msg_sender(
+                &mut self.user_list,
+                &mut self.channel_list,
+                &self.config,
+                &self.ban_list,
+                parsed_msg,
+            )
+        }
+
+        fn user(&self, id: UserId) -> Arc<Mutex<User>> {
+            self.user_list.get(id).unwrap()
+        }
+
+        /// Take everything sent to `id` so far.
+        fn sent(&self, id: UserId) -> Vec<String> {
+            self.user(id).lock().unwrap().take_sent()
+        }
+    }
+
+    /// The numerics of the replies in `sent`, or their command if they have none.
+    fn numerics(sent: &[String]) -> Vec<&str> {
+        sent.iter()
+            .map(|line| match line.strip_prefix(':') {
+                Some(line) => line.split(' ').nth(1).unwrap_or_default(),
+                None => line.split(' ').next().unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// The error a handler failed with.
+    fn error_type(result: Result<()>) -> ErrorType {
+        result
+            .unwrap_err()
+            .downcast::<ErrorType>()
+            .expect("Not an IRC error")
+    }
+
+    #[test]
+    fn test_register() {
+        let mut server = Server::new(ServerConfig::default());
+
+        // NICK and USER may come in either order
+        for (first, second) in [
+            ("NICK alice", "USER alice 0 * :Alice"),
+            ("USER bob 0 * :Bob", "NICK bob"),
+        ] {
+            let id = server.connect("10.0.0.1");
+            server.handle(id, first).unwrap();
+            assert!(!server.user(id).lock().unwrap().is_registered());
+            assert!(server.sent(id).is_empty());
+
+            server.handle(id, second).unwrap();
+            assert!(server.user(id).lock().unwrap().is_registered());
+            assert_eq!(numerics(&server.sent(id)).first(), Some(&"001"));
+        }
+    }
+
+    #[test]
+    fn test_not_registered() {
+        let mut server = Server::new(ServerConfig::default());
+        let id = server.connect("10.0.0.1");
+
+        let result = server.handle(id, "JOIN #team");
+        assert_eq!(error_type(result), ErrorType::NotRegistered);
+        server.handle(id, "NICK alice").unwrap();
+        let result = server.handle(id, "PRIVMSG alice :hi");
+        assert_eq!(error_type(result), ErrorType::NotRegistered);
+        assert!(!server.channel_list.has_channel("#team"));
+    }
+
+    #[test]
+    fn test_reregister() {
+        let mut server = Server::new(ServerConfig::default());
+        let id = server.register("alice", "10.0.0.1");
+
+        for line in ["USER mallory 0 * :Mallory", "PASS secret"] {
+            let result = server.handle(id, line);
+            assert_eq!(error_type(result), ErrorType::AlreadyRegistered);
+        }
+        assert_eq!(
+            server.user(id).lock().unwrap().get_username(),
+            Some("alice")
+        );
+    }
+
+    #[test]
+    fn test_server_password() {
+        let mut server = Server::new(ServerConfig {
+            password: Some("secret".to_owned()),
+            ..Default::default()
+        });
+
+        let id = server.connect("10.0.0.1");
+        server.handle(id, "PASS secret").unwrap();
+        server.handle(id, "NICK alice").unwrap();
+        server.handle(id, "USER alice 0 * :Alice").unwrap();
+        assert!(server.user(id).lock().unwrap().is_registered());
+
+        // a wrong password, or none, is turned away
+        for pass in [Some("Secret"), Some("secret2"), None] {
+            let id = server.connect("10.0.0.2");
+            let user = server.user(id);
+            if let Some(pass) = pass {
+                server.handle(id, &format!("PASS {pass}")).unwrap();
+            }
+            server.handle(id, "NICK bob").unwrap();
+            server.handle(id, "USER bob 0 * :Bob").unwrap();
+
+            assert!(server.user_list.get(id).is_none());
+            let sent = user.lock().unwrap().take_sent();
+            assert_eq!(numerics(&sent), vec!["464", "ERROR"]);
+        }
+    }
+
+    #[test]
+    fn test_kline_duration() {
+        let mut server = Server::new(ServerConfig::default());
+        let oper = server.register("oper", "10.0.0.1");
+        server
+            .user(oper)
+            .lock()
+            .unwrap()
+            .set_oper(Some("admin".to_owned()));
+
+        server
+            .handle(oper, "KLINE 60 *@192.0.2.0/24 :Spam")
+            .unwrap();
+        let bans = server.ban_list.list();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].expires_at, Some(bans[0].set_at + 60 * 60));
+
+        // too long to count the seconds of, or to add to now
+        for minutes in [u64::MAX, u64::MAX / 60] {
+            let result = server.handle(oper, &format!("KLINE {minutes} *@198.51.100.1 :Spam"));
+            assert!(matches!(
+                error_type(result),
+                ErrorType::UnknownError(command, _) if command == "KLINE"
+            ));
+        }
+        assert_eq!(server.ban_list.list().len(), 1);
+    }
+
+    const CERTFP: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    /// A server with a `bot` account, and a client from 10.0.0.1 showing its certificate.
+    fn sasl_client() -> (Server, UserId) {
+        let mut server = Server::new(ServerConfig {
+            accounts: vec![format!("bot:{CERTFP}").parse().unwrap()],
+            ..Default::default()
+        });
+        let id = server.connect_with("10.0.0.1", |conn_write| conn_write.set_certfp(CERTFP));
+        (server, id)
+    }
+
+    #[test]
+    fn test_sasl_needs_cap() {
+        let (mut server, id) = sasl_client();
+
+        let result = server.handle(id, "AUTHENTICATE EXTERNAL");
+        assert_eq!(error_type(result), ErrorType::SaslFail);
+        assert!(!server.user(id).lock().unwrap().is_authenticating());
+        assert!(server.sent(id).is_empty());
+    }
+
+    #[test]
+    fn test_sasl_external() {
+        let (mut server, id) = sasl_client();
+        server.handle(id, "CAP REQ :sasl").unwrap();
+        server.sent(id);
+
+        server.handle(id, "AUTHENTICATE EXTERNAL").unwrap();
+        assert_eq!(server.sent(id), vec!["AUTHENTICATE +\r\n"]);
+        server.handle(id, "AUTHENTICATE +").unwrap();
+        assert_eq!(numerics(&server.sent(id)), vec!["900", "903"]);
+        assert_eq!(server.user(id).lock().unwrap().get_account(), Some("bot"));
+
+        // once is enough
+        let result = server.handle(id, "AUTHENTICATE EXTERNAL");
+        assert_eq!(error_type(result), ErrorType::SaslAlready);
+    }
+
+    #[test]
+    fn test_sasl_authzid() {
+        let (mut server, id) = sasl_client();
+        server.handle(id, "CAP REQ :sasl").unwrap();
+
+        // an account the certificate isn't bound to
+        server.handle(id, "AUTHENTICATE EXTERNAL").unwrap();
+        let result = server.handle(
+            id,
+            &format!("AUTHENTICATE {}", BASE64_STANDARD.encode("admin")),
+        );
+        assert_eq!(error_type(result), ErrorType::SaslFail);
+        assert_eq!(server.user(id).lock().unwrap().get_account(), None);
+
+        server.handle(id, "AUTHENTICATE EXTERNAL").unwrap();
+        server
+            .handle(
+                id,
+                &format!("AUTHENTICATE {}", BASE64_STANDARD.encode("bot")),
+            )
+            .unwrap();
+        assert_eq!(server.user(id).lock().unwrap().get_account(), Some("bot"));
+    }
+
+    #[test]
+    fn test_sasl_failures() {
+        let (mut server, id) = sasl_client();
+        server.handle(id, "CAP REQ :sasl").unwrap();
+        server.sent(id);
+
+        // a mechanism the server doesn't have gets the list of those it has
+        let result = server.handle(id, "AUTHENTICATE PLAIN");
+        assert_eq!(error_type(result), ErrorType::SaslFail);
+        assert_eq!(numerics(&server.sent(id)), vec!["908"]);
+
+        // the client may give up halfway
+        server.handle(id, "AUTHENTICATE EXTERNAL").unwrap();
+        let result = server.handle(id, "AUTHENTICATE *");
+        assert_eq!(error_type(result), ErrorType::SaslAborted);
+        assert!(!server.user(id).lock().unwrap().is_authenticating());
+
+        // and without a certificate there is nothing to log in with
+        let other = server.connect("10.0.0.2");
+        server.handle(other, "CAP REQ :sasl").unwrap();
+        server.handle(other, "AUTHENTICATE EXTERNAL").unwrap();
+        let result = server.handle(other, "AUTHENTICATE +");
+        assert_eq!(error_type(result), ErrorType::SaslFail);
+        assert_eq!(server.user(other).lock().unwrap().get_account(), None);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let grace = std::time::Duration::from_millis(200);
+        let mut server = Server::new(ServerConfig {
+            shutdown_grace: grace,
+            ..Default::default()
+        });
+        let alice = server.register("alice", "10.0.0.1");
+        let bob = server.register("bob", "10.0.0.2");
+        server.handle(alice, "JOIN #team").unwrap();
+        server.handle(bob, "JOIN #team").unwrap();
+        let users = [alice, bob].map(|id| server.user(id));
+        for user in &users {
+            user.lock().unwrap().take_sent();
+        }
+
+        let started = Instant::now();
+        shutdown_server(
+            &mut server.user_list,
+            &mut server.channel_list,
+            &server.config,
+            "Server shutting down",
+        );
+        // nothing reads these connections, so it waits them out, but no longer
+        let elapsed = started.elapsed();
+        assert!(elapsed >= grace, "{elapsed:?}");
+        assert!(elapsed < grace * 10, "{elapsed:?}");
+
+        assert!(server.user_list.all_users().is_empty());
+        assert!(!server.channel_list.has_channel("#team"));
+
+        let sent = users.map(|user| user.lock().unwrap().take_sent());
+        for sent in &sent {
+            assert!(sent.contains(&"ERROR :Server shutting down\r\n".to_owned()));
+        }
+        // whoever is disconnected last sees the other one leave
+        let quits = sent
+            .iter()
+            .flatten()
+            .filter(|line| line.ends_with(" QUIT :Server shutting down\r\n"))
+            .count();
+        assert_eq!(quits, 1);
+    }
+}
@@ -29,3 +29,21 @@ pub mod user;
 pub mod channel_list;
 pub mod massage_sender;
 pub mod plugin;
+pub mod casemapping;
+pub mod keepalive;
+pub mod mask;
+pub mod operator;
+pub mod password;
+pub mod config;
+pub mod config_file;
+pub mod ban;
+pub mod flood;
+pub mod limits;
+pub mod cloak;
+pub mod split;
+pub mod tls;
+pub mod account;
+pub mod websocket;
+pub mod proxy;
+pub mod webirc;
+pub mod upgrade;
@@ -0,0 +1,95 @@
+//! IRC operators
+//!
+//! Operators are configured with a name, the SHA-256 hash of their password,
+//! and a host mask they must connect from. A user becomes an operator with
+//! `OPER <name> <password>`.
+//!
+//! A password hash can be generated with:
+//!
+//! ```text
+//! echo -n 'password' | sha256sum
+//! ```
+use crate::{mask, password};
+use std::str::FromStr;
+
+/// The credentials of a single operator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Operator {
+    pub name: String,
+    /// Lowercase hex SHA-256 of the password.
+    pub password_hash: String,
+    /// Mask the operator's host must match, e.g. `127.0.0.1` or `*`.
+    pub host_mask: String,
+}
+
+impl Operator {
+    pub fn check_password(&self, password: &str) -> bool {
+        password::check_hash(&self.password_hash, password)
+    }
+
+    pub fn matches_host(&self, host: &str) -> bool {
+        mask::matches(&self.host_mask, host)
+    }
+}
+
+/// Parse an operator from `<name>:<password hash>[:<host mask>]`.
+///
+/// The host mask defaults to `*`.
+impl FromStr for Operator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let name = parts.next().unwrap_or_default();
+        let password_hash = parts.next().unwrap_or_default();
+        let host_mask = parts.next().unwrap_or("*");
+
+        if name.is_empty() {
+            return Err(format!("operator has no name: {s}"));
+        }
+
+        if password_hash.len() != 64 || !password_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("operator {name} needs a hex SHA-256 password hash"));
+        }
+
+        Ok(Operator {
+            name: name.to_owned(),
+            password_hash: password_hash.to_owned(),
+            host_mask: host_mask.to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sha256("hunter2")
+    const HASH: &str = "f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7";
+
+    #[test]
+    fn test_parse() {
+        let oper = format!("admin:{HASH}:127.0.0.*")
+            .parse::<Operator>()
+            .unwrap();
+        assert_eq!(oper.name, "admin");
+        assert_eq!(oper.host_mask, "127.0.0.*");
+
+        let oper = format!("admin:{HASH}").parse::<Operator>().unwrap();
+        assert_eq!(oper.host_mask, "*");
+
+        assert!("admin:hunter2".parse::<Operator>().is_err());
+        assert!(format!(":{HASH}").parse::<Operator>().is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let oper = format!("admin:{HASH}:127.0.0.*")
+            .parse::<Operator>()
+            .unwrap();
+        assert!(oper.check_password("hunter2"));
+        assert!(!oper.check_password("hunter3"));
+        assert!(oper.matches_host("127.0.0.1"));
+        assert!(!oper.matches_host("10.0.0.1"));
+    }
+}
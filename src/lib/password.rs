@@ -0,0 +1,67 @@
+//! Password checks
+//!
+//! Passwords are compared in constant time, so how long a check takes
+//! doesn't tell a client how much of its guess was right.
+use sha2::{Digest, Sha256};
+
+/// Check `password` against `password_hash`, the hex SHA-256 of the right one.
+pub fn check_hash(password_hash: &str, password: &str) -> bool {
+    let hash = format!("{:x}", Sha256::digest(password.as_bytes()));
+    constant_time_eq(
+        hash.as_bytes(),
+        password_hash.to_ascii_lowercase().as_bytes(),
+    )
+}
+
+/// Check `password` against the right one, `expected`, kept in plain text.
+pub fn check(expected: &str, password: &str) -> bool {
+    // compared as digests, which don't give away the length either
+    constant_time_eq(
+        &Sha256::digest(expected.as_bytes()),
+        &Sha256::digest(password.as_bytes()),
+    )
+}
+
+/// Compare two byte strings, taking as long whichever bytes differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let diff = a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b));
+    std::hint::black_box(diff) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sha256("hunter2")
+    const HASH: &str = "f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7";
+
+    #[test]
+    fn test_check_hash() {
+        assert!(check_hash(HASH, "hunter2"));
+        assert!(check_hash(&HASH.to_ascii_uppercase(), "hunter2"));
+        assert!(!check_hash(HASH, "hunter3"));
+        assert!(!check_hash(HASH, ""));
+        assert!(!check_hash("", "hunter2"));
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(check("hunter2", "hunter2"));
+        assert!(!check("hunter2", "Hunter2"));
+        assert!(!check("hunter2", "hunter"));
+        assert!(!check("hunter2", ""));
+        assert!(check("", ""));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
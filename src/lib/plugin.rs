@@ -3,20 +3,41 @@
 //! This module is used to handle plugins.
 //! 
 //! Please refer to the `plugin_handler` function for more information.
+//! 
+//! Plugins that need to wait, or do anything slow, spawn a task on the async
+//! runtime for it, so the message handler can go on.
+//! 
+//! Plugins that have something to keep across restarts save it with
+//! `save_state` when the server shuts down, and get it back from `load_state`.
 use crate::{
+    ban::unix_time,
     channel_list::ChannelList,
-    types::{ErrorType, Nick, PrivMsg, PrivReply, Reply, Target},
-    user::UserList,
+    config::ServerConfig,
+    types::{server_name, ErrorType, Nick, Prefix, PrivMsg, PrivReply, Reply, Target, UserId},
+    user::{User, UserList},
 };
 use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// The names of every plugin, which is used as `use_plugin_<name>`.
+pub const PLUGINS: &[&str] = &["sample", "reminder"];
 
 /// Plugin handler
 /// 
-/// If you want to add a new plugin, add a new match statement.
+/// If you want to add a new plugin, add a new match statement,
+/// and add its name to `PLUGINS`.
 /// 
 /// You can see an example of a plugin in the `use_plugin_sample` function.
 /// 
-/// `"use_plugin_sample" => use_plugin_sample(user_list, message_str, receiver_nick)`
+/// `"use_plugin_sample" => use_plugin_sample(user_list, message_str, receiver)`
 /// 
 /// The `"use_plugin_sample"` is the command that will trigger the plugin.
 /// You can change it to whatever you want.
@@ -25,16 +46,27 @@ use anyhow::{anyhow, Result};
 /// 
 /// Because the plugin system will check if the command starts with `"use_plugin_"`.
 /// And it will not conflict with the IRC commands due to the prefix is longer than a user's nick.
+/// 
+/// Plugins the server configuration leaves out of `plugins` can't be used.
 pub fn plugin_handler(
     user_list: &mut UserList,
     _channel_list: &mut ChannelList,
+    config: &ServerConfig,
     target_nick: Nick,
-    receiver_nick: Nick,
+    receiver: UserId,
     message_str: &str,
 ) -> Result<()> {
+    let enabled = target_nick
+        .0
+        .strip_prefix("use_plugin_")
+        .is_some_and(|name| config.plugins.iter().any(|plugin| plugin == name));
+    if !enabled {
+        return Err(anyhow!(ErrorType::PluginCommandError));
+    }
+
     match target_nick.0.as_str() {
-        "use_plugin_sample" => use_plugin_sample(user_list, message_str, receiver_nick),
-        "use_plugin_reminder" => use_plugin_reminder(user_list, message_str, receiver_nick),
+        "use_plugin_sample" => use_plugin_sample(user_list, message_str, receiver),
+        "use_plugin_reminder" => use_plugin_reminder(user_list, message_str, receiver),
         _ => Err(anyhow!(ErrorType::PluginCommandError)),
     }?;
 
@@ -61,35 +93,34 @@ pub fn plugin_handler(
 pub fn use_plugin_sample(
     user_list: &mut UserList,
     message_str: &str,
-    receiver_nick: Nick,
+    receiver: UserId,
 ) -> Result<()> {
     // Set the plugin nick, which will show up in the message
     let plugin_nick = Nick("plugin_sample".to_owned());
 
-    // Clone the user list and the message string for the thread
+    // Clone the user list and the message string for the task
     let user_list = user_list.clone();
     let message_str = message_str.to_owned();
-    // Spawn a new thread to send the message
-    std::thread::spawn(move || {
-        // Get the user list
-        let users = user_list.get_users();
-        // Lock the user list
-        let mut users = users.lock().expect("Failed to lock users");
-        // Find the user by nick
-        let user = users
-            .iter_mut()
-            .find(|user| user.get_nick() == receiver_nick.clone())
-            .expect("Failed to find user");
-
-        // Send the message
-        user.send(Reply::PrivMsg(PrivReply {
-            sender_nick: plugin_nick.clone(),
+    // Spawn a new task to send the message
+    tokio::spawn(async move {
+        // Find the user by id, unless they have left meanwhile
+        let Some(user) = user_list.get(receiver) else {
+            return;
+        };
+        // Lock the user
+        let mut user = user.lock().expect("Failed to lock user");
+        // The message goes to the user by the nick they have now
+        let receiver_nick = user.get_nick();
+
+        // Send the message. A failed write means the connection is already
+        // gone, and there is no one left to tell.
+        let _ = user.send(Reply::PrivMsg(PrivReply {
+            sender: Prefix::new(plugin_nick.clone(), "plugin", server_name()),
             message: PrivMsg {
-                target: Target::User(receiver_nick.clone()),
+                target: Target::User(receiver_nick),
                 message: message_str.to_owned(),
             },
-        }))
-        .expect("Plugin Sample: Failed to send the message");
+        }));
     });
     Ok(())
 }
@@ -98,10 +129,8 @@ pub fn use_plugin_sample(
 fn use_plugin_reminder(
     user_list: &mut UserList,
     message_str: &str,
-    receiver_nick: Nick,
+    receiver: UserId,
 ) -> Result<()> {
-    let plugin_nick = Nick("plugin_reminder".to_owned());
-
     let mut iter = message_str.splitn(2, ' ');
 
     let seconds = iter
@@ -111,28 +140,272 @@ fn use_plugin_reminder(
         .map_err(|_| anyhow!(ErrorType::PluginCommandError))?;
 
     let sentence = iter.next().ok_or(anyhow!(ErrorType::PluginCommandError))?;
+    // too far off to say when
+    let due = unix_time()
+        .checked_add(seconds)
+        .ok_or(anyhow!(ErrorType::PluginCommandError))?;
 
-    let user_list = user_list.clone();
-    let sentence = sentence.to_owned();
-    std::thread::spawn(move || {
-        std::thread::sleep(std::time::Duration::from_secs(seconds));
-
-        let users = user_list.get_users();
-        let mut users = users.lock().expect("Failed to lock users");
-        let user = users
-            .iter_mut()
-            .find(|user| user.get_nick() == receiver_nick.clone())
-            .expect("Failed to find user");
-
-        user.send(Reply::PrivMsg(PrivReply {
-            sender_nick: plugin_nick.clone(),
+    schedule_reminder(
+        user_list.clone(),
+        Reminder {
+            number: NEXT_REMINDER.fetch_add(1, Ordering::Relaxed),
+            due,
+            receiver: Some(receiver),
+            nick: None,
+            sentence: sentence.to_owned(),
+        },
+    );
+
+    Ok(())
+}
+
+/// A reminder that hasn't been sent yet.
+#[derive(Debug, Clone)]
+struct Reminder {
+    /// Tells reminders apart, so a sent one can be taken off `REMINDERS`.
+    number: u64,
+    /// When to send it, in seconds since the Unix epoch.
+    due: u64,
+    /// Who asked for it, while they are still connected.
+    receiver: Option<UserId>,
+    /// Who asked for it, for a reminder saved before a restart,
+    /// when the connection it was asked on is long gone.
+    nick: Option<Nick>,
+    sentence: String,
+}
+
+impl Reminder {
+    fn to_line(&self, nick: &Nick) -> String {
+        format!("reminder\t{}\t{}\t{}", self.due, nick, self.sentence)
+    }
+
+    fn from_line(line: &str) -> Result<Self> {
+        let mut fields = line.splitn(4, '\t');
+        let mut next = || {
+            fields
+                .next()
+                .ok_or(anyhow!("missing field in plugin state: {line}"))
+        };
+
+        let kind = next()?;
+        if kind != "reminder" {
+            return Err(anyhow!("unknown plugin state: {kind}"));
+        }
+        let due = next()?.parse::<u64>()?;
+        let nick = Nick(next()?.to_owned());
+        let sentence = next()?.to_owned();
+
+        Ok(Reminder {
+            number: NEXT_REMINDER.fetch_add(1, Ordering::Relaxed),
+            due,
+            receiver: None,
+            nick: Some(nick),
+            sentence,
+        })
+    }
+}
+
+/// How often a reminder that is due looks for the nick it was saved with again.
+const NICK_RETRY: Duration = Duration::from_secs(10);
+
+/// Reminders waiting to be sent, so they can be saved when the server shuts down.
+static REMINDERS: Mutex<Vec<Reminder>> = Mutex::new(Vec::new());
+static NEXT_REMINDER: AtomicU64 = AtomicU64::new(0);
+
+/// Keep track of a reminder, and spawn a task to send it once it is due.
+fn schedule_reminder(user_list: UserList, reminder: Reminder) {
+    REMINDERS
+        .lock()
+        .expect("Failed to lock reminders")
+        .push(reminder.clone());
+
+    tokio::spawn(async move {
+        let wait = reminder.due.saturating_sub(unix_time());
+        tokio::time::sleep(Duration::from_secs(wait)).await;
+
+        let user = match (reminder.receiver, &reminder.nick) {
+            (Some(id), _) => user_list.get(id),
+            (None, Some(nick)) => Some(wait_for_nick(&user_list, nick).await),
+            (None, None) => None,
+        };
+
+        REMINDERS
+            .lock()
+            .expect("Failed to lock reminders")
+            .retain(|other| other.number != reminder.number);
+
+        let Some(user) = user else {
+            info!("Dropping a reminder for someone who has left");
+            return;
+        };
+        // the user may have changed nick while waiting
+        let mut user = user.lock().expect("Failed to lock user");
+        let receiver_nick = user.get_nick();
+
+        let plugin_nick = Nick("plugin_reminder".to_owned());
+        if let Err(err) = user.send(Reply::PrivMsg(PrivReply {
+            sender: Prefix::new(plugin_nick, "plugin", server_name()),
             message: PrivMsg {
-                target: Target::User(receiver_nick.clone()),
-                message: sentence.to_owned(),
+                target: Target::User(receiver_nick),
+                message: reminder.sentence.clone(),
             },
-        }))
-        .expect("Plugin Reminder: Failed to send the message");
+        })) {
+            warn!("Plugin Reminder: Failed to send the message: {}", err);
+        }
     });
+}
+
+/// Wait until someone with `nick` is registered, e.g. for a reminder saved
+/// before a restart, whose receiver hasn't reconnected yet.
+async fn wait_for_nick(user_list: &UserList, nick: &Nick) -> Arc<Mutex<User>> {
+    loop {
+        if let Some(user) = user_list
+            .find(nick)
+            .filter(|user| user.lock().expect("Failed to lock user").is_registered())
+        {
+            return user;
+        }
+        tokio::time::sleep(NICK_RETRY).await;
+    }
+}
+
+/// Save what plugins need to pick up where they left off to `path`.
+///
+/// Users are saved by nick, as connections don't survive a restart,
+/// so this needs to run before the users are removed.
+pub fn save_state(path: &Path, user_list: &UserList) -> Result<()> {
+    let reminders = REMINDERS.lock().expect("Failed to lock reminders").clone();
+
+    let mut contents = String::new();
+    for reminder in reminders {
+        let nick = match (reminder.receiver, &reminder.nick) {
+            (Some(id), _) => user_list
+                .get(id)
+                .map(|user| user.lock().expect("Failed to lock user").get_nick()),
+            (None, nick) => nick.clone(),
+        };
+        // nobody left to remind
+        let Some(nick) = nick else {
+            continue;
+        };
+
+        contents.push_str(&reminder.to_line(&nick));
+        contents.push('\n');
+    }
+
+    // write to a temporary file first, so a crash can't leave half the state
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path)?;
 
     Ok(())
 }
+
+/// Pick up the state plugins saved to `path` when the server last shut down.
+///
+/// Reminders go to whoever has the nick they were asked with by the time they
+/// are due, or as soon as someone has it after that.
+///
+/// The file is removed once it is loaded, so nothing is picked up twice if
+/// the server stops without saving again.
+pub fn load_state(path: &Path, user_list: &UserList) -> Result<()> {
+    let reminders = match std::fs::read_to_string(path) {
+        Ok(contents) => contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(Reminder::from_line)
+            .collect::<Result<Vec<_>>>()?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    std::fs::remove_file(path)?;
+
+    for reminder in reminders {
+        schedule_reminder(user_list.clone(), reminder);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reminder_line() {
+        let reminder = Reminder {
+            number: 0,
+            due: 1700000000,
+            receiver: Some(UserId(1)),
+            nick: None,
+            sentence: "stand up\tand stretch".to_owned(),
+        };
+
+        let line = reminder.to_line(&Nick("alice".to_owned()));
+        assert_eq!(line, "reminder\t1700000000\talice\tstand up\tand stretch");
+
+        let restored = Reminder::from_line(&line).unwrap();
+        assert_eq!(restored.due, 1700000000);
+        assert_eq!(restored.receiver, None);
+        assert_eq!(restored.nick, Some(Nick("alice".to_owned())));
+        assert_eq!(restored.sentence, "stand up\tand stretch");
+
+        assert!(Reminder::from_line("reminder\tsoon\talice\thi").is_err());
+        assert!(Reminder::from_line("alarm\t1700000000\talice\thi").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reminder_too_late() {
+        let mut user_list = UserList::new();
+        let result = use_plugin_reminder(&mut user_list, "18446744073709551615 never", UserId(1));
+
+        let error = result.unwrap_err().downcast::<ErrorType>().unwrap();
+        assert_eq!(error, ErrorType::PluginCommandError);
+        assert!(!REMINDERS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|reminder| reminder.sentence == "never"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_load_state() {
+        use crate::{connect::ConnectionWrite, user::RegistrationState};
+
+        let path = std::env::temp_dir().join(format!("iris-plugin-{}", std::process::id()));
+        std::fs::write(&path, "reminder\t1\tcarol\tcall back\n").unwrap();
+
+        let user_list = UserList::new();
+        load_state(&path, &user_list).unwrap();
+        // loaded once only
+        assert!(!path.exists());
+
+        // the reminder is long past due, but carol isn't back yet
+        tokio::time::sleep(NICK_RETRY * 3).await;
+        assert!(REMINDERS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|reminder| reminder.sentence == "call back"));
+
+        let id = UserId(1);
+        let conn_write = ConnectionWrite::for_test("10.0.0.1:50000".parse().unwrap(), id);
+        user_list.add_user(User::new(id, conn_write, "host".to_owned()));
+        let carol = user_list.get(id).unwrap();
+        {
+            let mut carol = carol.lock().unwrap();
+            assert!(user_list.set_nick(&mut carol, Nick("Carol".to_owned())));
+            carol.set_registration(RegistrationState::Registered);
+        }
+
+        tokio::time::sleep(NICK_RETRY).await;
+        let sent = carol.lock().unwrap().take_sent();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].ends_with(" PRIVMSG Carol :call back\r\n"));
+        assert!(!REMINDERS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|reminder| reminder.sentence == "call back"));
+    }
+}
@@ -0,0 +1,188 @@
+//! PROXY protocol
+//!
+//! A load balancer like HAProxy connects to the server itself, so without
+//! help every client would seem to come from the load balancer. On listeners
+//! set up for it, every connection starts with a PROXY protocol header (v1 or
+//! v2) that tells the server who the client really is, so bans, cloaks and
+//! connection limits apply to the client.
+//!
+//! Only connections from a trusted proxy may go to such a listener: the header
+//! is taken at its word.
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The start of a v2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The most a v1 header may take up, including the `\r\n`.
+const V1_MAX_LEN: usize = 107;
+
+/// Read the PROXY header a connection starts with, and nothing after it.
+///
+/// Returns the client's address, or `None` if the proxy didn't give one, e.g.
+/// for its own health checks. Then the connection's own address is the one to use.
+pub async fn read_header(socket: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<SocketAddr>> {
+    // both versions take up at least this much
+    let mut start = [0; 12];
+    socket.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut header = [0; 4];
+        socket.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut addresses = vec![0; len];
+        socket.read_exact(&mut addresses).await?;
+
+        return parse_v2(header[0], header[1], &addresses);
+    }
+
+    // a v1 header is a line, which has to be read a byte at a time to not read past it
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY header too long"));
+        }
+        let mut byte = [0];
+        socket.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY header isn't UTF-8"))?;
+    parse_v1(line)
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Parse a v1 header, e.g. `PROXY TCP4 192.0.2.1 198.51.100.1 56324 6667`.
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let mut words = line.split(' ');
+    if words.next() != Some("PROXY") {
+        return Err(invalid("not a PROXY header"));
+    }
+
+    match words.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("unknown PROXY protocol")),
+    }
+
+    let (Some(source), Some(_), Some(port), Some(_), None) = (
+        words.next(),
+        words.next(),
+        words.next(),
+        words.next(),
+        words.next(),
+    ) else {
+        return Err(invalid("malformed PROXY header"));
+    };
+
+    let ip = source
+        .parse::<IpAddr>()
+        .map_err(|_| invalid("invalid PROXY source address"))?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| invalid("invalid PROXY source port"))?;
+
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// Parse the rest of a v2 header, from its version and command byte,
+/// its family byte, and the addresses that follow.
+fn parse_v2(command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if command >> 4 != 2 {
+        return Err(invalid("unknown PROXY version"));
+    }
+
+    match command & 0x0f {
+        // the proxy's own connection, e.g. a health check
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unknown PROXY command")),
+    }
+
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match family {
+        // TCP over IPv4: source and destination addresses, then ports
+        0x11 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            Ok(Some(SocketAddr::new(ip.into(), port(8))))
+        }
+        // TCP over IPv6
+        0x21 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            Ok(Some(SocketAddr::new(ip.into(), port(32))))
+        }
+        0x11 | 0x21 => Err(invalid("PROXY addresses too short")),
+        // UDP, Unix sockets or unspecified, which say nothing about a TCP client
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_v1() {
+        let mut input = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 6667\r\nNICK alice\r\n"[..];
+        assert_eq!(
+            read_header(&mut input).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        // what comes after the header is left to be read
+        assert_eq!(input, b"NICK alice\r\n");
+
+        let mut input = &b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 6667\r\n"[..];
+        assert_eq!(
+            read_header(&mut input).await.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+
+        assert_eq!(
+            read_header(&mut &b"PROXY UNKNOWN\r\n"[..]).await.unwrap(),
+            None
+        );
+        assert!(read_header(&mut &b"NICK alice\r\nUSER a 0 * :A\r\n"[..])
+            .await
+            .is_err());
+        assert!(
+            read_header(&mut &b"PROXY TCP4 192.0.2.1 198.51.100.1\r\n"[..])
+                .await
+                .is_err()
+        );
+        assert!(read_header(&mut &[b'A'; 200][..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1]);
+        header.extend_from_slice(&56324u16.to_be_bytes());
+        header.extend_from_slice(&6667u16.to_be_bytes());
+        header.extend_from_slice(b"NICK alice\r\n");
+
+        let mut input = &header[..];
+        assert_eq!(
+            read_header(&mut input).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(input, b"NICK alice\r\n");
+
+        // LOCAL, from the proxy itself
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut &header[..]).await.unwrap(), None);
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x21, 0, 12]);
+        header.extend_from_slice(&[0; 12]);
+        assert!(read_header(&mut &header[..]).await.is_err());
+    }
+}
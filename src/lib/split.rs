@@ -0,0 +1,110 @@
+//! Long messages
+//!
+//! A line on the wire can be at most 512 bytes, including the trailing
+//! `\r\n`. A client's `PRIVMSG` fits in that, but by the time the server
+//! relays it with the sender's `nick!user@host` in front, it may not.
+//!
+//! Depending on the server's configuration, such a message is either split
+//! into several lines, or refused with `ERR_INPUTTOOLONG` (417).
+use std::str::FromStr;
+
+/// The longest line that may be sent, including the trailing `\r\n`.
+pub const MAX_LINE_LEN: usize = 512;
+
+/// What to do with a message that would be too long once relayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LongMessages {
+    /// Send the text as several messages, each as long as fits.
+    #[default]
+    Split,
+    /// Refuse the message, and tell the sender it was too long.
+    Reject,
+}
+
+impl std::fmt::Display for LongMessages {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            LongMessages::Split => write!(fmt, "split"),
+            LongMessages::Reject => write!(fmt, "reject"),
+        }
+    }
+}
+
+impl FromStr for LongMessages {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "split" => Ok(LongMessages::Split),
+            "reject" => Ok(LongMessages::Reject),
+            _ => Err(format!("unknown long message handling: {s}")),
+        }
+    }
+}
+
+/// Split `text` into pieces of at most `max_len` bytes.
+///
+/// Pieces break after the last space that fits, so words stay whole, and
+/// only words too long for a piece of their own are broken up. A break never
+/// falls inside a UTF-8 character.
+///
+/// Returns `None` if `max_len` is too small to hold a character.
+pub fn split_text(text: &str, max_len: usize) -> Option<Vec<&str>> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+
+    while rest.len() > max_len {
+        let mut end = max_len;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            return None;
+        }
+
+        // a space right after the piece is as good a break as one inside it
+        let space = if rest[end..].starts_with(' ') {
+            Some(end)
+        } else {
+            rest[..end].rfind(' ')
+        };
+
+        // the space a piece breaks at is dropped
+        let (piece, next) = match space {
+            Some(space) if space > 0 => (&rest[..space], &rest[space + 1..]),
+            _ => (&rest[..end], &rest[end..]),
+        };
+        pieces.push(piece);
+        rest = next;
+    }
+
+    pieces.push(rest);
+    Some(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_on_words() {
+        assert_eq!(split_text("hello world", 20), Some(vec!["hello world"]));
+        assert_eq!(split_text("hello world", 8), Some(vec!["hello", "world"]));
+        assert_eq!(split_text("hello world", 5), Some(vec!["hello", "world"]));
+        assert_eq!(
+            split_text("a bb ccc dddd", 6),
+            Some(vec!["a bb", "ccc", "dddd"])
+        );
+    }
+
+    #[test]
+    fn test_split_long_words() {
+        assert_eq!(
+            split_text("abcdefghij", 4),
+            Some(vec!["abcd", "efgh", "ij"])
+        );
+        // "é" is two bytes, and must not be cut in half
+        assert_eq!(split_text("ééé", 3), Some(vec!["é", "é", "é"]));
+        assert_eq!(split_text("€", 2), None);
+    }
+}
@@ -0,0 +1,316 @@
+//! TLS
+//!
+//! Clients can connect over TLS on a port of its own, next to the plaintext
+//! port. The certificate and key are read from PEM files, and can be read
+//! again with `TlsAcceptor::reload` (on `SIGHUP`), e.g. after the certificate
+//! was renewed. Connections that are already open keep the certificate they
+//! started with.
+//!
+//! Clients may present a certificate of their own. It isn't checked against
+//! any authority: its SHA-256 fingerprint (certfp) identifies the client, e.g.
+//! to log in to an account with SASL `EXTERNAL`.
+//!
+//! The handshake happens in the connection's own task, as it is first read
+//! from, so a slow client only ever holds up itself.
+use anyhow::{anyhow, Context, Result};
+use rustls::{
+    client::danger::HandshakeSignatureValid,
+    crypto::{
+        ring::default_provider, verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+    },
+    pki_types::{CertificateDer, PrivateKeyDer, UnixTime},
+    server::danger::{ClientCertVerified, ClientCertVerifier},
+    DigitallySignedStruct, DistinguishedName, ServerConfig, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub use tokio_rustls::server::TlsStream;
+
+/// Starts TLS sessions for new connections, with the current certificate.
+#[derive(Debug, Clone)]
+pub struct TlsAcceptor {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsAcceptor {
+    /// Read the certificate chain and private key from PEM files.
+    pub fn load(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Result<Self> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let config = load_config(&cert_path, &key_path)?;
+
+        Ok(Self {
+            cert_path,
+            key_path,
+            config: Arc::new(RwLock::new(Arc::new(config))),
+        })
+    }
+
+    /// Read the certificate and key again. New connections use them from now on.
+    ///
+    /// If they can't be read, the old ones are kept.
+    pub fn reload(&self) -> Result<()> {
+        let config = load_config(&self.cert_path, &self.key_path)?;
+        *self.config.write().expect("Failed to lock TLS config") = Arc::new(config);
+
+        Ok(())
+    }
+
+    /// Do the TLS handshake on a newly accepted socket.
+    pub async fn accept<S>(&self, socket: S) -> io::Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let config = self
+            .config
+            .read()
+            .expect("Failed to lock TLS config")
+            .clone();
+
+        tokio_rustls::TlsAcceptor::from(config).accept(socket).await
+    }
+}
+
+fn load_config(cert_path: &Path, key_path: &Path) -> Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_path).with_context(|| format!("Failed to open {}", cert_path.display()))?,
+    ))
+    .collect::<Result<Vec<CertificateDer>, _>>()
+    .with_context(|| format!("Failed to read {}", cert_path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", cert_path.display()));
+    }
+
+    let key: PrivateKeyDer = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(key_path).with_context(|| format!("Failed to open {}", key_path.display()))?,
+    ))
+    .with_context(|| format!("Failed to read {}", key_path.display()))?
+    .ok_or_else(|| anyhow!("No private key found in {}", key_path.display()))?;
+
+    let provider = Arc::new(default_provider());
+    let config = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(Arc::new(AnyClientCert { provider }))
+        .with_single_cert(certs, key)?;
+
+    Ok(config)
+}
+
+/// Asks clients for a certificate, and takes any certificate they own.
+///
+/// Who issued it doesn't matter, only its fingerprint is used.
+#[derive(Debug)]
+struct AnyClientCert {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn offer_client_auth(&self) -> bool {
+        true
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    // The signatures are still checked, so a client can't show someone else's certificate
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// The fingerprint of a certificate: lowercase hex SHA-256 of its DER encoding.
+pub fn certfp(cert: &CertificateDer<'_>) -> String {
+    format!("{:x}", Sha256::digest(cert.as_ref()))
+}
+
+/// The fingerprint of the certificate the client showed, if it showed one.
+pub fn peer_certfp<S>(stream: &TlsStream<S>) -> Option<String> {
+    stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(certfp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::CertifiedKey;
+    use rustls::{pki_types::PrivatePkcs8KeyDer, ClientConfig, RootCertStore};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::TlsConnector;
+
+    /// Write a new self-signed certificate for `localhost` and its key to a
+    /// temporary directory.
+    fn self_signed(name: &str) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("iris-tls-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+
+        (cert_path, key_path, cert.cert.der().clone())
+    }
+
+    /// Connect to `acceptor` with a client that only trusts `cert`, and send
+    /// a line through, both ways. The client shows `client_cert`, if given.
+    ///
+    /// Returns the fingerprint of the client's certificate, as the server saw it.
+    async fn echo(
+        acceptor: &TlsAcceptor,
+        cert: CertificateDer<'static>,
+        client_cert: Option<&CertifiedKey>,
+    ) -> io::Result<Option<String>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let (socket, _) = listener.accept().await?;
+                let mut stream = acceptor.accept(socket).await?;
+
+                stream.write_all(b"hello\r\n").await?;
+                stream.flush().await?;
+
+                let mut line = [0; 7];
+                stream.read_exact(&mut line).await?;
+                assert_eq!(&line, b"world\r\n");
+                Ok::<_, io::Error>(peer_certfp(&stream))
+            })
+        };
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match client_cert {
+            Some(client_cert) => config
+                .with_client_auth_cert(
+                    vec![client_cert.cert.der().clone()],
+                    PrivatePkcs8KeyDer::from(client_cert.key_pair.serialize_der()).into(),
+                )
+                .unwrap(),
+            None => config.with_no_client_auth(),
+        };
+
+        let result = async {
+            let socket = TcpStream::connect(addr).await?;
+            let mut client = TlsConnector::from(Arc::new(config))
+                .connect("localhost".try_into().unwrap(), socket)
+                .await?;
+
+            let mut line = [0; 7];
+            client.read_exact(&mut line).await?;
+            assert_eq!(&line, b"hello\r\n");
+            client.write_all(b"world\r\n").await?;
+            client.flush().await
+        }
+        .await;
+
+        // the server gives up once the client does
+        result.and(server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_tls_echo() {
+        let (cert_path, key_path, cert) = self_signed("echo");
+        let acceptor = TlsAcceptor::load(cert_path, key_path).unwrap();
+
+        assert_eq!(echo(&acceptor, cert, None).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_tls_certfp() {
+        let (cert_path, key_path, cert) = self_signed("certfp");
+        let acceptor = TlsAcceptor::load(cert_path, key_path).unwrap();
+
+        let client_cert = rcgen::generate_simple_self_signed(vec!["bot".to_string()]).unwrap();
+        let expected = format!("{:x}", Sha256::digest(client_cert.cert.der()));
+
+        let certfp = echo(&acceptor, cert, Some(&client_cert)).await.unwrap();
+        assert_eq!(certfp, Some(expected));
+    }
+
+    #[tokio::test]
+    async fn test_tls_reload() {
+        let (cert_path, key_path, old_cert) = self_signed("reload");
+        let acceptor = TlsAcceptor::load(&cert_path, &key_path).unwrap();
+
+        let (new_cert_path, new_key_path, new_cert) = self_signed("reload-new");
+        std::fs::copy(new_cert_path, &cert_path).unwrap();
+        std::fs::copy(new_key_path, &key_path).unwrap();
+        acceptor.reload().unwrap();
+
+        assert!(echo(&acceptor, old_cert, None).await.is_err());
+        echo(&acceptor, new_cert.clone(), None).await.unwrap();
+
+        // a broken certificate leaves the current one in place
+        std::fs::write(&cert_path, "not a certificate").unwrap();
+        assert!(acceptor.reload().is_err());
+        echo(&acceptor, new_cert, None).await.unwrap();
+    }
+}
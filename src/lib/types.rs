@@ -1,70 +1,262 @@
 //! Types for the IRC protocol.
+use crate::casemapping::casemapping;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+};
 
 /// All relevant IRC errors are listed here.
 /// See the assignment documentation for more information.
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+///
+/// Each error carries the parameters its numeric reply needs; the nick of
+/// the user it is sent to is added by `ErrorReply`.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum ErrorType {
-    NoNickNameGiven = 431,
-    ErroneousNickname = 432,
-    NickCollision = 436,
-    NoRecipient = 411,
-    NoTextToSend = 412,
-    NoOrigin = 409,
-    UnknownCommand = 421,
-    NeedMoreParams = 461,
-    NoSuchNick = 401,
-    NoSuchChannel = 403,
+    /// 400, with the command that failed and why.
+    UnknownError(String, String),
+    /// 401, with the nick that doesn't exist.
+    NoSuchNick(String),
+    /// 403, with the channel that doesn't exist.
+    NoSuchChannel(String),
+    /// 404, with the channel the message couldn't be sent to.
+    CannotSendToChan(String),
+    /// 409
+    NoOrigin,
+    /// 410, with the CAP subcommand that isn't known.
+    InvalidCapCmd(String),
+    /// 411, with the command that had no recipient.
+    NoRecipient(String),
+    /// 412
+    NoTextToSend,
+    /// 417
+    InputTooLong,
+    /// 421, with the unknown command.
+    UnknownCommand(String),
+    /// 422
+    NoMotd,
+    /// 431
+    NoNickNameGiven,
+    /// 432, with the invalid nick.
+    ErroneousNickname(String),
+    /// 436, with the nick that is taken.
+    NickCollision(String),
+    /// 441, with the nick and the channel they aren't on.
+    UserNotInChannel(String, String),
+    /// 442, with the channel the user isn't on.
+    NotOnChannel(String),
+    /// 443, with the nick and the channel they are already on.
+    UserOnChannel(String, String),
+    /// 451
+    NotRegistered,
+    /// 461, with the command that needs more parameters.
+    NeedMoreParams(String),
+    /// 462
+    AlreadyRegistered,
+    /// 464
+    PasswdMismatch,
+    /// 465
+    YoureBannedCreep,
+    /// 472, with the unknown mode and the channel it was used on.
+    UnknownMode(char, String),
+    /// 473, with the channel.
+    InviteOnlyChan(String),
+    /// 474, with the channel.
+    BannedFromChan(String),
+    /// 475, with the channel.
+    BadChannelKey(String),
+    /// 481
+    NoPrivileges,
+    /// 482, with the channel.
+    ChanOPrivsNeeded(String),
+    /// 491
+    NoOperHost,
+    /// 501
+    UModeUnknownFlag,
+    /// 502
+    UsersDontMatch,
+    /// 904
+    SaslFail,
+    /// 906
+    SaslAborted,
+    /// 907
+    SaslAlready,
     // For Plugin
-    PluginCommandError = 500,
+    /// 500
+    PluginCommandError,
 }
 
-/// This is the name of your server, all messages originating from
-/// the server should be listed as from this name.
-pub const SERVER_NAME: &str = "iris-server";
+/// The name of the server, unless it is given another one.
+pub const DEFAULT_SERVER_NAME: &str = "iris-server";
+
+/// The longest nick a user may take, unless the server is told otherwise.
+pub const DEFAULT_NICK_LEN: usize = 9;
+
+static SERVER_NAME: OnceLock<String> = OnceLock::new();
+static NICK_LEN: AtomicUsize = AtomicUsize::new(DEFAULT_NICK_LEN);
+
+/// Set the name of the server.
+///
+/// This should be called before any connection is accepted, since clients
+/// don't expect the server to change its name. Only the first call counts.
+pub fn set_server_name(name: String) {
+    let _ = SERVER_NAME.set(name);
+}
+
+/// The name of the server. All messages originating from the server
+/// are listed as from this name.
+pub fn server_name() -> &'static str {
+    SERVER_NAME
+        .get()
+        .map_or(DEFAULT_SERVER_NAME, String::as_str)
+}
+
+/// Set the longest nick a user may take.
+///
+/// Users keep the nick they have if it is longer, only new nicks are checked.
+pub fn set_nick_len(nick_len: usize) {
+    NICK_LEN.store(nick_len, Ordering::Relaxed);
+}
+
+/// The longest nick a user may take.
+pub fn nick_len() -> usize {
+    NICK_LEN.load(Ordering::Relaxed)
+}
+
+impl ErrorType {
+    /// The numeric of the error.
+    pub fn code(&self) -> u16 {
+        match self {
+            ErrorType::UnknownError(_, _) => 400,
+            ErrorType::NoSuchNick(_) => 401,
+            ErrorType::NoSuchChannel(_) => 403,
+            ErrorType::CannotSendToChan(_) => 404,
+            ErrorType::NoOrigin => 409,
+            ErrorType::InvalidCapCmd(_) => 410,
+            ErrorType::NoRecipient(_) => 411,
+            ErrorType::NoTextToSend => 412,
+            ErrorType::InputTooLong => 417,
+            ErrorType::UnknownCommand(_) => 421,
+            ErrorType::NoMotd => 422,
+            ErrorType::NoNickNameGiven => 431,
+            ErrorType::ErroneousNickname(_) => 432,
+            ErrorType::NickCollision(_) => 436,
+            ErrorType::UserNotInChannel(_, _) => 441,
+            ErrorType::NotOnChannel(_) => 442,
+            ErrorType::UserOnChannel(_, _) => 443,
+            ErrorType::NotRegistered => 451,
+            ErrorType::NeedMoreParams(_) => 461,
+            ErrorType::AlreadyRegistered => 462,
+            ErrorType::PasswdMismatch => 464,
+            ErrorType::YoureBannedCreep => 465,
+            ErrorType::UnknownMode(_, _) => 472,
+            ErrorType::InviteOnlyChan(_) => 473,
+            ErrorType::BannedFromChan(_) => 474,
+            ErrorType::BadChannelKey(_) => 475,
+            ErrorType::NoPrivileges => 481,
+            ErrorType::ChanOPrivsNeeded(_) => 482,
+            ErrorType::NoOperHost => 491,
+            ErrorType::UModeUnknownFlag => 501,
+            ErrorType::UsersDontMatch => 502,
+            ErrorType::SaslFail => 904,
+            ErrorType::SaslAborted => 906,
+            ErrorType::SaslAlready => 907,
+            ErrorType::PluginCommandError => 500,
+        }
+    }
+
+    /// The parameters of the error, between the recipient's nick and the text.
+    fn params(&self) -> Vec<String> {
+        match self {
+            ErrorType::NoSuchNick(param)
+            | ErrorType::NoSuchChannel(param)
+            | ErrorType::CannotSendToChan(param)
+            | ErrorType::InvalidCapCmd(param)
+            | ErrorType::UnknownCommand(param)
+            | ErrorType::ErroneousNickname(param)
+            | ErrorType::NickCollision(param)
+            | ErrorType::NotOnChannel(param)
+            | ErrorType::NeedMoreParams(param)
+            | ErrorType::InviteOnlyChan(param)
+            | ErrorType::BannedFromChan(param)
+            | ErrorType::BadChannelKey(param)
+            | ErrorType::ChanOPrivsNeeded(param) => vec![param.clone()],
+            ErrorType::UserNotInChannel(nick, channel)
+            | ErrorType::UserOnChannel(nick, channel) => {
+                vec![nick.clone(), channel.clone()]
+            }
+            ErrorType::UnknownMode(mode, _) => vec![mode.to_string()],
+            ErrorType::UnknownError(command, _) => vec![command.clone()],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The human readable text of the error.
+    fn text(&self) -> String {
+        match self {
+            ErrorType::UnknownError(_, reason) => reason.clone(),
+            ErrorType::NoSuchNick(_) => "No such nick/channel".to_owned(),
+            ErrorType::NoSuchChannel(_) => "No such channel".to_owned(),
+            ErrorType::CannotSendToChan(_) => "Cannot send to channel".to_owned(),
+            ErrorType::NoOrigin => "No origin specified".to_owned(),
+            ErrorType::InvalidCapCmd(_) => "Invalid CAP command".to_owned(),
+            ErrorType::NoRecipient(command) => format!("No recipient given ({command})"),
+            ErrorType::NoTextToSend => "No text to send".to_owned(),
+            ErrorType::InputTooLong => "Input line was too long".to_owned(),
+            ErrorType::UnknownCommand(_) => "Unknown command".to_owned(),
+            ErrorType::NoMotd => "MOTD File is missing".to_owned(),
+            ErrorType::NoNickNameGiven => "No nickname given".to_owned(),
+            // Typo is same as in RFC1459
+            ErrorType::ErroneousNickname(_) => "Erroneus nickname".to_owned(),
+            ErrorType::NickCollision(_) => "Nickname collision".to_owned(),
+            ErrorType::UserNotInChannel(_, _) => "They aren't on that channel".to_owned(),
+            ErrorType::NotOnChannel(_) => "You're not on that channel".to_owned(),
+            ErrorType::UserOnChannel(_, _) => "is already on channel".to_owned(),
+            ErrorType::NotRegistered => "You have not registered".to_owned(),
+            ErrorType::NeedMoreParams(_) => "Not enough parameters".to_owned(),
+            ErrorType::AlreadyRegistered => "You may not reregister".to_owned(),
+            ErrorType::PasswdMismatch => "Password incorrect".to_owned(),
+            ErrorType::YoureBannedCreep => "You are banned from this server".to_owned(),
+            ErrorType::UnknownMode(_, channel) => {
+                format!("is unknown mode char to me for {channel}")
+            }
+            ErrorType::InviteOnlyChan(_) => "Cannot join channel (+i)".to_owned(),
+            ErrorType::BannedFromChan(_) => "Cannot join channel (+b)".to_owned(),
+            ErrorType::BadChannelKey(_) => "Cannot join channel (+k)".to_owned(),
+            ErrorType::NoPrivileges => "Permission Denied- You're not an IRC operator".to_owned(),
+            ErrorType::ChanOPrivsNeeded(_) => "You're not channel operator".to_owned(),
+            ErrorType::NoOperHost => "No O-lines for your host".to_owned(),
+            ErrorType::UModeUnknownFlag => "Unknown MODE flag".to_owned(),
+            ErrorType::UsersDontMatch => "Cannot change mode for other users".to_owned(),
+            ErrorType::SaslFail => "SASL authentication failed".to_owned(),
+            ErrorType::SaslAborted => "SASL authentication aborted".to_owned(),
+            ErrorType::SaslAlready => "You have already authenticated using SASL".to_owned(),
+            ErrorType::PluginCommandError => "Plugin invalid".to_owned(),
+        }
+    }
+}
 
 impl std::error::Error for ErrorType {}
 
+/// Shows the error as it appears after the recipient's nick, e.g. `401 bob :No such nick/channel`.
 impl std::fmt::Display for ErrorType {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        match *self {
-            ErrorType::NoNickNameGiven => {
-                write!(fmt, ":{SERVER_NAME} 431 :No nickname given.")
-            }
-            ErrorType::ErroneousNickname => {
-                // Typo is same as in RFC1459
-                write!(fmt, ":{SERVER_NAME} 432 :Erroneus nickname")
-            }
-            ErrorType::NoRecipient => {
-                write!(fmt, ":{SERVER_NAME} 411 :No recipient given")
-            }
-            ErrorType::NoTextToSend => {
-                write!(fmt, ":{SERVER_NAME} 412 :No text to send")
-            }
-            ErrorType::NoOrigin => {
-                write!(fmt, ":{SERVER_NAME} 409 :No origin specified")
-            }
-            ErrorType::UnknownCommand => {
-                write!(fmt, ":{SERVER_NAME} 421 :Unknown command")
-            }
-            ErrorType::NeedMoreParams => {
-                write!(fmt, ":{SERVER_NAME} 461 :Not enough parameters")
-            }
-            ErrorType::NoSuchNick => {
-                write!(fmt, ":{SERVER_NAME} 401 :No such nick/channel")
-            }
-            ErrorType::NoSuchChannel => {
-                write!(fmt, ":{SERVER_NAME} 403 :No such channel")
-            }
-            ErrorType::NickCollision => {
-                write!(fmt, ":{SERVER_NAME} 436 :Nickname collision")
-            }
-            ErrorType::PluginCommandError => {
-                write!(fmt, ":{SERVER_NAME} 500 :Plugin invalid")
-            }
+        write!(fmt, "{:03}", self.code())?;
+        for param in self.params() {
+            write!(fmt, " {param}")?;
         }
+        write!(fmt, " :{}", self.text())
     }
 }
 
+/// `ERR_NEEDMOREPARAMS` for a command, given as its split up parts.
+fn need_more_params(value: &[String]) -> ErrorType {
+    ErrorType::NeedMoreParams(value.first().cloned().unwrap_or_default())
+}
+
 /// Given an IRC command, this will split it up into component parts.
 /// Particularly, the prefix (optionally), then all space-separated args,
 /// then (optionally) the final argument.
@@ -112,23 +304,61 @@ impl std::fmt::Display for Target {
     }
 }
 
+/// Identifies a connected user, whatever their nick.
+///
+/// Every connection is given one when it is accepted, and keeps it until it
+/// closes; no other connection gets the same one while the server runs,
+/// upgrades included. Users that haven't picked a nick yet are shown by it,
+/// as `*<number>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct UserId(pub(crate) u64);
+
+impl std::fmt::Display for UserId {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "*{}", self.0)
+    }
+}
+
 /// A nickname.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+///
+/// Nicks compare and hash according to the server's casemapping,
+/// but keep the case they were given in for display.
+#[derive(Debug, Clone)]
 pub struct Nick(pub String);
 
+impl Nick {
+    /// The nick folded with the server's casemapping, for use as a key.
+    pub fn folded(&self) -> String {
+        casemapping().fold(&self.0)
+    }
+}
+
+impl PartialEq for Nick {
+    fn eq(&self, other: &Self) -> bool {
+        casemapping().equals(&self.0, &other.0)
+    }
+}
+
+impl Eq for Nick {}
+
+impl std::hash::Hash for Nick {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.folded().hash(state);
+    }
+}
+
 impl TryFrom<String> for Nick {
     type Error = ErrorType;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        let value = value;
-        if (1..10).contains(&value.len())
+        if (1..=nick_len()).contains(&value.len())
             && value.is_ascii()
             && value.chars().next().unwrap_or('!').is_alphabetic()
             && value.chars().all(char::is_alphanumeric)
         {
             Ok(Nick(value))
         } else {
-            Err(ErrorType::ErroneousNickname)
+            Err(ErrorType::ErroneousNickname(value))
         }
     }
 }
@@ -139,10 +369,57 @@ impl std::fmt::Display for Nick {
     }
 }
 
+/// The source of a message relayed from a user, shown as `nick!user@host`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prefix {
+    pub nick: Nick,
+    pub user: String,
+    pub host: String,
+}
+
+impl Prefix {
+    pub fn new(nick: Nick, user: impl Into<String>, host: impl Into<String>) -> Self {
+        Self {
+            nick,
+            user: user.into(),
+            host: host.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Prefix {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(fmt, "{}!{}@{}", self.nick, self.user, self.host)
+    }
+}
+
 /// An IRC channel.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+///
+/// Like `Nick`, channels compare and hash according to the server's casemapping.
+#[derive(Debug, Clone)]
 pub struct Channel(pub String);
 
+impl Channel {
+    /// The channel name folded with the server's casemapping, for use as a key.
+    pub fn folded(&self) -> String {
+        casemapping().fold(&self.0)
+    }
+}
+
+impl PartialEq for Channel {
+    fn eq(&self, other: &Self) -> bool {
+        casemapping().equals(&self.0, &other.0)
+    }
+}
+
+impl Eq for Channel {}
+
+impl std::hash::Hash for Channel {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.folded().hash(state);
+    }
+}
+
 impl TryFrom<String> for Channel {
     type Error = ErrorType;
 
@@ -154,7 +431,7 @@ impl TryFrom<String> for Channel {
         {
             Ok(Channel(value))
         } else {
-            Err(ErrorType::NoSuchChannel)
+            Err(ErrorType::NoSuchChannel(value))
         }
     }
 }
@@ -197,9 +474,9 @@ impl TryFrom<Vec<String>> for JoinMsg {
 
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
         value
-            .into_iter()
-            .nth(1)
-            .ok_or(ErrorType::NeedMoreParams)
+            .get(1)
+            .cloned()
+            .ok_or_else(|| need_more_params(&value))
             .and_then(Channel::try_from)
             .map(|channel| JoinMsg { channel })
     }
@@ -217,30 +494,150 @@ impl TryFrom<Vec<String>> for PartMsg {
 
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
         value
-            .into_iter()
-            .nth(1)
-            .ok_or(ErrorType::NeedMoreParams)
+            .get(1)
+            .cloned()
+            .ok_or_else(|| need_more_params(&value))
             .and_then(Channel::try_from)
             .map(|channel| PartMsg { channel })
     }
 }
 
 /// A message to register a new user.
-// For example: `USER ignored ignored ignored :Thomas Kunc\r\n`
+// For example: `USER tfpk ignored ignored :Thomas Kunc\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UserMsg {
+    pub username: String,
     pub real_name: String,
 }
 
+/// The longest username kept from `USER`.
+pub const USERLEN: usize = 10;
+
 impl TryFrom<Vec<String>> for UserMsg {
     type Error = ErrorType;
 
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        // the username ends up in `nick!user@host`, so it can't contain
+        // anything that would break that apart
+        let username = value
+            .get(1)
+            .ok_or_else(|| need_more_params(&value))?
+            .chars()
+            .filter(|c| c.is_ascii_graphic() && !matches!(c, '!' | '@' | ':'))
+            .take(USERLEN)
+            .collect::<String>();
+        if username.is_empty() {
+            return Err(need_more_params(&value));
+        }
+
+        Ok(UserMsg {
+            username,
+            real_name: value
+                .get(4)
+                .cloned()
+                .ok_or_else(|| need_more_params(&value))?,
+        })
+    }
+}
+
+/// A password for the server, sent before registering.
+/// For example: `PASS secret\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassMsg {
+    pub password: String,
+}
+
+impl TryFrom<Vec<String>> for PassMsg {
+    type Error = ErrorType;
+
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
         value
-            .into_iter()
-            .nth(4)
-            .ok_or(ErrorType::NeedMoreParams)
-            .map(|real_name| UserMsg { real_name })
+            .get(1)
+            .cloned()
+            .ok_or_else(|| need_more_params(&value))
+            .map(|password| PassMsg { password })
+    }
+}
+
+/// A gateway telling the server who its user is, sent before registering.
+/// For example: `WEBIRC hunter2 kiwi user.example.com 192.0.2.1\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebircMsg {
+    pub password: String,
+    pub gateway: String,
+    pub hostname: String,
+    pub ip: String,
+}
+
+impl TryFrom<Vec<String>> for WebircMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        // options after the address, such as `secure`, aren't used
+        match &value[..] {
+            [_, password, gateway, hostname, ip, ..] => Ok(WebircMsg {
+                password: password.clone(),
+                gateway: gateway.clone(),
+                hostname: hostname.clone(),
+                ip: ip.clone(),
+            }),
+            _ => Err(need_more_params(&value)),
+        }
+    }
+}
+
+/// The capabilities clients may enable with `CAP REQ`, with the value
+/// `CAP LS 302` shows for them, if any.
+///
+/// `CAP LS` offers these, and informational ones such as `sts` that can't be requested.
+pub const CAPABILITIES: &[(&str, Option<&str>)] = &[("sasl", Some("EXTERNAL"))];
+
+/// The SASL mechanisms the server supports.
+pub const SASL_MECHANISMS: &[&str] = &["EXTERNAL"];
+
+/// A step of SASL authentication.
+/// For example: `AUTHENTICATE EXTERNAL\r\n`, then `AUTHENTICATE +\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticateMsg {
+    /// The mechanism to use, base64 data for it, `+` for no data, or `*` to abort.
+    pub data: String,
+}
+
+impl TryFrom<Vec<String>> for AuthenticateMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        value
+            .get(1)
+            .cloned()
+            .ok_or_else(|| need_more_params(&value))
+            .map(|data| AuthenticateMsg { data })
+    }
+}
+
+/// Capability negotiation.
+/// For example: `CAP LS 302\r\n`, `CAP REQ :multi-prefix\r\n` or `CAP END\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapMsg {
+    /// `LS`, `LIST`, `REQ` or `END`, in uppercase.
+    pub subcommand: String,
+    /// The capabilities of a `REQ`, or the version of an `LS`.
+    pub arg: Option<String>,
+}
+
+impl TryFrom<Vec<String>> for CapMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let subcommand = value
+            .get(1)
+            .ok_or_else(|| need_more_params(&value))?
+            .to_ascii_uppercase();
+
+        Ok(CapMsg {
+            subcommand,
+            arg: value.get(2).cloned(),
+        })
     }
 }
 
@@ -257,7 +654,12 @@ impl TryFrom<Vec<String>> for PrivMsg {
 
     fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
         Ok(PrivMsg {
-            target: Target::from(value.get(1).ok_or(ErrorType::NoRecipient)?.to_string()),
+            target: Target::from(
+                value
+                    .get(1)
+                    .ok_or_else(|| ErrorType::NoRecipient(value[0].clone()))?
+                    .to_string(),
+            ),
             // skip(2) here skips the PRIVMSG instruction and target.
             message: value
                 .into_iter()
@@ -286,6 +688,175 @@ impl TryFrom<Vec<String>> for QuitMsg {
     }
 }
 
+/// A message to become an IRC operator.
+/// For example: `OPER admin hunter2\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperMsg {
+    pub name: String,
+    pub password: String,
+}
+
+impl TryFrom<Vec<String>> for OperMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        Ok(OperMsg {
+            name: value
+                .get(1)
+                .ok_or_else(|| need_more_params(&value))?
+                .to_string(),
+            password: value
+                .get(2)
+                .ok_or_else(|| need_more_params(&value))?
+                .to_string(),
+        })
+    }
+}
+
+/// A message from an operator to disconnect a user.
+/// For example: `KILL tom :Spamming\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KillMsg {
+    pub nick: Nick,
+    pub reason: String,
+}
+
+impl TryFrom<Vec<String>> for KillMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        Ok(KillMsg {
+            nick: Nick(
+                value
+                    .get(1)
+                    .ok_or_else(|| need_more_params(&value))?
+                    .to_string(),
+            ),
+            reason: value
+                .get(2)
+                .ok_or_else(|| need_more_params(&value))?
+                .to_string(),
+        })
+    }
+}
+
+/// A message to show or change a user's modes.
+/// For example: `MODE tom +w\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeMsg {
+    pub target: Target,
+    pub modes: Option<String>,
+}
+
+impl TryFrom<Vec<String>> for ModeMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        Ok(ModeMsg {
+            target: Target::from(
+                value
+                    .get(1)
+                    .ok_or_else(|| need_more_params(&value))?
+                    .to_string(),
+            ),
+            modes: value.get(2).cloned(),
+        })
+    }
+}
+
+/// A message from an operator to ban a `user@host` mask from the server.
+/// The optional duration is in minutes.
+/// For example: `KLINE 60 *@10.0.0.0/8 :Spamming\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KlineMsg {
+    pub duration: Option<u64>,
+    pub mask: String,
+    pub reason: String,
+}
+
+impl TryFrom<Vec<String>> for KlineMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        let command = value[0].clone();
+        // skip(1) here skips the KLINE instruction.
+        let mut args = value.into_iter().skip(1).peekable();
+
+        let duration = args
+            .peek()
+            .and_then(|arg| arg.parse::<u64>().ok())
+            .filter(|duration| *duration > 0);
+        if duration.is_some() {
+            args.next();
+        }
+
+        Ok(KlineMsg {
+            duration,
+            mask: args.next().ok_or(ErrorType::NeedMoreParams(command))?,
+            reason: args.next().unwrap_or_else(|| "No reason".to_owned()),
+        })
+    }
+}
+
+/// A message from an operator to remove a ban.
+/// For example: `UNKLINE *@10.0.0.0/8\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnklineMsg {
+    pub mask: String,
+}
+
+impl TryFrom<Vec<String>> for UnklineMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        value
+            .get(1)
+            .cloned()
+            .ok_or_else(|| need_more_params(&value))
+            .map(|mask| UnklineMsg { mask })
+    }
+}
+
+/// A query for server statistics.
+/// For example: `STATS k\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsMsg {
+    pub query: String,
+}
+
+impl TryFrom<Vec<String>> for StatsMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        value
+            .get(1)
+            .cloned()
+            .ok_or_else(|| need_more_params(&value))
+            .map(|query| StatsMsg { query })
+    }
+}
+
+/// A query for information about a user.
+/// For example: `WHOIS tom\r\n`, or `WHOIS iris-server tom\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoisMsg {
+    pub nick: Nick,
+}
+
+impl TryFrom<Vec<String>> for WhoisMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+        // the nick is always last, after the optional server
+        value
+            .into_iter()
+            .skip(1)
+            .last()
+            .ok_or(ErrorType::NoNickNameGiven)
+            .map(|nick| WhoisMsg { nick: Nick(nick) })
+    }
+}
+
 /// A list of every possible message that can be sent.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Message {
@@ -293,22 +864,37 @@ pub enum Message {
     User(UserMsg),
     PrivMsg(PrivMsg),
     Ping(String),
+    Pong(String),
     Join(JoinMsg),
     Part(PartMsg),
     Quit(QuitMsg),
+    Mode(ModeMsg),
+    Oper(OperMsg),
+    Kill(KillMsg),
+    Wallops(String),
+    Die,
+    Restart,
+    Kline(KlineMsg),
+    Unkline(UnklineMsg),
+    Stats(StatsMsg),
+    Whois(WhoisMsg),
+    Pass(PassMsg),
+    Cap(CapMsg),
+    Authenticate(AuthenticateMsg),
+    Webirc(WebircMsg),
 }
 
 /// To parse a message, construct this struct.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UnparsedMessage<'a> {
-    pub sender_nick: Nick,
+    pub sender: UserId,
     pub message: &'a str,
 }
 
 /// After parsing an `UnparsedMessage`, this struct will be created.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ParsedMessage {
-    pub sender_nick: Nick,
+    pub sender: UserId,
     pub message: Message,
 }
 
@@ -330,17 +916,49 @@ impl<'a> TryFrom<UnparsedMessage<'a>> for ParsedMessage {
                     .ok_or(ErrorType::NoOrigin)?
                     .to_string(),
             )),
+            "PONG" => Ok(Message::Pong(
+                // Skip here ignores the "PONG".
+                command
+                    .iter()
+                    .skip(1)
+                    .last()
+                    .ok_or(ErrorType::NoOrigin)?
+                    .to_string(),
+            )),
             "PRIVMSG" => Ok(Message::PrivMsg(PrivMsg::try_from(command)?)),
             "USER" => Ok(Message::User(UserMsg::try_from(command)?)),
             "NICK" => Ok(Message::Nick(NickMsg::try_from(command)?)),
             "JOIN" => Ok(Message::Join(JoinMsg::try_from(command)?)),
             "PART" => Ok(Message::Part(PartMsg::try_from(command)?)),
             "QUIT" => Ok(Message::Quit(QuitMsg::try_from(command)?)),
-            _ => Err(ErrorType::UnknownCommand),
+            "MODE" => Ok(Message::Mode(ModeMsg::try_from(command)?)),
+            "OPER" => Ok(Message::Oper(OperMsg::try_from(command)?)),
+            "KILL" => Ok(Message::Kill(KillMsg::try_from(command)?)),
+            "WALLOPS" => Ok(Message::Wallops(
+                // Skip here ignores the "WALLOPS".
+                command
+                    .iter()
+                    .skip(1)
+                    .last()
+                    .ok_or(ErrorType::NeedMoreParams(command[0].clone()))?
+                    .to_string(),
+            )),
+            "DIE" => Ok(Message::Die),
+            "RESTART" => Ok(Message::Restart),
+            // With a single server, G-lines and K-lines are the same thing.
+            "KLINE" | "GLINE" => Ok(Message::Kline(KlineMsg::try_from(command)?)),
+            "UNKLINE" | "UNGLINE" => Ok(Message::Unkline(UnklineMsg::try_from(command)?)),
+            "STATS" => Ok(Message::Stats(StatsMsg::try_from(command)?)),
+            "WHOIS" => Ok(Message::Whois(WhoisMsg::try_from(command)?)),
+            "PASS" => Ok(Message::Pass(PassMsg::try_from(command)?)),
+            "CAP" => Ok(Message::Cap(CapMsg::try_from(command)?)),
+            "AUTHENTICATE" => Ok(Message::Authenticate(AuthenticateMsg::try_from(command)?)),
+            "WEBIRC" => Ok(Message::Webirc(WebircMsg::try_from(command)?)),
+            _ => Err(ErrorType::UnknownCommand(command[0].clone())),
         }?;
 
         Ok(ParsedMessage {
-            sender_nick: value.sender_nick,
+            sender: value.sender,
             message,
         })
     }
@@ -349,25 +967,31 @@ impl<'a> TryFrom<UnparsedMessage<'a>> for ParsedMessage {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PrivReply {
     pub message: PrivMsg,
-    pub sender_nick: Nick,
+    pub sender: Prefix,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct JoinReply {
     pub message: JoinMsg,
-    pub sender_nick: Nick,
+    pub sender: Prefix,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PartReply {
     pub message: PartMsg,
-    pub sender_nick: Nick,
+    pub sender: Prefix,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct QuitReply {
     pub message: QuitMsg,
-    pub sender_nick: Nick,
+    pub sender: Prefix,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NickReply {
+    pub sender: Prefix,
+    pub nick: Nick,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -376,51 +1000,398 @@ pub struct WelcomeReply {
     pub message: String,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ISupportReply {
+    pub target_nick: Nick,
+    pub tokens: Vec<String>,
+}
+
+/// The message of the day, sent to users as they finish registering.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MotdReply {
+    pub target_nick: Nick,
+    pub lines: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct YoureOperReply {
+    pub target_nick: Nick,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UModeIsReply {
+    pub target_nick: Nick,
+    pub modes: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeReply {
+    pub sender: Prefix,
+    pub target_nick: Nick,
+    pub modes: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WallopsReply {
+    pub sender: Prefix,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoticeReply {
+    pub target_nick: Nick,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsKlineReply {
+    pub target_nick: Nick,
+    pub user: String,
+    pub host: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndOfStatsReply {
+    pub target_nick: Nick,
+    pub query: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoisUserReply {
+    pub target_nick: Nick,
+    pub nick: Nick,
+    pub username: String,
+    pub host: String,
+    pub real_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoisChannelsReply {
+    pub target_nick: Nick,
+    pub nick: Nick,
+    pub channels: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoisServerReply {
+    pub target_nick: Nick,
+    pub nick: Nick,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoisOperatorReply {
+    pub target_nick: Nick,
+    pub nick: Nick,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoisActuallyReply {
+    pub target_nick: Nick,
+    pub nick: Nick,
+    pub ip: IpAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoisCertfpReply {
+    pub target_nick: Nick,
+    pub nick: Nick,
+    pub certfp: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoisAccountReply {
+    pub target_nick: Nick,
+    pub nick: Nick,
+    pub account: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggedInReply {
+    pub target_nick: Nick,
+    pub prefix: Prefix,
+    pub account: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaslSuccessReply {
+    pub target_nick: Nick,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaslMechsReply {
+    pub target_nick: Nick,
+    /// Comma separated mechanisms.
+    pub mechanisms: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndOfWhoisReply {
+    pub target_nick: Nick,
+    pub nick: Nick,
+}
+
+/// An answer to a `CAP` command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapReply {
+    /// The recipient's nick, or `*` if they don't have one yet.
+    pub target_nick: Nick,
+    pub subcommand: String,
+    /// Space separated capabilities.
+    pub caps: String,
+}
+
+/// An error, sent to the user whose message caused it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorReply {
+    /// The recipient's nick, or `*` if they don't have one yet.
+    pub target_nick: Nick,
+    pub error: ErrorType,
+}
+
 /// Every possible reply to a message.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Reply {
+    Ping(String),
     Pong(String),
     Welcome(WelcomeReply),
+    ISupport(ISupportReply),
+    Motd(MotdReply),
     PrivMsg(PrivReply),
     Join(JoinReply),
     Part(PartReply),
-    Error(ErrorType),
+    Error(ErrorReply),
     Quit(QuitReply),
+    Nick(NickReply),
+    YoureOper(YoureOperReply),
+    UModeIs(UModeIsReply),
+    Mode(ModeReply),
+    Wallops(WallopsReply),
+    /// A `NOTICE` from the server.
+    Notice(NoticeReply),
+    StatsKline(StatsKlineReply),
+    EndOfStats(EndOfStatsReply),
+    WhoisUser(WhoisUserReply),
+    WhoisChannels(WhoisChannelsReply),
+    WhoisServer(WhoisServerReply),
+    WhoisOperator(WhoisOperatorReply),
+    /// The real address of a user, only shown to operators.
+    WhoisActually(WhoisActuallyReply),
+    /// The fingerprint of a user's client certificate, only shown
+    /// to the user and to operators.
+    WhoisCertfp(WhoisCertfpReply),
+    WhoisAccount(WhoisAccountReply),
+    EndOfWhois(EndOfWhoisReply),
+    Cap(CapReply),
+    /// The server's side of SASL authentication.
+    Authenticate(String),
+    LoggedIn(LoggedInReply),
+    SaslSuccess(SaslSuccessReply),
+    SaslMechs(SaslMechsReply),
+    /// An `ERROR` message, sent just before the server closes a connection.
+    ErrorMsg(String),
 }
 
 impl std::fmt::Display for Reply {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let server = server_name();
         match self {
+            Reply::Ping(p) => write!(fmt, "PING :{p}\r\n"),
             Reply::Pong(p) => write!(fmt, "PONG :{p}\r\n"),
             Reply::Welcome(r) => {
                 let nick = &r.target_nick;
                 let message = &r.message;
-                write!(fmt, ":{SERVER_NAME} 001 {nick} :{message}\r\n")
+                write!(fmt, ":{server} 001 {nick} :{message}\r\n")
+            }
+            Reply::ISupport(r) => {
+                let nick = &r.target_nick;
+                let tokens = r.tokens.join(" ");
+                write!(
+                    fmt,
+                    ":{server} 005 {nick} {tokens} :are supported by this server\r\n"
+                )
+            }
+            Reply::Motd(r) => {
+                let nick = &r.target_nick;
+                write!(
+                    fmt,
+                    ":{server} 375 {nick} :- {server} Message of the day - \r\n"
+                )?;
+                for line in &r.lines {
+                    write!(fmt, ":{server} 372 {nick} :- {line}\r\n")?;
+                }
+                write!(fmt, ":{server} 376 {nick} :End of /MOTD command.\r\n")
             }
             Reply::PrivMsg(r) => {
                 let nick = &r.message.target;
                 let message = &r.message.message;
-                let from = &r.sender_nick;
+                let from = &r.sender;
                 write!(fmt, ":{from} PRIVMSG {nick} :{message}\r\n")
             }
-            Reply::Error(e) => {
-                write!(fmt, ":{SERVER_NAME} {e}\r\n")
+            Reply::Error(r) => {
+                let code = r.error.code();
+                let nick = &r.target_nick;
+                write!(fmt, ":{server} {code:03} {nick}")?;
+                for param in r.error.params() {
+                    write!(fmt, " {param}")?;
+                }
+                write!(fmt, " :{}\r\n", r.error.text())
             }
             Reply::Join(r) => {
-                let sender = &r.sender_nick;
+                let sender = &r.sender;
                 let channel = &r.message.channel;
                 write!(fmt, ":{sender} JOIN {channel}\r\n")
             }
             Reply::Part(r) => {
-                let sender = &r.sender_nick;
+                let sender = &r.sender;
                 let channel = &r.message.channel;
                 write!(fmt, ":{sender} PART {channel}\r\n")
             }
             Reply::Quit(r) => {
-                let sender = &r.sender_nick.to_string();
-                let message = &r.message.message.as_ref().unwrap_or(sender);
+                let sender = &r.sender;
+                let nick = &r.sender.nick.0;
+                let message = &r.message.message.as_ref().unwrap_or(nick);
                 write!(fmt, ":{sender} QUIT :{message}\r\n")
             }
+            Reply::Nick(r) => {
+                let sender = &r.sender;
+                let nick = &r.nick;
+                write!(fmt, ":{sender} NICK :{nick}\r\n")
+            }
+            Reply::YoureOper(r) => {
+                let nick = &r.target_nick;
+                write!(fmt, ":{server} 381 {nick} :You are now an IRC operator\r\n")
+            }
+            Reply::UModeIs(r) => {
+                let nick = &r.target_nick;
+                let modes = &r.modes;
+                write!(fmt, ":{server} 221 {nick} {modes}\r\n")
+            }
+            Reply::Mode(r) => {
+                let sender = &r.sender;
+                let nick = &r.target_nick;
+                let modes = &r.modes;
+                write!(fmt, ":{sender} MODE {nick} :{modes}\r\n")
+            }
+            Reply::Wallops(r) => {
+                let sender = &r.sender;
+                let message = &r.message;
+                write!(fmt, ":{sender} WALLOPS :{message}\r\n")
+            }
+            Reply::Notice(r) => {
+                let nick = &r.target_nick;
+                let message = &r.message;
+                write!(fmt, ":{server} NOTICE {nick} :{message}\r\n")
+            }
+            Reply::StatsKline(r) => {
+                let nick = &r.target_nick;
+                let user = &r.user;
+                let host = &r.host;
+                let reason = &r.reason;
+                write!(fmt, ":{server} 216 {nick} K {host} * {user} :{reason}\r\n")
+            }
+            Reply::EndOfStats(r) => {
+                let nick = &r.target_nick;
+                let query = &r.query;
+                write!(
+                    fmt,
+                    ":{server} 219 {nick} {query} :End of /STATS report\r\n"
+                )
+            }
+            Reply::WhoisUser(r) => {
+                let target = &r.target_nick;
+                let nick = &r.nick;
+                let username = &r.username;
+                let host = &r.host;
+                let real_name = &r.real_name;
+                write!(
+                    fmt,
+                    ":{server} 311 {target} {nick} {username} {host} * :{real_name}\r\n"
+                )
+            }
+            Reply::WhoisChannels(r) => {
+                let target = &r.target_nick;
+                let nick = &r.nick;
+                let channels = r.channels.join(" ");
+                write!(fmt, ":{server} 319 {target} {nick} :{channels}\r\n")
+            }
+            Reply::WhoisServer(r) => {
+                let target = &r.target_nick;
+                let nick = &r.nick;
+                write!(
+                    fmt,
+                    ":{server} 312 {target} {nick} {server} :IRIS IRC server\r\n"
+                )
+            }
+            Reply::WhoisOperator(r) => {
+                let target = &r.target_nick;
+                let nick = &r.nick;
+                write!(fmt, ":{server} 313 {target} {nick} :is an IRC operator\r\n")
+            }
+            Reply::WhoisActually(r) => {
+                let target = &r.target_nick;
+                let nick = &r.nick;
+                let ip = &r.ip;
+                write!(
+                    fmt,
+                    ":{server} 338 {target} {nick} {ip} :actually using host\r\n"
+                )
+            }
+            Reply::WhoisCertfp(r) => {
+                let target = &r.target_nick;
+                let nick = &r.nick;
+                let certfp = &r.certfp;
+                write!(
+                    fmt,
+                    ":{server} 276 {target} {nick} :has client certificate fingerprint {certfp}\r\n"
+                )
+            }
+            Reply::WhoisAccount(r) => {
+                let target = &r.target_nick;
+                let nick = &r.nick;
+                let account = &r.account;
+                write!(
+                    fmt,
+                    ":{server} 330 {target} {nick} {account} :is logged in as\r\n"
+                )
+            }
+            Reply::EndOfWhois(r) => {
+                let target = &r.target_nick;
+                let nick = &r.nick;
+                write!(fmt, ":{server} 318 {target} {nick} :End of /WHOIS list\r\n")
+            }
+            Reply::Cap(r) => {
+                let target = &r.target_nick;
+                let subcommand = &r.subcommand;
+                let caps = &r.caps;
+                write!(fmt, ":{server} CAP {target} {subcommand} :{caps}\r\n")
+            }
+            Reply::Authenticate(data) => write!(fmt, "AUTHENTICATE {data}\r\n"),
+            Reply::LoggedIn(r) => {
+                let target = &r.target_nick;
+                let prefix = &r.prefix;
+                let account = &r.account;
+                write!(
+                    fmt,
+                    ":{server} 900 {target} {prefix} {account} :You are now logged in as {account}\r\n"
+                )
+            }
+            Reply::SaslSuccess(r) => {
+                let target = &r.target_nick;
+                write!(
+                    fmt,
+                    ":{server} 903 {target} :SASL authentication successful\r\n"
+                )
+            }
+            Reply::SaslMechs(r) => {
+                let target = &r.target_nick;
+                let mechanisms = &r.mechanisms;
+                write!(
+                    fmt,
+                    ":{server} 908 {target} {mechanisms} :are available SASL mechanisms\r\n"
+                )
+            }
+            Reply::ErrorMsg(message) => write!(fmt, "ERROR :{message}\r\n"),
         }
     }
 }
@@ -434,7 +1405,7 @@ mod tests {
         assert_eq!(
             ParsedMessage::try_from(UnparsedMessage {
                 message: "PING :host-name with space\r\n",
-                sender_nick: Nick("Person".to_string())
+                sender: UserId(1)
             })
             .unwrap()
             .message,
@@ -447,7 +1418,7 @@ mod tests {
         assert_eq!(
             ParsedMessage::try_from(UnparsedMessage {
                 message: "PRIVMSG tom :Hi Tom, how are you?\r\n",
-                sender_nick: Nick("Person".to_string())
+                sender: UserId(1)
             })
             .unwrap()
             .message,
@@ -463,7 +1434,7 @@ mod tests {
         assert_eq!(
             ParsedMessage::try_from(UnparsedMessage {
                 message: "NICK tfpk\r\n",
-                sender_nick: Nick("Person".to_string())
+                sender: UserId(1)
             })
             .unwrap()
             .message,
@@ -474,9 +1445,178 @@ mod tests {
         assert_eq!(
             ParsedMessage::try_from(UnparsedMessage {
                 message: "NICK tfpkasdfasdfasdf\r\n",
-                sender_nick: Nick("Person".to_string())
+                sender: UserId(1)
+            }),
+            Err(ErrorType::ErroneousNickname("tfpkasdfasdfasdf".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_user() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "USER to!m@x 0 * :Tom Smith\r\n",
+                sender: UserId(1)
+            })
+            .unwrap()
+            .message,
+            Message::User(UserMsg {
+                username: "tomx".to_string(),
+                real_name: "Tom Smith".to_string(),
+            })
+        );
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "USER @ 0 * :Tom\r\n",
+                sender: UserId(1)
+            }),
+            Err(ErrorType::NeedMoreParams("USER".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_cap() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "CAP req :multi-prefix sasl\r\n",
+                sender: UserId(1)
+            })
+            .unwrap()
+            .message,
+            Message::Cap(CapMsg {
+                subcommand: "REQ".to_string(),
+                arg: Some("multi-prefix sasl".to_string()),
+            })
+        );
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "CAP\r\n",
+                sender: UserId(1)
             }),
-            Err(ErrorType::ErroneousNickname)
+            Err(ErrorType::NeedMoreParams("CAP".to_string()))
         );
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "PASS :secret pass\r\n",
+                sender: UserId(1)
+            })
+            .unwrap()
+            .message,
+            Message::Pass(PassMsg {
+                password: "secret pass".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_webirc() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "WEBIRC hunter2 kiwi user.example.com 192.0.2.1 :secure\r\n",
+                sender: UserId(1)
+            })
+            .unwrap()
+            .message,
+            Message::Webirc(WebircMsg {
+                password: "hunter2".to_string(),
+                gateway: "kiwi".to_string(),
+                hostname: "user.example.com".to_string(),
+                ip: "192.0.2.1".to_string(),
+            })
+        );
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "WEBIRC hunter2 kiwi user.example.com\r\n",
+                sender: UserId(1)
+            }),
+            Err(ErrorType::NeedMoreParams("WEBIRC".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_error_reply() {
+        let reply = |nick: &str, error| {
+            Reply::Error(ErrorReply {
+                target_nick: Nick(nick.to_string()),
+                error,
+            })
+            .to_string()
+        };
+
+        assert_eq!(
+            reply("me", ErrorType::NoSuchNick("bob".to_string())),
+            ":iris-server 401 me bob :No such nick/channel\r\n"
+        );
+        assert_eq!(
+            reply("*", ErrorType::NotRegistered),
+            ":iris-server 451 * :You have not registered\r\n"
+        );
+        assert_eq!(
+            reply(
+                "me",
+                ErrorType::UserNotInChannel("bob".to_string(), "#team".to_string())
+            ),
+            ":iris-server 441 me bob #team :They aren't on that channel\r\n"
+        );
+        assert_eq!(
+            reply(
+                "me",
+                ErrorType::UserOnChannel("bob".to_string(), "#team".to_string())
+            ),
+            ":iris-server 443 me bob #team :is already on channel\r\n"
+        );
+        assert_eq!(
+            reply("me", ErrorType::InviteOnlyChan("#team".to_string())),
+            ":iris-server 473 me #team :Cannot join channel (+i)\r\n"
+        );
+        assert_eq!(
+            reply("me", ErrorType::BannedFromChan("#team".to_string())),
+            ":iris-server 474 me #team :Cannot join channel (+b)\r\n"
+        );
+        assert_eq!(
+            reply("me", ErrorType::BadChannelKey("#team".to_string())),
+            ":iris-server 475 me #team :Cannot join channel (+k)\r\n"
+        );
+        assert_eq!(
+            reply("me", ErrorType::ChanOPrivsNeeded("#team".to_string())),
+            ":iris-server 482 me #team :You're not channel operator\r\n"
+        );
+        assert_eq!(
+            reply("me", ErrorType::UnknownMode('x', "#team".to_string())),
+            ":iris-server 472 me x :is unknown mode char to me for #team\r\n"
+        );
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "FOO bar\r\n",
+                sender: UserId(1)
+            }),
+            Err(ErrorType::UnknownCommand("FOO".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_prefix() {
+        let prefix = Prefix::new(Nick("tom".to_string()), "tom", "ABC.IP");
+        assert_eq!(prefix.to_string(), "tom!tom@ABC.IP");
+        assert_eq!(
+            Reply::Nick(NickReply {
+                sender: prefix,
+                nick: Nick("tim".to_string()),
+            })
+            .to_string(),
+            ":tom!tom@ABC.IP NICK :tim\r\n"
+        );
+    }
+
+    #[test]
+    fn test_nick_casemapping() {
+        use std::collections::HashSet;
+
+        assert_eq!(Nick("Alice".to_string()), Nick("aLICE".to_string()));
+        assert_ne!(Nick("Alice".to_string()), Nick("Alice2".to_string()));
+        assert_eq!(Channel("#Team".to_string()), Channel("#team".to_string()));
+
+        let nicks = HashSet::from([Nick("Bob".to_string()), Nick("bob".to_string())]);
+        assert_eq!(nicks.len(), 1);
     }
 }
@@ -0,0 +1,378 @@
+//! Upgrades without disconnecting anyone
+//!
+//! On SIGUSR2 the server execs its binary again, usually a build that was
+//! just deployed over it, and hands everything over to the new process:
+//!
+//! 1. The listeners stop accepting. Clients that connect meanwhile wait in
+//!    the backlog, for the new process to accept.
+//! 2. Every connection stops reading, and the message handler finishes what
+//!    was already read. Plaintext connections stop writing too, leaving the
+//!    rest of their SendQ for the new process to write.
+//! 3. The users, their connections and the channels are written to an
+//!    anonymous in-memory file, so nobody else can read the passwords in it,
+//!    or change it on the way. It is kept open across the exec, like the
+//!    sockets, and the new process is told its fd in `IRIS_UPGRADE_STATE`.
+//! 4. The new process reads the state, before it starts any threads, and
+//!    picks every connection up where it was, so the clients notice nothing.
+//!
+//! TLS and WebSocket connections can't be handed over, as their sessions
+//! only exist in the process that started them. Those users are asked to
+//! reconnect, and disconnected like for a shutdown.
+use crate::{
+    channel_list::ChannelList,
+    config::ServerConfig,
+    connect::{ConnectionState, Handover, ListenerState},
+    massage_sender::disconnect_user,
+    plugin,
+    types::{QuitMsg, Reply, UserId},
+    user::{UserList, UserState},
+};
+use anyhow::{anyhow, Context, Error, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    fs::File,
+    io::{self, Read, Seek, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    time::Instant,
+};
+
+/// The environment variable with the fd of the state file.
+pub const STATE_ENV: &str = "IRIS_UPGRADE_STATE";
+
+/// Everything the new process needs to pick up where the old one was.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpgradeState {
+    /// The id given to the last client that connected, so no id is given out twice.
+    pub last_id: UserId,
+    pub listeners: Vec<ListenerState>,
+    pub users: Vec<HandedOverUser>,
+    pub channels: Vec<ChannelState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HandedOverUser {
+    pub user: UserState,
+    pub connection: ConnectionState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelState {
+    /// The name the channel was created with.
+    pub name: String,
+    pub members: Vec<UserId>,
+}
+
+/// The binary to exec: the one the server was started from, which has
+/// usually been replaced by a new build since.
+///
+/// Fails if there is nothing there anymore.
+pub fn executable() -> Result<PathBuf> {
+    let exe = std::env::current_exe().context("Failed to find the server's binary")?;
+
+    // Linux marks a binary that was replaced after it started as deleted
+    let exe = match exe
+        .to_str()
+        .and_then(|path| path.strip_suffix(" (deleted)"))
+    {
+        Some(path) => PathBuf::from(path),
+        None => exe,
+    };
+    if !exe.is_file() {
+        return Err(anyhow!(
+            "There is no binary at {} to upgrade to",
+            exe.display()
+        ));
+    }
+
+    Ok(exe)
+}
+
+/// Take every user and channel for the new process.
+///
+/// Every connection must have been told with `User::start_handover`, giving
+/// `handovers`, and the message handler must have stopped. Plugins save their
+/// state first, while the users are still there. Users whose connection
+/// can't be handed over are told to reconnect and disconnected. This returns
+/// once they have been sent what they had left, or `config.shutdown_grace`
+/// has passed.
+///
+/// This waits on the connections, so it isn't for the async runtime's threads.
+pub fn hand_over(
+    user_list: &mut UserList,
+    channel_list: &mut ChannelList,
+    config: &ServerConfig,
+    handovers: Vec<(UserId, Handover)>,
+    listeners: Vec<ListenerState>,
+    last_id: UserId,
+) -> UpgradeState {
+    if let Some(path) = &config.plugin_state_file {
+        if let Err(err) = plugin::save_state(path, user_list) {
+            error!("Failed to save the plugin state: {:#}", err);
+        }
+    }
+
+    let deadline = Instant::now() + config.shutdown_grace;
+    let mut handovers = handovers.into_iter().collect::<HashMap<_, _>>();
+
+    // Wait for every connection first, without holding on to the users,
+    // which their readers may need to stop
+    let mut ready = Vec::new();
+    let mut left_behind = Vec::new();
+    for user in user_list.all_users() {
+        let id = user.lock().expect("Failed to lock user").get_id();
+        match handovers.remove(&id) {
+            Some(handover) if handover.wait(deadline) => ready.push(user),
+            _ => left_behind.push(id),
+        }
+    }
+
+    let mut closed = Vec::new();
+    for id in left_behind {
+        let Some(user) = user_list.get(id) else {
+            continue;
+        };
+        let mut user = user.lock().expect("Failed to lock user");
+        let _ = user.send(Reply::ErrorMsg(
+            "Server upgrading, please reconnect".to_owned(),
+        ));
+        closed.push(user.closed());
+        drop(user);
+
+        let quit_msg = QuitMsg {
+            message: Some("Server upgrading".to_owned()),
+        };
+        if let Err(err) = disconnect_user(user_list, channel_list, id, quit_msg) {
+            warn!("Failed to disconnect {}: {}", id, err);
+        }
+    }
+
+    // Taken last, so the QUITs for those left behind go along
+    let mut users = Vec::new();
+    for user in ready {
+        let mut user = user.lock().expect("Failed to lock user");
+        match user.take_handover() {
+            Some(connection) => users.push(HandedOverUser {
+                user: user.to_state(),
+                connection,
+            }),
+            None => {
+                // its reader and writer have stopped, so there is nothing left to do
+                warn!("Failed to hand over {}", user.get_nick());
+                user.close();
+            }
+        }
+    }
+
+    let channels = channel_list
+        .channels()
+        .map(|(name, members)| ChannelState {
+            name: name.to_owned(),
+            members: members
+                .iter()
+                .copied()
+                .filter(|id| users.iter().any(|handed_over| handed_over.user.id == *id))
+                .collect(),
+        })
+        .filter(|channel| !channel.members.is_empty())
+        .collect();
+
+    for closed in closed {
+        closed.wait(deadline);
+    }
+
+    UpgradeState {
+        last_id,
+        listeners,
+        users,
+        channels,
+    }
+}
+
+/// Write `state` to the state file, and exec `exe` with the arguments the
+/// server was started with.
+///
+/// This only returns if it failed. The fds in `state` are still this
+/// process's then, to pick up again.
+pub fn exec(exe: &Path, state: &UpgradeState) -> Error {
+    let file = match write_state(state) {
+        Ok(file) => file,
+        Err(err) => return err.context("Failed to write the upgrade state"),
+    };
+
+    info!(
+        "Upgrading to {}, handing over {} connections",
+        exe.display(),
+        state.users.len()
+    );
+
+    // Everything handed over is kept open across the exec, and only then,
+    // so nothing leaks into another process if this fails
+    let fd = file.as_raw_fd();
+    let fds = state
+        .listeners
+        .iter()
+        .map(|listener| listener.fd)
+        .chain(state.users.iter().map(|user| user.connection.fd))
+        .chain([fd])
+        .collect::<Vec<_>>();
+    let err = match set_cloexec(&fds, false) {
+        Ok(()) => {
+            let mut args = std::env::args_os();
+            let mut command = Command::new(exe);
+            if let Some(arg0) = args.next() {
+                command.arg0(arg0);
+            }
+            let err = command.args(args).env(STATE_ENV, fd.to_string()).exec();
+            anyhow!(err).context(format!("Failed to exec {}", exe.display()))
+        }
+        Err(err) => anyhow!(err).context("Failed to keep the connections open"),
+    };
+
+    if let Err(err) = set_cloexec(&fds, true) {
+        warn!("Failed to close the connections on exec again: {}", err);
+    }
+    err
+}
+
+fn set_cloexec(fds: &[RawFd], cloexec: bool) -> io::Result<()> {
+    let flags = if cloexec { libc::FD_CLOEXEC } else { 0 };
+    for &fd in fds {
+        // SAFETY: only changes the flags of an fd this process owns
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Write `state` to an anonymous file, which is only ever reachable through
+/// its fd.
+fn write_state(state: &UpgradeState) -> Result<File> {
+    const NAME: &CStr = c"iris-upgrade";
+
+    let contents = toml::to_string(state)?;
+
+    // SAFETY: `NAME` is a valid C string
+    let fd = unsafe { libc::memfd_create(NAME.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // SAFETY: the fd was just created, and nothing else owns it
+    let mut file = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    file.write_all(contents.as_bytes())?;
+
+    Ok(file)
+}
+
+/// Read the state the process that ran before left, if this process was
+/// started by an upgrade.
+///
+/// This changes the environment, which isn't safe once there are other
+/// threads, so it must be called before the async runtime starts.
+pub fn take_state() -> Result<Option<UpgradeState>> {
+    let Some(fd) = std::env::var_os(STATE_ENV) else {
+        return Ok(None);
+    };
+    // a later RESTART is a fresh start
+    std::env::remove_var(STATE_ENV);
+
+    let fd = fd
+        .to_str()
+        .and_then(|fd| fd.parse::<RawFd>().ok())
+        .ok_or_else(|| anyhow!("Invalid {STATE_ENV}: {fd:?}"))?;
+    // SAFETY: the process that ran before handed the file over for us to own
+    let mut file = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+    let mut contents = String::new();
+    file.rewind()
+        .and_then(|_| file.read_to_string(&mut contents))
+        .context("Failed to read the upgrade state")?;
+
+    let state = toml::from_str(&contents).context("Invalid upgrade state")?;
+
+    Ok(Some(state))
+}
+
+/// Put the channels of the process that ran before back together.
+pub fn restore_channels(channel_list: &mut ChannelList, channels: Vec<ChannelState>) {
+    for channel in channels {
+        for id in channel.members {
+            channel_list.join_channel(&channel.name, id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::RegistrationState;
+
+    #[test]
+    fn test_state_file() {
+        let state = UpgradeState {
+            last_id: UserId(8),
+            listeners: vec![ListenerState {
+                address: "[::]:6667".to_owned(),
+                fd: 5,
+            }],
+            users: vec![HandedOverUser {
+                user: UserState {
+                    id: UserId(7),
+                    nick: Some("alice".to_owned()),
+                    username: Some("alice".to_owned()),
+                    real_name: Some("Alice \"A\" Liddell".to_owned()),
+                    registration: RegistrationState::Registered,
+                    password: None,
+                    caps: vec!["sasl".to_owned()],
+                    account: None,
+                    host: "1a2b3c.cloak".to_owned(),
+                    gateway: None,
+                    joined_channels: vec!["#Team".to_owned()],
+                    oper_name: None,
+                    wallops: true,
+                },
+                connection: ConnectionState {
+                    fd: 9,
+                    addr: "[2001:db8::1]:52000".parse().unwrap(),
+                    listener: "[::]:6667".to_owned(),
+                    peer_uid: None,
+                    input: vec!["PRIVMSG #Team :hi".to_owned()],
+                    partial_input: b"PING :x\r\nPRIV".to_vec(),
+                    output: vec![":bob!bob@host PRIVMSG #Team :hey\r\n".to_owned()],
+                },
+            }],
+            channels: vec![ChannelState {
+                name: "#Team".to_owned(),
+                members: vec![UserId(7)],
+            }],
+        };
+
+        let contents = toml::to_string(&state).unwrap();
+        assert_eq!(toml::from_str::<UpgradeState>(&contents).unwrap(), state);
+    }
+
+    #[test]
+    fn test_restore_channels() {
+        let mut channel_list = ChannelList::new();
+        restore_channels(
+            &mut channel_list,
+            vec![ChannelState {
+                name: "#Team".to_owned(),
+                members: vec![UserId(1), UserId(2)],
+            }],
+        );
+
+        assert_eq!(channel_list.get_name("#team"), Some("#Team"));
+        assert!(channel_list.has_user("#team", UserId(1)));
+        assert!(channel_list.has_user("#team", UserId(2)));
+    }
+}
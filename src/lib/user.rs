@@ -3,22 +3,83 @@
 //! This module contains the User struct which is used to keep track of
 //! information about a user.
 use crate::{
-    connect::ConnectionWrite,
-    types::{ErrorType, Nick, Reply},
+    casemapping::casemapping,
+    connect::{Closed, ConnectionState, ConnectionWrite, Handover},
+    limits::LimitError,
+    types::{ErrorReply, ErrorType, Nick, Prefix, Reply, UserId},
 };
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     fmt::Debug,
-    sync::{Arc, Mutex},
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Duration,
 };
+use tokio::time::Instant;
+
+/// How far a connection has got with registering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RegistrationState {
+    /// Waiting for `NICK` and `USER`, which may come in either order.
+    Connecting,
+    /// The client started capability negotiation, and registration
+    /// waits until it sends `CAP END`.
+    CapNegotiating,
+    /// The user may use every command.
+    Registered,
+}
 
 /// This struct is used to keep track of information about a user.
 pub struct User {
-    id: String,
+    id: UserId,
     connection_write: ConnectionWrite,
     nick: Option<String>,
+    username: Option<String>,
     real_name: Option<String>,
+    registration: RegistrationState,
+    /// The password given in `PASS`.
+    password: Option<String>,
+    /// Capabilities enabled with `CAP REQ`.
+    caps: Vec<String>,
+    /// The account the user logged in to, with SASL.
+    account: Option<String>,
+    /// The user has picked a SASL mechanism, and the server waits for their data.
+    authenticating: bool,
+    /// The host shown to other users, a cloak of the IP address.
+    host: String,
+    /// The WEBIRC gateway the user came through.
+    gateway: Option<String>,
     joined_channels: Vec<String>,
+    connected_at: Instant,
+    last_activity: Instant,
+    ping_sent_at: Option<Instant>,
+    /// A `QUIT` for the user is on its way to the message handler, e.g.
+    /// after a timeout, so nothing more needs to be done about them.
+    quitting: bool,
+    oper_name: Option<String>,
+    wallops: bool,
+}
+
+/// A user handed over to a new copy of the server, see `upgrade`.
+///
+/// A SASL exchange that was going on has to be started again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserState {
+    pub id: UserId,
+    pub nick: Option<String>,
+    pub username: Option<String>,
+    pub real_name: Option<String>,
+    pub registration: RegistrationState,
+    pub password: Option<String>,
+    pub caps: Vec<String>,
+    pub account: Option<String>,
+    pub host: String,
+    pub gateway: Option<String>,
+    pub joined_channels: Vec<String>,
+    pub oper_name: Option<String>,
+    pub wallops: bool,
 }
 
 impl Debug for User {
@@ -27,47 +88,147 @@ impl Debug for User {
             .field("id", &self.id)
             .field("nick", &self.nick)
             .field("real_name", &self.real_name)
+            .field("registration", &self.registration)
+            .field("account", &self.account)
+            .field("host", &self.host)
+            .field("oper_name", &self.oper_name)
             .finish()
     }
 }
 
 impl User {
-    pub fn new(id: String, connection_write: ConnectionWrite) -> Self {
+    pub fn new(id: UserId, connection_write: ConnectionWrite, host: String) -> Self {
         Self {
             id,
             connection_write,
             nick: None,
+            username: None,
             real_name: None,
+            registration: RegistrationState::Connecting,
+            password: None,
+            caps: Vec::new(),
+            account: None,
+            authenticating: false,
+            host,
+            gateway: None,
             joined_channels: Vec::new(),
+            connected_at: Instant::now(),
+            last_activity: Instant::now(),
+            ping_sent_at: None,
+            quitting: false,
+            oper_name: None,
+            wallops: false,
         }
     }
 
-    pub fn get_id(&self) -> String {
-        self.id.clone()
+    pub fn get_id(&self) -> UserId {
+        self.id
     }
 
     pub fn get_nick(&self) -> Nick {
         match &self.nick {
             Some(nick) => Nick(nick.clone()),
-            None => Nick(self.get_id()),
+            None => Nick(self.id.to_string()),
         }
     }
 
     pub fn get_real_name(&self) -> Nick {
         match &self.real_name {
             Some(real_name) => Nick(real_name.clone()),
-            None => Nick(self.get_id()),
+            None => Nick(self.id.to_string()),
         }
     }
 
-    pub fn set_nick(&mut self, nick: String) {
+    /// Only `UserList::set_nick` changes a nick, so the user can still be found by it.
+    fn set_nick(&mut self, nick: String) {
         self.nick = Some(nick);
     }
 
+    /// The username given in `USER`, if the user has sent it.
+    pub fn get_username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    pub fn set_username(&mut self, username: String) {
+        self.username = Some(username);
+    }
+
     pub fn set_real_name(&mut self, real_name: String) {
         self.real_name = Some(real_name);
     }
 
+    /// The host shown to other users. Unlike `get_ip`, this is safe to show anyone.
+    pub fn get_host(&self) -> &str {
+        &self.host
+    }
+
+    /// The source of messages from the user, `nick!user@host`.
+    pub fn get_prefix(&self) -> Prefix {
+        Prefix::new(
+            self.get_nick(),
+            self.get_username().unwrap_or("*"),
+            self.get_host(),
+        )
+    }
+
+    /// Whether the user connected over TLS.
+    pub fn is_secure(&self) -> bool {
+        self.connection_write.is_tls()
+    }
+
+    /// The fingerprint of the user's TLS client certificate, if they showed one.
+    pub fn get_certfp(&self) -> Option<String> {
+        self.connection_write.certfp()
+    }
+
+    pub fn get_account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
+    pub fn set_account(&mut self, account: String) {
+        self.account = Some(account);
+    }
+
+    pub fn is_authenticating(&self) -> bool {
+        self.authenticating
+    }
+
+    pub fn set_authenticating(&mut self, authenticating: bool) {
+        self.authenticating = authenticating;
+    }
+
+    /// The IP address the user connected from. Only operators may see it.
+    pub fn get_ip(&self) -> IpAddr {
+        self.connection_write.ip()
+    }
+
+    /// The IP address bans are matched against: none for a user on a Unix
+    /// socket, who is known by their host instead.
+    pub fn get_remote_ip(&self) -> Option<IpAddr> {
+        self.connection_write.remote_ip()
+    }
+
+    pub fn get_gateway(&self) -> Option<&str> {
+        self.gateway.as_deref()
+    }
+
+    /// Record that the user came through a WEBIRC gateway, and is really
+    /// at `ip`, shown to others as `host`.
+    ///
+    /// Fails if the user's real host has no room in the connection limits.
+    pub fn set_gateway(
+        &mut self,
+        gateway: String,
+        ip: IpAddr,
+        host: String,
+    ) -> Result<(), LimitError> {
+        self.connection_write.set_ip(ip)?;
+        self.gateway = Some(gateway);
+        self.host = host;
+
+        Ok(())
+    }
+
     pub fn is_set_nick(&self) -> bool {
         self.nick.is_some()
     }
@@ -76,6 +237,53 @@ impl User {
         self.real_name.is_some()
     }
 
+    pub fn get_registration(&self) -> RegistrationState {
+        self.registration
+    }
+
+    pub fn set_registration(&mut self, registration: RegistrationState) {
+        self.registration = registration;
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.registration == RegistrationState::Registered
+    }
+
+    pub fn get_password(&self) -> Option<&str> {
+        self.password.as_deref()
+    }
+
+    pub fn set_password(&mut self, password: String) {
+        self.password = Some(password);
+    }
+
+    pub fn get_caps(&self) -> &Vec<String> {
+        &self.caps
+    }
+
+    pub fn enable_cap(&mut self, cap: &str) {
+        if !self.has_cap(cap) {
+            self.caps.push(cap.to_owned());
+        }
+    }
+
+    pub fn disable_cap(&mut self, cap: &str) {
+        self.caps.retain(|enabled| enabled != cap);
+    }
+
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.iter().any(|enabled| enabled == cap)
+    }
+
+    /// The nick to address replies to, `*` for users that haven't picked one yet.
+    pub fn get_reply_nick(&self) -> Nick {
+        if self.is_set_nick() {
+            self.get_nick()
+        } else {
+            Nick("*".to_owned())
+        }
+    }
+
     pub fn send(&mut self, reply: Reply) -> Result<()> {
         self.connection_write.write_message(&format!("{}", reply))?;
 
@@ -83,10 +291,10 @@ impl User {
     }
 
     pub fn send_back_error(&mut self, err: ErrorType) -> Result<()> {
-        self.connection_write
-            .write_message(&format!("{}\r\n", err))?;
-
-        Ok(())
+        self.send(Reply::Error(ErrorReply {
+            target_nick: self.get_reply_nick(),
+            error: err,
+        }))
     }
 
     pub fn join_channel(&mut self, channel_name: &str) {
@@ -94,22 +302,179 @@ impl User {
     }
 
     pub fn part_channel(&mut self, channel_name: &str) {
-        self.joined_channels.retain(|name| name != channel_name);
+        self.joined_channels
+            .retain(|name| !casemapping().equals(name, channel_name));
     }
 
     pub fn get_joined_channels(&self) -> &Vec<String> {
         &self.joined_channels
     }
+
+    /// Record that the user has sent us something, which also answers any outstanding `PING`.
+    pub fn touch(&mut self) {
+        self.last_activity = Instant::now();
+        self.ping_sent_at = None;
+    }
+
+    /// Record that the server has sent the user a `PING`.
+    pub fn ping_sent(&mut self) {
+        self.ping_sent_at = Some(Instant::now());
+    }
+
+    /// How long ago the user connected.
+    pub fn connected_for(&self) -> Duration {
+        self.connected_at.elapsed()
+    }
+
+    /// How long ago the user last sent us something.
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    /// How long the user's outstanding `PING` has been waiting for an answer, if any.
+    pub fn ping_sent_for(&self) -> Option<Duration> {
+        self.ping_sent_at.map(|sent_at| sent_at.elapsed())
+    }
+
+    /// Record that a `QUIT` for the user was handed to the message handler.
+    pub fn set_quitting(&mut self) {
+        self.quitting = true;
+    }
+
+    /// Whether a `QUIT` for the user is already on its way, see `set_quitting`.
+    pub fn is_quitting(&self) -> bool {
+        self.quitting
+    }
+
+    /// Make the user an IRC operator, logged in as the given operator name.
+    pub fn set_oper(&mut self, oper_name: Option<String>) {
+        self.oper_name = oper_name;
+    }
+
+    pub fn is_oper(&self) -> bool {
+        self.oper_name.is_some()
+    }
+
+    /// The operator name the user logged in with, if they are an operator.
+    pub fn get_oper_name(&self) -> Option<&str> {
+        self.oper_name.as_deref()
+    }
+
+    /// Set whether the user receives `WALLOPS` (user mode `+w`).
+    pub fn set_wallops(&mut self, wallops: bool) {
+        self.wallops = wallops;
+    }
+
+    pub fn is_wallops(&self) -> bool {
+        self.wallops
+    }
+
+    /// The user's modes, e.g. `+ow`.
+    pub fn get_modes(&self) -> String {
+        let mut modes = String::from("+");
+        if self.is_oper() {
+            modes.push('o');
+        }
+        if self.is_wallops() {
+            modes.push('w');
+        }
+        modes
+    }
+
+    /// Close the user's connection, which also stops their reader task.
+    ///
+    /// Anything already sent to the user is still written out first.
+    pub fn close(&mut self) {
+        self.connection_write.shutdown();
+    }
+
+    /// Something to wait for the user's connection to be closed with.
+    pub fn closed(&self) -> Closed {
+        self.connection_write.closed()
+    }
+
+    /// Start handing the user's connection over to a new copy of the server,
+    /// see `ConnectionWrite::start_handover`.
+    pub fn start_handover(&mut self) -> Handover {
+        self.connection_write.start_handover()
+    }
+
+    /// Take what the new copy of the server needs to pick the user's
+    /// connection up, see `ConnectionWrite::take_handover`.
+    pub fn take_handover(&mut self) -> Option<ConnectionState> {
+        self.connection_write.take_handover()
+    }
+
+    /// What a new copy of the server needs to know about the user.
+    pub fn to_state(&self) -> UserState {
+        UserState {
+            id: self.id,
+            nick: self.nick.clone(),
+            username: self.username.clone(),
+            real_name: self.real_name.clone(),
+            registration: self.registration,
+            password: self.password.clone(),
+            caps: self.caps.clone(),
+            account: self.account.clone(),
+            host: self.host.clone(),
+            gateway: self.gateway.clone(),
+            joined_channels: self.joined_channels.clone(),
+            oper_name: self.oper_name.clone(),
+            wallops: self.wallops,
+        }
+    }
+
+    /// Pick up a user the server that ran before handed over.
+    pub fn from_state(state: UserState, connection_write: ConnectionWrite) -> Self {
+        Self {
+            nick: state.nick,
+            username: state.username,
+            real_name: state.real_name,
+            registration: state.registration,
+            password: state.password,
+            caps: state.caps,
+            account: state.account,
+            gateway: state.gateway,
+            joined_channels: state.joined_channels,
+            oper_name: state.oper_name,
+            wallops: state.wallops,
+            ..Self::new(state.id, connection_write, state.host)
+        }
+    }
+
+    /// Take the messages sent to the user so far, see `ConnectionWrite::for_test`.
+    #[cfg(test)]
+    pub(crate) fn take_sent(&mut self) -> Vec<String> {
+        self.connection_write.take_queued()
+    }
 }
 
+/// Every connected user, found by id or by nick.
+///
+/// Each user has a lock of their own, so handling one user's message doesn't
+/// hold up anyone else's. The indexes are only locked for as long as a lookup
+/// or a change takes.
+///
+/// The locks are always taken in the same order, a user before the indexes:
+/// `set_nick` and `remove_user` lock the indexes while their caller holds the
+/// user. So never lock a user while holding the indexes; `get`, `find` and
+/// `all_users` let go of them before handing out any user.
 pub struct UserList {
-    users: Arc<Mutex<Vec<User>>>,
+    index: Arc<RwLock<UserIndex>>,
+}
+
+#[derive(Default)]
+struct UserIndex {
+    by_id: HashMap<UserId, Arc<Mutex<User>>>,
+    /// The ids of users that have picked a nick, by that nick.
+    /// `Nick` compares and hashes with the server's casemapping.
+    by_nick: HashMap<Nick, UserId>,
 }
 
 impl Clone for UserList {
     fn clone(&self) -> Self {
         Self {
-            users: self.users.clone(),
+            index: self.index.clone(),
         }
     }
 }
@@ -123,15 +488,266 @@ impl Default for UserList {
 impl UserList {
     pub fn new() -> Self {
         Self {
-            users: Arc::new(Mutex::new(Vec::new())),
+            index: Arc::new(RwLock::new(UserIndex::default())),
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, UserIndex> {
+        self.index.read().expect("Failed to lock users")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, UserIndex> {
+        self.index.write().expect("Failed to lock users")
+    }
+
+    /// Add a user. A newly connected one hasn't picked a nick yet, but one
+    /// handed over by the server that ran before may have.
+    pub fn add_user(&self, user: User) {
+        let mut index = self.write();
+        let id = user.get_id();
+
+        if user.is_set_nick() {
+            index.by_nick.insert(user.get_nick(), id);
+        }
+        index.by_id.insert(id, Arc::new(Mutex::new(user)));
+    }
+
+    /// Find a user by their id.
+    pub fn get(&self, id: UserId) -> Option<Arc<Mutex<User>>> {
+        self.read().by_id.get(&id).cloned()
+    }
+
+    /// Find a user by their nick. Users that haven't picked one yet can't be found.
+    pub fn find(&self, nick: &Nick) -> Option<Arc<Mutex<User>>> {
+        let index = self.read();
+        let id = index.by_nick.get(nick)?;
+        index.by_id.get(id).cloned()
+    }
+
+    /// Give a user a new nick, which may only differ in case from their current one.
+    ///
+    /// Returns `false`, leaving the user as they were, if someone else has the nick.
+    pub fn set_nick(&self, user: &mut User, nick: Nick) -> bool {
+        let mut index = self.write();
+        let id = user.get_id();
+
+        if index.by_nick.get(&nick).is_some_and(|other| *other != id) {
+            return false;
+        }
+
+        if user.is_set_nick() {
+            index.by_nick.remove(&user.get_nick());
+        }
+        user.set_nick(nick.0.clone());
+        index.by_nick.insert(nick, id);
+
+        true
+    }
+
+    /// Remove a user, so they can't be found anymore.
+    pub fn remove_user(&self, user: &User) {
+        let mut index = self.write();
+        let id = user.get_id();
+
+        index.by_id.remove(&id);
+        if user.is_set_nick() && index.by_nick.get(&user.get_nick()) == Some(&id) {
+            index.by_nick.remove(&user.get_nick());
         }
     }
 
-    pub fn add_user(&mut self, user: User) {
-        self.users.lock().expect("Failed to lock users").push(user);
+    /// Every user connected right now.
+    pub fn all_users(&self) -> Vec<Arc<Mutex<User>>> {
+        self.read().by_id.values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel_list::ChannelList;
+
+    fn new_user(id: u64) -> User {
+        let id = UserId(id);
+        let addr = "10.0.0.1:50000".parse().unwrap();
+        User::new(id, ConnectionWrite::for_test(addr, id), "host".to_owned())
+    }
+
+    fn nick(nick: &str) -> Nick {
+        Nick(nick.to_owned())
+    }
+
+    /// Add a new user, and give them `name` as their nick.
+    fn add_with_nick(user_list: &UserList, id: u64, name: &str) -> Arc<Mutex<User>> {
+        user_list.add_user(new_user(id));
+        let user = user_list.get(UserId(id)).unwrap();
+        assert!(user_list.set_nick(&mut user.lock().unwrap(), nick(name)));
+        user
+    }
+
+    fn found_id(user_list: &UserList, name: &str) -> Option<UserId> {
+        user_list
+            .find(&nick(name))
+            .map(|user| user.lock().unwrap().get_id())
+    }
+
+    #[test]
+    fn test_add_user() {
+        let user_list = UserList::new();
+        user_list.add_user(new_user(1));
+
+        // a user without a nick yet can only be found by id
+        assert_eq!(
+            user_list.get(UserId(1)).unwrap().lock().unwrap().get_id(),
+            UserId(1)
+        );
+        assert!(user_list.get(UserId(2)).is_none());
+        assert!(user_list.find(&nick("*1")).is_none());
+        assert_eq!(user_list.all_users().len(), 1);
+
+        // one handed over with a nick can be found by it straight away
+        let mut user = new_user(2);
+        user.set_nick("alice".to_owned());
+        user_list.add_user(user);
+        assert_eq!(found_id(&user_list, "alice"), Some(UserId(2)));
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let user_list = UserList::new();
+        add_with_nick(&user_list, 1, "Alice");
+
+        assert_eq!(found_id(&user_list, "alice"), Some(UserId(1)));
+        assert_eq!(found_id(&user_list, "ALICE"), Some(UserId(1)));
+        assert_eq!(found_id(&user_list, "bob"), None);
+    }
+
+    #[test]
+    fn test_nick_collision() {
+        let user_list = UserList::new();
+        add_with_nick(&user_list, 1, "alice");
+        let bob = add_with_nick(&user_list, 2, "bob");
+
+        let mut bob = bob.lock().unwrap();
+        assert!(!user_list.set_nick(&mut bob, nick("ALICE")));
+        assert_eq!(bob.get_nick(), nick("bob"));
+        drop(bob);
+        assert_eq!(found_id(&user_list, "alice"), Some(UserId(1)));
+        assert_eq!(found_id(&user_list, "bob"), Some(UserId(2)));
+    }
+
+    #[test]
+    fn test_rename_user() {
+        let user_list = UserList::new();
+        let mut channel_list = ChannelList::new();
+        let alice = add_with_nick(&user_list, 1, "alice");
+        add_with_nick(&user_list, 2, "bob");
+        channel_list.join_channel("#team", UserId(1));
+        channel_list.join_channel("#team", UserId(2));
+
+        assert!(user_list.set_nick(&mut alice.lock().unwrap(), nick("carol")));
+
+        assert_eq!(found_id(&user_list, "alice"), None);
+        assert_eq!(found_id(&user_list, "carol"), Some(UserId(1)));
+        assert_eq!(found_id(&user_list, "bob"), Some(UserId(2)));
+        // channels know their members by id, so nothing changes there
+        assert!(channel_list.has_user("#team", UserId(1)));
+        assert!(channel_list.has_user("#team", UserId(2)));
+
+        // and the old nick is free for anyone
+        let dave = add_with_nick(&user_list, 3, "dave");
+        assert!(user_list.set_nick(&mut dave.lock().unwrap(), nick("Alice")));
     }
 
-    pub fn get_users(&self) -> Arc<Mutex<Vec<User>>> {
-        self.users.clone()
+    #[test]
+    fn test_case_only_rename() {
+        let user_list = UserList::new();
+        let alice = add_with_nick(&user_list, 1, "alice");
+
+        assert!(user_list.set_nick(&mut alice.lock().unwrap(), nick("Alice")));
+
+        assert_eq!(alice.lock().unwrap().get_nick().0, "Alice");
+        assert_eq!(found_id(&user_list, "alice"), Some(UserId(1)));
+    }
+
+    #[test]
+    fn test_remove_user() {
+        let user_list = UserList::new();
+        let alice = add_with_nick(&user_list, 1, "alice");
+
+        user_list.remove_user(&alice.lock().unwrap());
+
+        assert!(user_list.get(UserId(1)).is_none());
+        assert_eq!(found_id(&user_list, "alice"), None);
+        assert!(user_list.all_users().is_empty());
+
+        // which frees the nick
+        add_with_nick(&user_list, 2, "Alice");
+        assert_eq!(found_id(&user_list, "alice"), Some(UserId(2)));
+    }
+
+    #[test]
+    fn test_lock_order() {
+        use std::{sync::mpsc, thread, time::Duration};
+
+        let user_list = UserList::new();
+        let alice = add_with_nick(&user_list, 1, "alice");
+        let bob = add_with_nick(&user_list, 2, "bob");
+        add_with_nick(&user_list, 3, "carol");
+
+        let (done, finished) = mpsc::channel();
+
+        // Changing the indexes while holding a user, like the message handler does
+        let changing = {
+            let user_list = user_list.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                for round in 0..1000 {
+                    let mut alice = alice.lock().unwrap();
+                    // give the other thread every chance to get in between
+                    thread::yield_now();
+                    let name = if round % 2 == 0 { "alice2" } else { "alice" };
+                    assert!(user_list.set_nick(&mut alice, nick(name)));
+
+                    let carol = user_list.find(&nick("carol")).unwrap();
+                    user_list.remove_user(&carol.lock().unwrap());
+                    thread::yield_now();
+                    add_with_nick(&user_list, 3, "carol");
+                }
+                done.send("changing").unwrap();
+            })
+        };
+
+        // while looking users up, and locking them, while holding another one
+        let looking = {
+            let user_list = user_list.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    let _held = bob.lock().unwrap();
+                    if let Some(alice) = user_list.get(UserId(1)) {
+                        drop(alice.lock().unwrap());
+                    }
+                    for name in ["alice", "alice2", "carol"] {
+                        if let Some(user) = user_list.find(&nick(name)) {
+                            drop(user.lock().unwrap());
+                        }
+                    }
+                    for user in user_list.all_users() {
+                        if !Arc::ptr_eq(&user, &bob) {
+                            drop(user.lock().unwrap());
+                        }
+                    }
+                }
+                done.send("looking").unwrap();
+            })
+        };
+
+        // a deadlock would leave one of them waiting forever
+        for _ in 0..2 {
+            finished
+                .recv_timeout(Duration::from_secs(30))
+                .expect("Deadlocked");
+        }
+        changing.join().unwrap();
+        looking.join().unwrap();
     }
 }
@@ -0,0 +1,88 @@
+//! WEBIRC gateways
+//!
+//! A web gateway connects to the server on behalf of its users, so without
+//! help every user would seem to come from the gateway. A gateway that is
+//! configured here can tell the server who the user really is, with
+//! `WEBIRC <password> <gateway> <hostname> <ip>` before registering, so bans,
+//! cloaks and connection limits apply to the user.
+//!
+//! Gateways are configured with a name, the SHA-256 hash of their password,
+//! and the host mask they must connect from, like operators.
+use crate::{mask::HostMask, password};
+use std::{net::IpAddr, str::FromStr};
+
+/// A gateway trusted to send `WEBIRC`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebircGateway {
+    pub name: String,
+    /// Lowercase hex SHA-256 of the password.
+    pub password_hash: String,
+    /// The addresses the gateway connects from.
+    pub host_mask: HostMask,
+}
+
+impl WebircGateway {
+    pub fn check_password(&self, password: &str) -> bool {
+        password::check_hash(&self.password_hash, password)
+    }
+
+    pub fn matches_ip(&self, ip: IpAddr) -> bool {
+        self.host_mask.matches(ip, &ip.to_string())
+    }
+}
+
+/// Parse a gateway from `<name>:<password hash>:<host mask>`.
+///
+/// Unlike an operator's, the host mask can't be left out: anyone who can
+/// send `WEBIRC` can claim to be anyone.
+impl FromStr for WebircGateway {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let name = parts.next().unwrap_or_default();
+        let password_hash = parts.next().unwrap_or_default();
+
+        if name.is_empty() {
+            return Err(format!("gateway has no name: {s}"));
+        }
+
+        if password_hash.len() != 64 || !password_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("gateway {name} needs a hex SHA-256 password hash"));
+        }
+
+        let host_mask = parts
+            .next()
+            .ok_or_else(|| format!("gateway {name} needs a host mask"))?
+            .parse()?;
+
+        Ok(WebircGateway {
+            name: name.to_owned(),
+            password_hash: password_hash.to_owned(),
+            host_mask,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sha256("hunter2")
+    const HASH: &str = "f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7";
+
+    #[test]
+    fn test_parse() {
+        let gateway = format!("kiwi:{HASH}:192.0.2.0/24")
+            .parse::<WebircGateway>()
+            .unwrap();
+        assert_eq!(gateway.name, "kiwi");
+        assert!(gateway.check_password("hunter2"));
+        assert!(!gateway.check_password("hunter3"));
+        assert!(gateway.matches_ip("192.0.2.10".parse().unwrap()));
+        assert!(!gateway.matches_ip("198.51.100.1".parse().unwrap()));
+
+        assert!(format!("kiwi:{HASH}").parse::<WebircGateway>().is_err());
+        assert!("kiwi:hunter2:*".parse::<WebircGateway>().is_err());
+    }
+}
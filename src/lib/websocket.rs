@@ -0,0 +1,643 @@
+//! WebSocket
+//!
+//! Browser clients can connect over WebSocket (RFC 6455), following the IRCv3
+//! WebSocket spec: every frame carries one IRC message, without the trailing
+//! `\r\n`. The client picks the `text.ircv3.net` or `binary.ircv3.net`
+//! subprotocol, which decides whether the server sends text or binary frames.
+//! Clients that pick neither get text frames.
+//!
+//! A `WebSocketReader` turns the frames back into lines, and a
+//! `WebSocketWriter` sends lines as frames, so the rest of the server reads
+//! and writes a WebSocket connection like any other. The opening handshake
+//! happens on the first read; whatever is written before it is done is held
+//! back until then.
+//!
+//! The socket is written to by both the reader (to answer pings) and the
+//! writer, so writing goes through a shared lock. What is to be written is
+//! kept until it has all gone out, so a read or write that is given up on
+//! never leaves half a frame behind.
+use base64::prelude::{Engine, BASE64_STANDARD};
+use sha1::{Digest, Sha1};
+use std::{io, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
+};
+
+/// Added to the client's key to make the `Sec-WebSocket-Accept` header.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The most a client's opening handshake may take up.
+const MAX_HANDSHAKE_LEN: usize = 8192;
+
+/// The most a single message from a client may take up.
+const MAX_MESSAGE_LEN: usize = 16 * 1024;
+
+/// How many bytes are read from the socket at once.
+const READ_SIZE: usize = 4096;
+
+const TEXT_PROTOCOL: &str = "text.ircv3.net";
+const BINARY_PROTOCOL: &str = "binary.ircv3.net";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Start a WebSocket connection, reading from `reader` and writing to
+/// `writer`, the two halves of the same socket. Clients with an `Origin`
+/// outside `origins` are refused, unless `origins` is empty.
+pub fn accept<R, W>(
+    reader: R,
+    writer: W,
+    origins: Arc<[String]>,
+) -> (WebSocketReader<R, W>, WebSocketWriter<W>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        socket: writer,
+        state: State::Handshake,
+        binary: false,
+        held: Vec::new(),
+        output: Vec::new(),
+    }));
+
+    (
+        WebSocketReader {
+            socket: reader,
+            shared: shared.clone(),
+            origins,
+            input: Vec::new(),
+            message: Vec::new(),
+            lines: Vec::new(),
+            lines_read: 0,
+        },
+        WebSocketWriter {
+            shared,
+            line: Vec::new(),
+        },
+    )
+}
+
+/// Reads IRC lines from a WebSocket connection.
+pub struct WebSocketReader<R, W> {
+    socket: R,
+    shared: Arc<Mutex<Shared<W>>>,
+    /// The origins clients may connect from. Empty allows any.
+    origins: Arc<[String]>,
+    /// Bytes read from the socket that haven't been made sense of yet.
+    input: Vec<u8>,
+    /// The message being put together from fragments.
+    message: Vec<u8>,
+    /// Lines ready to be read, and how much of them has been.
+    lines: Vec<u8>,
+    lines_read: usize,
+}
+
+/// Writes IRC lines to a WebSocket connection.
+pub struct WebSocketWriter<W> {
+    shared: Arc<Mutex<Shared<W>>>,
+    /// The part of a line written so far.
+    line: Vec<u8>,
+}
+
+/// What the reader and writer share: the socket writes go through.
+struct Shared<W> {
+    socket: W,
+    state: State,
+    /// Send binary frames instead of text ones.
+    binary: bool,
+    /// Frames written before the handshake was done.
+    held: Vec<u8>,
+    /// Bytes on their way to the socket.
+    output: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Handshake,
+    Open,
+    Closed,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> WebSocketReader<R, W> {
+    /// Read lines into `buf`, each ending in `\r\n`. `Ok(0)` means the
+    /// connection was closed.
+    ///
+    /// This can be given up on, e.g. with a timeout, without losing anything.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.shared.lock().await.state == State::Handshake {
+            self.handshake().await?;
+        }
+
+        if !self.read_frames().await? {
+            return Ok(0);
+        }
+
+        let lines = &self.lines[self.lines_read..];
+        let n_bytes = lines.len().min(buf.len());
+        buf[..n_bytes].copy_from_slice(&lines[..n_bytes]);
+        self.lines_read += n_bytes;
+
+        Ok(n_bytes)
+    }
+
+    /// Read more from the socket into `input`. `Ok(false)` means it was closed.
+    async fn fill_input(&mut self) -> io::Result<bool> {
+        let mut buf = [0; READ_SIZE];
+        let n_bytes = self.socket.read(&mut buf).await?;
+        self.input.extend_from_slice(&buf[..n_bytes]);
+        Ok(n_bytes > 0)
+    }
+
+    /// Read the client's opening handshake and answer it.
+    async fn handshake(&mut self) -> io::Result<()> {
+        let end = loop {
+            if let Some(end) = self.input.windows(4).position(|bytes| bytes == b"\r\n\r\n") {
+                break end;
+            }
+            if self.input.len() > MAX_HANDSHAKE_LEN {
+                return Err(self.refuse("400 Bad Request", "handshake too long").await);
+            }
+            if !self.fill_input().await? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        };
+
+        let request = String::from_utf8_lossy(&self.input[..end]).into_owned();
+
+        let request = match parse_handshake(&request, &self.origins) {
+            Ok(request) => request,
+            Err(status) => return Err(self.refuse(status, "refused handshake").await),
+        };
+
+        let mut response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n",
+            accept_key(&request.key)
+        );
+        if let Some(protocol) = request.protocol {
+            response.push_str(&format!("Sec-WebSocket-Protocol: {protocol}\r\n"));
+        }
+        response.push_str("\r\n");
+
+        let mut shared = self.shared.lock().await;
+        // the client may start sending frames straight away
+        self.input.drain(..end + 4);
+        shared.binary = request.protocol == Some(BINARY_PROTOCOL);
+        shared.output.extend_from_slice(response.as_bytes());
+        let held = std::mem::take(&mut shared.held);
+        shared.output.extend_from_slice(&held);
+        shared.state = State::Open;
+        shared.flush().await
+    }
+
+    /// Answer a handshake that can't go ahead with `status`, and give the error to fail with.
+    async fn refuse(&self, status: &str, reason: &str) -> io::Error {
+        let mut shared = self.shared.lock().await;
+        shared.state = State::Closed;
+        shared.output =
+            format!("HTTP/1.1 {status}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n")
+                .into_bytes();
+        let _ = shared.flush().await;
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    }
+
+    /// Handle the frames in `input`, until there are lines to hand on.
+    /// `Ok(false)` means the connection was closed.
+    async fn read_frames(&mut self) -> io::Result<bool> {
+        while self.lines_read == self.lines.len() {
+            let Some((frame, len)) = parse_frame(&self.input)? else {
+                if !self.fill_input().await? {
+                    return Ok(false);
+                }
+                continue;
+            };
+
+            match frame.opcode {
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    self.input.drain(..len);
+                    if self.message.len() + frame.payload.len() > MAX_MESSAGE_LEN {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "message too long",
+                        ));
+                    }
+                    self.message.extend_from_slice(&frame.payload);
+
+                    if frame.fin {
+                        // some clients send the `\r\n` anyway
+                        let message = std::mem::take(&mut self.message);
+                        let message = message.strip_suffix(b"\r\n").unwrap_or(&message);
+                        self.lines.clear();
+                        self.lines_read = 0;
+                        self.lines.extend_from_slice(message);
+                        self.lines.extend_from_slice(b"\r\n");
+                    }
+                }
+                OPCODE_PING => {
+                    // the frame is only taken once the pong is sure to go out
+                    let mut shared = self.shared.lock().await;
+                    self.input.drain(..len);
+                    if shared.state == State::Open {
+                        shared.send(OPCODE_PONG, &frame.payload);
+                    }
+                    shared.flush().await?;
+                }
+                OPCODE_PONG => {
+                    self.input.drain(..len);
+                }
+                OPCODE_CLOSE => {
+                    // echo the status code back
+                    let mut shared = self.shared.lock().await;
+                    self.input.drain(..len);
+                    if shared.state == State::Open {
+                        shared.send(OPCODE_CLOSE, frame.payload.get(..2).unwrap_or(&[]));
+                        shared.state = State::Closed;
+                    }
+                    let _ = shared.flush().await;
+                    return Ok(false);
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unknown WebSocket opcode",
+                    ))
+                }
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// Every line written is sent as a frame of its own, without the `\r\n`.
+impl<W: AsyncWrite + Unpin> WebSocketWriter<W> {
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut shared = self.shared.lock().await;
+        self.line.extend_from_slice(buf);
+
+        while let Some(end) = self.line.windows(2).position(|bytes| bytes == b"\r\n") {
+            let opcode = if shared.binary {
+                OPCODE_BINARY
+            } else {
+                OPCODE_TEXT
+            };
+            shared.send(opcode, &self.line[..end]);
+            self.line.drain(..end + 2);
+        }
+
+        shared.flush().await
+    }
+
+    /// Send a close frame, if the connection is open, then shut the socket down.
+    pub async fn close(&mut self) -> io::Result<()> {
+        let mut shared = self.shared.lock().await;
+        if shared.state == State::Open {
+            shared.send(OPCODE_CLOSE, &1000u16.to_be_bytes());
+            shared.state = State::Closed;
+        }
+        shared.flush().await?;
+        shared.socket.shutdown().await
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Shared<W> {
+    /// Queue a frame, or hold it back if the handshake isn't done yet.
+    fn send(&mut self, opcode: u8, payload: &[u8]) {
+        let frame = encode_frame(opcode, payload);
+        match self.state {
+            State::Handshake => self.held.extend_from_slice(&frame),
+            State::Open => self.output.extend_from_slice(&frame),
+            // nothing more may be sent after a close frame
+            State::Closed => {}
+        }
+    }
+
+    /// Write out everything queued. What has been written is taken off as
+    /// it goes, so if this is given up on, the rest goes out next time.
+    async fn flush(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            let n_bytes = self.socket.write(&self.output).await?;
+            if n_bytes == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.output.drain(..n_bytes);
+        }
+        self.socket.flush().await
+    }
+}
+
+/// What the server needs from a client's opening handshake.
+#[derive(Debug, PartialEq, Eq)]
+struct Handshake {
+    key: String,
+    protocol: Option<&'static str>,
+}
+
+/// Check an opening handshake, or give the HTTP status to refuse it with.
+fn parse_handshake(request: &str, origins: &[String]) -> Result<Handshake, &'static str> {
+    let mut lines = request.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    if !request_line.starts_with("GET ") {
+        return Err("400 Bad Request");
+    }
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim()))
+        .collect::<Vec<_>>();
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| *value)
+    };
+    let has_token = |name: &str, token: &str| {
+        header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        })
+    };
+
+    if !has_token("upgrade", "websocket")
+        || !has_token("connection", "upgrade")
+        || header("sec-websocket-version") != Some("13")
+    {
+        return Err("400 Bad Request");
+    }
+    let key = header("sec-websocket-key").ok_or("400 Bad Request")?;
+
+    // only browsers send an origin, and only they need keeping from other sites
+    if let Some(origin) = header("origin") {
+        if !origins.is_empty()
+            && !origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+        {
+            return Err("403 Forbidden");
+        }
+    }
+
+    // the first of the client's subprotocols that the server knows
+    let protocol = header("sec-websocket-protocol").and_then(|protocols| {
+        protocols.split(',').map(str::trim).find_map(|protocol| {
+            [TEXT_PROTOCOL, BINARY_PROTOCOL]
+                .into_iter()
+                .find(|known| known.eq_ignore_ascii_case(protocol))
+        })
+    });
+
+    Ok(Handshake {
+        key: key.to_owned(),
+        protocol,
+    })
+}
+
+/// The `Sec-WebSocket-Accept` header for a client's `Sec-WebSocket-Key`.
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    BASE64_STANDARD.encode(hasher.finalize())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Parse a frame from a client off the start of `input`, with how many bytes
+/// it took up, or `None` if it isn't all there yet.
+fn parse_frame(input: &[u8]) -> io::Result<Option<(Frame, usize)>> {
+    let invalid = |reason| io::Error::new(io::ErrorKind::InvalidData, reason);
+
+    let [first, second, ..] = *input else {
+        return Ok(None);
+    };
+    if first & 0x70 != 0 {
+        return Err(invalid("reserved WebSocket bits set"));
+    }
+    // clients must mask what they send
+    if second & 0x80 == 0 {
+        return Err(invalid("unmasked WebSocket frame"));
+    }
+
+    let (len, mut start) = match second & 0x7f {
+        126 => match input.get(2..4) {
+            Some(len) => (u16::from_be_bytes([len[0], len[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match input.get(2..10) {
+            Some(len) => (u64::from_be_bytes(len.try_into().unwrap()), 10),
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+    if len > MAX_MESSAGE_LEN as u64 {
+        return Err(invalid("WebSocket frame too long"));
+    }
+
+    let Some(mask) = input.get(start..start + 4) else {
+        return Ok(None);
+    };
+    start += 4;
+
+    let end = start + len as usize;
+    let Some(payload) = input.get(start..end) else {
+        return Ok(None);
+    };
+
+    let payload = payload
+        .iter()
+        .zip(mask.iter().cycle())
+        .map(|(byte, mask)| byte ^ mask)
+        .collect();
+
+    Ok(Some((
+        Frame {
+            fin: first & 0x80 != 0,
+            opcode: first & 0x0f,
+            payload,
+        },
+        end,
+    )))
+}
+
+/// Encode a whole, unmasked frame from the server.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a masked frame, as a client sends it.
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = encode_frame(opcode, payload);
+        let start = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        let masked = frame
+            .drain(start..)
+            .zip(mask.iter().cycle())
+            .map(|(byte, mask)| byte ^ mask)
+            .collect::<Vec<_>>();
+        frame.extend_from_slice(&mask);
+        frame.extend_from_slice(&masked);
+        frame
+    }
+
+    fn request(extra: &str) -> String {
+        format!(
+            "GET / HTTP/1.1\r\nHost: irc.example.com\r\nUpgrade: websocket\r\n\
+             Connection: keep-alive, Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{extra}"
+        )
+    }
+
+    #[test]
+    fn test_handshake() {
+        // the example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let origins = ["https://irc.example.com".to_owned()];
+        let handshake = parse_handshake(
+            &request("Sec-WebSocket-Protocol: foo, binary.ircv3.net, text.ircv3.net\r\n"),
+            &origins,
+        )
+        .unwrap();
+        assert_eq!(handshake.key, "dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(handshake.protocol, Some(BINARY_PROTOCOL));
+
+        assert_eq!(
+            parse_handshake(&request(""), &origins).unwrap().protocol,
+            None
+        );
+        assert_eq!(
+            parse_handshake(&request("Origin: https://irc.example.com\r\n"), &origins).map(|_| ()),
+            Ok(())
+        );
+        assert_eq!(
+            parse_handshake(&request("Origin: https://evil.example.com\r\n"), &origins),
+            Err("403 Forbidden")
+        );
+        assert_eq!(
+            parse_handshake(&request("Origin: https://evil.example.com\r\n"), &[]).map(|_| ()),
+            Ok(())
+        );
+        assert_eq!(
+            parse_handshake("GET / HTTP/1.1\r\nHost: irc.example.com", &[]),
+            Err("400 Bad Request")
+        );
+    }
+
+    #[test]
+    fn test_frames() {
+        let frame = client_frame(OPCODE_TEXT, b"PING :hello");
+        assert_eq!(parse_frame(&frame[..5]).unwrap(), None);
+        let (parsed, len) = parse_frame(&frame).unwrap().unwrap();
+        assert_eq!(len, frame.len());
+        assert_eq!(
+            parsed,
+            Frame {
+                fin: true,
+                opcode: OPCODE_TEXT,
+                payload: b"PING :hello".to_vec(),
+            }
+        );
+
+        let long = vec![b'a'; 300];
+        let (parsed, _) = parse_frame(&client_frame(OPCODE_BINARY, &long))
+            .unwrap()
+            .unwrap();
+        assert_eq!(parsed.payload, long);
+
+        // frames from the server aren't masked, which a client's must be
+        assert!(parse_frame(&encode_frame(OPCODE_TEXT, b"hi")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let (server, mut client) = tokio::io::duplex(64 * 1024);
+        let (reader, writer) = tokio::io::split(server);
+        let (mut reader, mut writer) = accept(reader, writer, Arc::from([]));
+
+        // held back until the handshake is done
+        writer.write(b"NOTICE * :early\r\n").await.unwrap();
+
+        client
+            .write_all(request("Sec-WebSocket-Protocol: text.ircv3.net\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(OPCODE_TEXT, b"NICK alice"))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(OPCODE_PING, b""))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(OPCODE_TEXT, b"USER a 0 * :A\r\n"))
+            .await
+            .unwrap();
+
+        let mut buf = [0; 512];
+        let n_bytes = reader.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n_bytes], b"NICK alice\r\n");
+        let n_bytes = reader.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n_bytes], b"USER a 0 * :A\r\n");
+
+        writer.write(b"PING :x\r\n").await.unwrap();
+        writer.close().await.unwrap();
+        drop((reader, writer));
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+
+        let end = response
+            .windows(4)
+            .position(|bytes| bytes == b"\r\n\r\n")
+            .unwrap();
+        let head = String::from_utf8_lossy(&response[..end]);
+        assert!(head.starts_with("HTTP/1.1 101"));
+        assert!(head.contains("Sec-WebSocket-Protocol: text.ircv3.net"));
+
+        assert_eq!(
+            response[end + 4..],
+            [
+                encode_frame(OPCODE_TEXT, b"NOTICE * :early"),
+                encode_frame(OPCODE_PONG, b""),
+                encode_frame(OPCODE_TEXT, b"PING :x"),
+                encode_frame(OPCODE_CLOSE, &1000u16.to_be_bytes()),
+            ]
+            .concat()
+        );
+    }
+}
@@ -1,25 +1,73 @@
+use crate::arguments::{Arguments, Sources};
 use anyhow::{anyhow, Result};
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
 use iris_lib::{
+    ban::BanList,
+    casemapping::set_casemapping,
     channel_list::ChannelList,
-    connect::{ConnectionError, ConnectionManager},
-    massage_sender::{error_msg_sender, global_msg_sender},
-    types::{ErrorType, Message, Nick, ParsedMessage, UnparsedMessage, SERVER_NAME},
+    cloak::Cloak,
+    config::{ConnectionClass, ServerConfig, SharedConfig},
+    connect::{ConnectionError, ConnectionManager, ConnectionRead, ConnectionWrite},
+    flood::FloodControl,
+    keepalive::spawn_keepalive,
+    massage_sender::{error_msg_sender, global_msg_sender, shutdown_server},
+    plugin,
+    tls::TlsAcceptor,
+    types::{
+        set_nick_len, set_server_name, ErrorType, Message, ParsedMessage, QuitMsg, Reply,
+        UnparsedMessage, UserId,
+    },
+    upgrade::{self, HandedOverUser, UpgradeState},
     user::{User, UserList},
 };
 use simple_logger::SimpleLogger;
-use std::net::IpAddr;
+use std::{
+    path::PathBuf,
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task::JoinHandle,
+    time::timeout,
+};
 
 #[macro_use]
 extern crate log;
 
-#[derive(Parser)]
-struct Arguments {
-    #[clap(default_value = "127.0.0.1")]
-    ip_address: IpAddr,
+mod arguments;
+
+/// Read the settings again, and apply whatever can change while the server runs.
+///
+/// `arguments` are the settings in use, and are updated. The rest only
+/// changes when the server is restarted, which is logged. If the new settings
+/// have a problem, nothing changes.
+fn reload_config(
+    sources: &Sources,
+    arguments: &mut Arguments,
+    config: &SharedConfig,
+    connection_manager: &mut ConnectionManager,
+) -> Result<()> {
+    let Some(path) = &sources.command_line.config else {
+        return Ok(());
+    };
+    let mut new = sources.load()?;
+
+    for setting in new.keep_restart_only(arguments) {
+        warn!("Keeping the {} until the server is restarted", setting);
+    }
+
+    config.replace(new.server_config(config.get().cloak.clone())?);
+    connection_manager.set_limits(new.limits());
+    set_nick_len(new.nick_len);
+    log::set_max_level(new.log_level.unwrap_or(sources.default_log_level));
 
-    #[clap(default_value = "6991")]
-    port: u16,
+    *arguments = new;
+    info!("Reloaded the configuration from {}", path.display());
+
+    Ok(())
 }
 
 fn main() {
@@ -29,119 +77,604 @@ fn main() {
         .init()
         .expect("Failed to initialize logger!");
 
-    let arguments = Arguments::parse();
-    info!(
-        "Launching {} at {}:{}",
-        SERVER_NAME, arguments.ip_address, arguments.port
-    );
+    // Started by an upgrade, with everything the server that ran before handed
+    // over. It is read before the async runtime starts any threads.
+    let handed_over = match upgrade::take_state() {
+        Ok(state) => state,
+        Err(err) => {
+            error!("{:#}", err);
+            std::process::exit(1);
+        }
+    };
 
-    let mut user_list = UserList::new();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start the async runtime!")
+        .block_on(run(handed_over));
+}
 
-    let mut connection_manager = ConnectionManager::launch(arguments.ip_address, arguments.port);
+async fn run(mut handed_over: Option<UpgradeState>) {
+    let matches = Arguments::command().get_matches();
+    let sources = Sources {
+        command_line: Arguments::from_arg_matches(&matches).unwrap_or_else(|err| err.exit()),
+        matches,
+        default_log_level: log::max_level(),
+    };
+    let mut arguments = match sources.load() {
+        Ok(arguments) => arguments,
+        Err(err) => {
+            error!("{:#}", err);
+            std::process::exit(1);
+        }
+    };
 
-    // Channel
-    let (sender, receiver) = std::sync::mpsc::channel::<Result<ParsedMessage, (ErrorType, Nick)>>();
+    log::set_max_level(arguments.log_level.unwrap_or(sources.default_log_level));
+    set_casemapping(arguments.casemapping);
+    set_server_name(arguments.server_name.clone());
+    set_nick_len(arguments.nick_len);
+    info!("Launching {}", arguments.server_name);
 
-    // Thread to send messages
-    {
-        // The clone is needed because the thread will take ownership of the variable
-        let mut user_list = user_list.clone();
-
-        std::thread::spawn(move || {
-            let mut channel_list = ChannelList::new();
-
-            for msg in receiver {
-                if let Ok(parsed_msg) = msg {
-                    let sender_nick = parsed_msg.sender_nick.clone();
-                    if let Err(err) = global_msg_sender(&mut user_list, &mut channel_list, parsed_msg) {
-                        error!("Error when handling message: {}", err);
-                        error_msg_sender(err, &user_list, sender_nick);
-                    } else {
-                        debug!("Message handled successfully!");
-                    }
-                } else if let Err((err, nick)) = msg {
-                    let err = anyhow!(err);
-                    error!("Error when parsing message: {}", err);
-                    error_msg_sender(err, &user_list, nick);
-                }
+    let listeners = arguments.listeners();
+
+    let user_list = UserList::new();
+
+    let cloak = match &arguments.cloak_key {
+        Some(key) => Cloak::new(key.as_bytes()),
+        None => {
+            warn!("No cloak key given, using a random one");
+            Cloak::random()
+        }
+    };
+    let config = match arguments.server_config(cloak) {
+        Ok(config) => SharedConfig::new(config),
+        Err(err) => {
+            error!("{:#}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let ban_list = BanList::load(&arguments.ban_file).expect("Failed to load the ban list!");
+
+    let acceptor = match (&arguments.tls_cert, &arguments.tls_key) {
+        (Some(cert), Some(key)) => {
+            Some(TlsAcceptor::load(cert, key).expect("Failed to load the TLS certificate!"))
+        }
+        _ => None,
+    };
+
+    let mut connection_manager = match ConnectionManager::launch(
+        &listeners,
+        acceptor.as_ref(),
+        ban_list.clone(),
+        arguments.limits(),
+        arguments.line_len,
+        handed_over
+            .as_mut()
+            .map(|state| std::mem::take(&mut state.listeners))
+            .unwrap_or_default(),
+    ) {
+        Ok(connection_manager) => connection_manager,
+        Err(err) => {
+            error!("Failed to start listening: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    for listener in &listeners {
+        info!(
+            "Listening at {}{}{}",
+            listener.address,
+            if listener.tls { " for TLS" } else { "" },
+            if listener.websocket {
+                " (WebSocket)"
+            } else {
+                ""
             }
-        });
+        );
     }
 
-    loop {
-        // This function call will block until a new client connects!
-        let (mut conn_read, conn_write) = connection_manager.accept_new_connection();
+    if let Some(path) = &config.get().plugin_state_file {
+        if let Err(err) = plugin::load_state(path, &user_list) {
+            error!("Failed to load the plugin state: {:#}", err);
+        }
+    }
+
+    // The message handler has the channel list to itself, until the server shuts down
+    let channel_list = Arc::new(Mutex::new(ChannelList::new()));
 
-        let user = User::new(conn_read.id(), conn_write);
+    // The TLS certificate and the config file are read again on SIGHUP,
+    // e.g. after the certificate was renewed
+    let mut hangups = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP!");
 
-        user_list.add_user(user);
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM!");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT!");
+    // SIGUSR2 upgrades to the binary the server was started from, see `upgrade`
+    let mut upgrades = signal(SignalKind::user_defined2()).expect("Failed to listen for SIGUSR2!");
 
-        info!("New connection from {}", conn_read.id());
+    // Serve until the server is told to stop. A failed upgrade starts over
+    // with the connections it stopped.
+    loop {
+        // Channel
+        let (sender, receiver) =
+            std::sync::mpsc::channel::<Result<ParsedMessage, (ErrorType, UserId)>>();
 
-        {
-            let user_list = user_list.clone();
-            let sender = sender.clone();
+        if let Some(state) = handed_over.take() {
+            resume_users(
+                state,
+                &mut connection_manager,
+                &user_list,
+                &mut channel_list.lock().expect("Failed to lock channels"),
+                &config.get(),
+                &sender,
+            );
+        }
 
-            std::thread::spawn(move || {
-                loop {
-                    // debug!("Waiting for message...");
-                    let message = match conn_read.read_message() {
-                        Ok(message) => message,
-                        Err(
-                            ConnectionError::ConnectionLost | ConnectionError::ConnectionClosed,
-                        ) => {
-                            warn!("Lost connection.");
-                            break;
-                        }
-                        Err(_) => {
-                            debug!("Invalid message received... ignoring message.");
-                            continue;
+        let dispatcher = spawn_dispatcher(
+            receiver,
+            user_list.clone(),
+            channel_list.clone(),
+            config.clone(),
+            ban_list.clone(),
+        );
+
+        // Task to ping idle users and drop timed out connections
+        let keepalive = spawn_keepalive(user_list.clone(), sender.clone(), config.clone());
+
+        let upgrade_to = loop {
+            // This waits until a new client connects, or the server is told to stop!
+            let (conn_read, conn_write) = tokio::select! {
+                connection = connection_manager.accept_new_connection() => connection,
+                _ = hangups.recv() => {
+                    if let Some(acceptor) = &acceptor {
+                        match acceptor.reload() {
+                            Ok(()) => info!("Reloaded the TLS certificate"),
+                            Err(err) => error!("Failed to reload the TLS certificate: {:#}", err),
                         }
-                    };
-
-                    debug!("Received message: {message}");
-
-                    // Get the user's nick by id
-                    let users = user_list.get_users();
-                    let users = users.lock().expect("Failed to lock users list!");
-                    let user = users
-                        .iter()
-                        .find(|user| user.get_id() == conn_read.id())
-                        .expect("Failed to find user!");
-                    let user_nick = user.get_nick();
-
-                    // Parse the message
-                    let parsed_msg = match ParsedMessage::try_from(UnparsedMessage {
-                        sender_nick: user_nick.clone(),
-                        message: &message,
-                    }) {
-                        Ok(parsed_msg) => parsed_msg,
+                    }
+                    if let Err(err) =
+                        reload_config(&sources, &mut arguments, &config, &mut connection_manager)
+                    {
+                        error!("Failed to reload the configuration: {:#}", err);
+                    }
+                    continue;
+                }
+                _ = terminate.recv() => {
+                    info!("Received SIGTERM");
+                    break None;
+                }
+                _ = interrupt.recv() => {
+                    info!("Received SIGINT");
+                    break None;
+                }
+                _ = upgrades.recv() => {
+                    info!("Received SIGUSR2");
+                    match upgrade::executable() {
+                        Ok(exe) => break Some(exe),
                         Err(err) => {
-                            sender.send(Err((err, user_nick))).expect("The channel is closed!");
-                            debug!("Invalid message received... ignoring message.");
+                            error!("Not upgrading: {:#}", err);
                             continue;
                         }
-                    };
+                    }
+                }
+            };
+
+            serve_connection(conn_read, conn_write, &user_list, &sender, &config.get());
+        };
 
-                    debug!("Parsed message: {:?}", parsed_msg);
+        let Some(exe) = upgrade_to else {
+            break;
+        };
 
-                    // Drop the lock before sending the message
-                    let is_registered = user.is_set_nick() && user.is_set_real_name();
-                    drop(users);
+        // it would only get in the way of the connections stopping
+        keepalive.abort();
+        let _ = keepalive.await;
 
-                    sender.send(Ok(parsed_msg.clone())).expect("The channel is closed!");
+        let (err, mut state) = upgrade_server(
+            exe,
+            &mut connection_manager,
+            &user_list,
+            channel_list.clone(),
+            &config,
+            sender,
+            dispatcher,
+        )
+        .await;
+        error!("Failed to upgrade, carrying on: {:#}", err);
 
-                    // Check if the user is quitting
-                    // If so, quit the thread
-                    if let Message::Quit(_) = parsed_msg.message {
-                        // Check if the user has a nick and a real name
-                        if is_registered {
-                            info!("User {} has quit.", user_nick);
-                            break;
+        // Pick everything up again like a new process would, from the users
+        // and channels the upgrade left
+        for user in user_list.all_users() {
+            let user = user.lock().expect("Failed to lock user");
+            user_list.remove_user(&user);
+        }
+        *channel_list.lock().expect("Failed to lock channels") = ChannelList::new();
+
+        connection_manager = match ConnectionManager::launch(
+            &arguments.listeners(),
+            acceptor.as_ref(),
+            ban_list.clone(),
+            arguments.limits(),
+            arguments.line_len,
+            std::mem::take(&mut state.listeners),
+        ) {
+            Ok(connection_manager) => connection_manager,
+            Err(err) => {
+                error!("Failed to start listening again: {}", err);
+                std::process::exit(1);
+            }
+        };
+        handed_over = Some(state);
+    }
+
+    info!("Shutting down");
+
+    // Stop taking new connections
+    drop(connection_manager);
+
+    // Holding on to the channel list keeps the message handler from running
+    // anything else, including another shutdown from a DIE, until the process exits.
+    tokio::task::spawn_blocking(move || {
+        let mut user_list = user_list;
+        let mut channel_list = channel_list.lock().expect("Failed to lock channels");
+        shutdown_server(
+            &mut user_list,
+            &mut channel_list,
+            &config.get(),
+            "Server shutting down",
+        );
+        std::process::exit(0);
+    })
+    .await
+    .expect("Failed to shut down!");
+}
+
+/// Take in a newly connected client: add them as a user, in the class they
+/// belong to, and start their reader task.
+fn serve_connection(
+    conn_read: ConnectionRead,
+    mut conn_write: ConnectionWrite,
+    user_list: &UserList,
+    sender: &Sender<Result<ParsedMessage, (ErrorType, UserId)>>,
+    config: &ServerConfig,
+) {
+    let ip = conn_read.ip();
+    // local clients on a Unix socket are known by their user id instead
+    let host = match conn_read.peer_uid() {
+        Some(uid) => format!("uid-{uid}.localhost"),
+        None => config.cloak.cloak(ip),
+    };
+    let class = connection_class(&conn_read, config, &host);
+    conn_write.set_sendq(class.sendq);
+
+    info!(
+        "New connection {} from {} ({}) in class {}",
+        conn_read.id(),
+        ip,
+        host,
+        class.name
+    );
+
+    let mut user = User::new(conn_read.id(), conn_write, host);
+
+    if let Some(account) = conn_read
+        .trusted_uid()
+        .and_then(|uid| config.find_account_by_uid(uid))
+    {
+        info!("{} logged in as {}", conn_read.id(), account.name);
+        user.set_account(account.name.clone());
+    }
+
+    user_list.add_user(user);
+
+    let flood = FloodControl::new(config.flood);
+    tokio::spawn(read_messages(
+        conn_read,
+        user_list.clone(),
+        sender.clone(),
+        flood,
+        class,
+    ));
+}
+
+/// The connection class of a client shown as `host`.
+fn connection_class(
+    conn_read: &ConnectionRead,
+    config: &ServerConfig,
+    host: &str,
+) -> ConnectionClass {
+    // the listener's class, if it has one, goes before the host's
+    match conn_read.listener_class() {
+        Some(name) => config
+            .find_class(name)
+            .cloned()
+            .expect("Listener classes are checked whenever the config is loaded"),
+        None => config.class_for(conn_read.ip(), host),
+    }
+}
+
+/// Pick up the users and channels the server that ran before handed over,
+/// and start reading from their connections again.
+fn resume_users(
+    state: UpgradeState,
+    connection_manager: &mut ConnectionManager,
+    user_list: &UserList,
+    channel_list: &mut ChannelList,
+    config: &ServerConfig,
+    sender: &Sender<Result<ParsedMessage, (ErrorType, UserId)>>,
+) {
+    connection_manager.skip_ids(state.last_id);
+    for HandedOverUser {
+        user,
+        mut connection,
+    } in state.users
+    {
+        let id = user.id;
+        let input = std::mem::take(&mut connection.input);
+        let (conn_read, mut conn_write) = match connection_manager.resume(id, connection) {
+            Ok(connection) => connection,
+            Err(err) => {
+                warn!("Failed to pick up the connection of {}: {}", id, err);
+                continue;
+            }
+        };
+
+        let class = connection_class(&conn_read, config, &user.host);
+        conn_write.set_sendq(class.sendq);
+        user_list.add_user(User::from_state(user, conn_write));
+
+        // the lines read before the upgrade are handled first
+        let mut flood = FloodControl::new(config.flood);
+        for line in input {
+            let _ = flood.push(line);
+        }
+        tokio::spawn(read_messages(
+            conn_read,
+            user_list.clone(),
+            sender.clone(),
+            flood,
+            class,
+        ));
+    }
+
+    let mut channels = state.channels;
+    for channel in &mut channels {
+        channel.members.retain(|id| user_list.get(*id).is_some());
+    }
+    upgrade::restore_channels(channel_list, channels);
+
+    info!(
+        "Picked up {} connections from before the upgrade",
+        user_list.all_users().len()
+    );
+}
+
+/// Hand everything over to a new copy of the server, run from `exe`, see `upgrade`.
+///
+/// This only returns if that failed, with the error and what was to be handed
+/// over. By then the listeners, the connections and the message handler have
+/// stopped, and the connections that couldn't be handed over are gone.
+async fn upgrade_server(
+    exe: PathBuf,
+    connection_manager: &mut ConnectionManager,
+    user_list: &UserList,
+    channel_list: Arc<Mutex<ChannelList>>,
+    config: &SharedConfig,
+    sender: Sender<Result<ParsedMessage, (ErrorType, UserId)>>,
+    dispatcher: JoinHandle<()>,
+) -> (anyhow::Error, UpgradeState) {
+    let listeners = connection_manager.hand_over_listeners().await;
+
+    // clients accepted just before go along too
+    while let Some((conn_read, conn_write)) = connection_manager.try_accept_new_connection() {
+        serve_connection(conn_read, conn_write, user_list, &sender, &config.get());
+    }
+
+    let handovers = user_list
+        .all_users()
+        .into_iter()
+        .map(|user| {
+            let mut user = user.lock().expect("Failed to lock user");
+            (user.get_id(), user.start_handover())
+        })
+        .collect();
+
+    // The message handler stops once it has handled everything the readers
+    // handed it before they stopped
+    drop(sender);
+    let grace = config.get().shutdown_grace;
+    if timeout(grace, dispatcher).await.is_err() {
+        warn!("The message handler didn't stop in time, upgrading anyway");
+    }
+
+    let last_id = connection_manager.last_id();
+    let user_list = user_list.clone();
+    let config = config.get();
+    tokio::task::spawn_blocking(move || {
+        let mut user_list = user_list;
+        let mut channel_list = channel_list.lock().expect("Failed to lock channels");
+        let state = upgrade::hand_over(
+            &mut user_list,
+            &mut channel_list,
+            &config,
+            handovers,
+            listeners,
+            last_id,
+        );
+        (upgrade::exec(&exe, &state), state)
+    })
+    .await
+    .expect("Failed to upgrade!")
+}
+
+/// Start the thread that handles messages. The handlers lock the users and
+/// channels, and may wait, so they get a thread of their own instead of a task.
+///
+/// It stops once every reader, and everything else sending to it, has.
+fn spawn_dispatcher(
+    receiver: Receiver<Result<ParsedMessage, (ErrorType, UserId)>>,
+    mut user_list: UserList,
+    channel_list: Arc<Mutex<ChannelList>>,
+    config: SharedConfig,
+    ban_list: BanList,
+) -> JoinHandle<()> {
+    tokio::task::spawn_blocking(move || {
+        for msg in receiver {
+            let mut channel_list = channel_list.lock().expect("Failed to lock channels");
+            // a reload takes effect from the next message on
+            let config = config.get();
+            if let Ok(parsed_msg) = msg {
+                let sender_id = parsed_msg.sender;
+                if let Err(err) = global_msg_sender(
+                    &mut user_list,
+                    &mut channel_list,
+                    &config,
+                    &ban_list,
+                    parsed_msg,
+                ) {
+                    error!("Error when handling message: {}", err);
+                    error_msg_sender(err, &user_list, sender_id);
+                } else {
+                    debug!("Message handled successfully!");
+                }
+            } else if let Err((err, id)) = msg {
+                let err = anyhow!(err);
+                error!("Error when parsing message: {}", err);
+                error_msg_sender(err, &user_list, id);
+            }
+        }
+    })
+}
+
+/// Read messages from a client until they leave, handing them to the message handler.
+///
+/// Lines go through the client's flood control first, so a client sending too fast
+/// is slowed down, and disconnected if it keeps going.
+async fn read_messages(
+    mut conn_read: ConnectionRead,
+    user_list: UserList,
+    sender: Sender<Result<ParsedMessage, (ErrorType, UserId)>>,
+    mut flood: FloodControl,
+    class: ConnectionClass,
+) {
+    loop {
+        // Wake up in time to hand on lines that had to wait for the client's budget
+        conn_read.set_read_timeout(flood.wait_time());
+
+        // debug!("Waiting for message...");
+        match conn_read.read_message().await {
+            Ok(message) => {
+                debug!("Received message: {message}");
+
+                // Record the activity, even if the message has to wait
+                let Some(user) = user_list.get(conn_read.id()) else {
+                    // The user has already been removed, e.g. after a timeout.
+                    break;
+                };
+                let mut user = user.lock().expect("Failed to lock user!");
+                user.touch();
+
+                if flood.push(message).is_err() {
+                    warn!("Excess flood from {}", user.get_nick());
+                    let _ = user.send(Reply::ErrorMsg("Excess Flood".to_owned()));
+                    quit_from_reader(&sender, user.get_id(), "Excess Flood");
+                    break;
+                }
+            }
+            Err(ConnectionError::TimedOut) => {}
+            Err(ConnectionError::HandedOver) => {
+                // the lines that had to wait go along with the connection
+                conn_read.hand_over(flood.take_queue());
+                return;
+            }
+            Err(err @ (ConnectionError::ConnectionLost | ConnectionError::ConnectionClosed)) => {
+                warn!("Lost connection.");
+
+                // Hand the user to the message handler as a QUIT, so their channels
+                // are told and they are removed. If they were already removed
+                // (QUIT, timeout, ...), there is nothing left to clean up.
+                if let Some(user) = user_list.get(conn_read.id()) {
+                    let user = user.lock().expect("Failed to lock user!");
+                    // the server may have closed it, e.g. for exceeding the SendQ
+                    let reason = conn_read.close_reason().unwrap_or_else(|| {
+                        match err {
+                            ConnectionError::ConnectionClosed => {
+                                "Remote host closed the connection"
+                            }
+                            _ => "Read error",
                         }
-                    }
+                        .to_owned()
+                    });
+                    quit_from_reader(&sender, user.get_id(), &reason);
                 }
-            });
+                break;
+            }
+            Err(_) => {
+                debug!("Invalid message received... ignoring message.");
+                continue;
+            }
+        }
+
+        // Hand on every line the client has the budget for
+        loop {
+            // The user is gone once the message handler has removed them
+            let Some(user) = user_list.get(conn_read.id()) else {
+                return;
+            };
+            let user = user.lock().expect("Failed to lock user!");
+
+            let exempt = class.flood_exempt || user.is_oper();
+            let Some(message) = flood.pop(exempt) else {
+                break;
+            };
+            let user_nick = user.get_nick();
+
+            // Drop the lock before sending the message
+            drop(user);
+
+            // Parse the message
+            let parsed_msg = match ParsedMessage::try_from(UnparsedMessage {
+                sender: conn_read.id(),
+                message: &message,
+            }) {
+                Ok(parsed_msg) => parsed_msg,
+                Err(err) => {
+                    sender
+                        .send(Err((err, conn_read.id())))
+                        .expect("The channel is closed!");
+                    debug!("Invalid message received... ignoring message.");
+                    continue;
+                }
+            };
+
+            debug!("Parsed message: {:?}", parsed_msg);
+
+            sender
+                .send(Ok(parsed_msg.clone()))
+                .expect("The channel is closed!");
+
+            // Check if the user is quitting
+            // If so, quit the thread, the message handler closes the connection
+            if let Message::Quit(_) = parsed_msg.message {
+                info!("User {} has quit.", user_nick);
+                return;
+            }
         }
     }
 }
+
+/// Hand a user the reader task is giving up on to the message handler as a `QUIT`.
+fn quit_from_reader(
+    sender: &Sender<Result<ParsedMessage, (ErrorType, UserId)>>,
+    id: UserId,
+    reason: &str,
+) {
+    sender
+        .send(Ok(ParsedMessage {
+            sender: id,
+            message: Message::Quit(QuitMsg {
+                message: Some(reason.to_owned()),
+            }),
+        }))
+        .expect("The channel is closed!");
+}